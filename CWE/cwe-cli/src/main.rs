@@ -1,23 +1,298 @@
-use cwe::device::list_devices;
-use cwe::device::check_firmware_sanitize;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use anyhow::{bail, Context};
+use clap::Parser;
+use cwe::ata;
+use cwe::device::{check_firmware_sanitize, enumerate_block_devices_linux, group_by_controller, Device, DeviceType};
+use cwe::evidence::WipeEvidence;
+use cwe::health;
+use cwe::nvme;
+use cwe::identity::OrgSecret;
+use cwe::partition;
+use cwe::progress::{ProgressEvent, WipePhase};
+use cwe::safety::{self, WipeOptions};
+use cwe::select::select_device;
+use cwe::transport::LinuxTransport;
+use cwe::wipe;
 
 // Main entry point for the utility
 // Working steps
-// 1. List Devices
+// 1. Pick the device (--device, or the interactive menu)
 // 2. Check wiping options for selected device
 //    -- Firmware supported sanitize
 //    -- Purge 
 //    -- Clear
 
+#[derive(Parser)]
+#[command(name = "cwe-cli", about = "Certified wipe engine")]
+struct Args {
+    /// Target device: /dev path, by-id:NAME, wwn:WWN, id:HASH or model:GLOB[,serial:GLOB]
+    #[arg(short, long)]
+    device: Option<String>,
 
-fn main(){
-    println!("Enumerating block devices");
+    /// List devices and exit
+    #[arg(short, long)]
+    list: bool,
+
+    /// With --list, print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Include serial numbers, WWNs and by-id links in the output (left out by default)
+    #[arg(long)]
+    show_serials: bool,
+
+    /// Recover an NVMe controller left in sanitize failure mode and exit
+    #[arg(long)]
+    exit_sanitize_failure: bool,
+
+    /// Unlock and disable the password an interrupted ATA security erase left on the drive, and exit
+    #[arg(long)]
+    ata_security_recover: bool,
+
+    /// Revert a self-encrypting drive to factory state with the PSID from its label, and exit.
+    /// Destroys the data on drives with media encryption; asks for confirmation like --wipe
+    #[arg(long, value_name = "PSID")]
+    psid_revert: Option<String>,
+
+    /// Wipe the device after the checks, printing progress
+    #[arg(long)]
+    wipe: bool,
+
+    /// With --wipe, the LBA format index an NVMe format switches to (default: the one in use)
+    #[arg(long, value_name = "INDEX")]
+    nvme_lba_format: Option<u8>,
+
+    /// With --wipe or --psid-revert, write the evidence record to PATH instead of printing it
+    #[arg(long, value_name = "PATH")]
+    evidence: Option<PathBuf>,
+
+    /// With --wipe or --psid-revert, skip typing the device path to confirm
+    #[arg(long)]
+    yes: bool,
+
+    /// With --wipe or --psid-revert, go ahead even when the disk is in use
+    #[arg(long)]
+    force: bool,
+}
+
+fn print_devices(devices: &[Device], show_serials: bool) {
+    println!("------------Devices------------");
+    let groups = group_by_controller(devices);
+    for (i, device) in devices.iter().enumerate() {
+        println!("#{} {} {} {:?} {} bytes", i, device.id, device.dev_path, device.bus, device.size_bytes);
+        if show_serials {
+            println!("    serial: {}", device.serial.as_deref().unwrap_or("none"));
+        }
+        if let Some(siblings) = device.controller.as_ref().and_then(|c| groups.get(c))
+            && siblings.len() > 1
+        {
+            let names: Vec<&str> = siblings.iter().map(|d| d.dev_path.as_str()).collect();
+            println!("    shares controller {} with: {}", device.controller.as_deref().unwrap_or(""), names.join(", "));
+        }
+    }
+}
+
+// The old numbered menu, only when a human is at the terminal.
+fn choose_interactively(devices: Vec<Device>) -> anyhow::Result<Device> {
+    if !io::stdin().is_terminal() {
+        bail!("no --device given and stdin is not a terminal");
+    }
+    if devices.is_empty() {
+        bail!("no devices found");
+    }
+    print_devices(&devices, false);
+
+    loop {
+        print!("Device to wipe: ");
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            bail!("no device chosen");
+        }
+        match input.trim().parse::<usize>() {
+            Ok(choice) if choice < devices.len() => return Ok(devices[choice].clone()),
+            _ => println!("Please type a number between 0 and {}", devices.len() - 1),
+        }
+    }
+}
+
+// One line per phase, rewritten in place while the phase runs.
+fn progress_printer() -> impl Fn(&ProgressEvent) + Send + Sync {
+    let last = Mutex::new(None::<WipePhase>);
+    move |e: &ProgressEvent| {
+        let mut last = last.lock().unwrap();
+        if last.is_some() && *last != Some(e.phase) {
+            println!();
+        }
+        *last = Some(e.phase);
+        print!("\r{}: {}   ", e.device, e);
+        if e.phase == WipePhase::Done {
+            println!();
+        }
+        let _ = io::stdout().flush();
+    }
+}
+
+fn confirm_wipe(dev: &Device) -> anyhow::Result<()> {
+    if !io::stdin().is_terminal() {
+        bail!("refusing to wipe without --yes, stdin is not a terminal");
+    }
+    print!("Everything on {} will be destroyed. Type the device path to confirm: ", dev.dev_path);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    if input.trim() != dev.dev_path {
+        bail!("confirmation did not match, nothing was wiped");
+    }
+    Ok(())
+}
+
+// The certificate with everything the wipe found and did, as JSON.
+fn save_evidence(ev: &WipeEvidence, path: Option<&Path>) -> anyhow::Result<()> {
+    let json = serde_json::to_string_pretty(ev)?;
+    match path {
+        Some(path) => {
+            fs::write(path, json + "\n").with_context(|| format!("writing evidence to {}", path.display()))?;
+            println!("Evidence {} written to {}", ev.certificate_id, path.display());
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // ids are keyed on this, the same drive keeps its id across runs
+    let (secret, created) = OrgSecret::load_noting_creation().context("loading organization secret")?;
+    // stderr, so it doesn't end up in --json output
+    if let Some(path) = created {
+        eprintln!("Created organization secret {}, copy it to every station that should share device ids", path.display());
+    }
+
+    if args.list {
+        let devices = enumerate_block_devices_linux(&secret).context("enumerating block devices")?;
+        if args.json {
+            let out = if args.show_serials {
+                devices.iter().map(|d| d.serialize_with_serial()).collect::<Result<Vec<_>, _>>()?
+            } else {
+                devices.iter().map(serde_json::to_value).collect::<Result<Vec<_>, _>>()?
+            };
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        } else {
+            print_devices(&devices, args.show_serials);
+        }
+        return Ok(());
+    }
 
     // Get the device to wipe
-    let mut dev = list_devices();
-    
+    let mut dev = match &args.device {
+        Some(spec) => select_device(spec, &secret)?,
+        None => {
+            println!("Enumerating block devices");
+            let devices = enumerate_block_devices_linux(&secret).context("enumerating block devices")?;
+            choose_interactively(devices)?
+        }
+    };
+    println!("Selected {} ({})", dev.dev_path, dev.id);
+
+    if args.exit_sanitize_failure {
+        if dev.devtype != DeviceType::Nvme {
+            bail!("{} is not an NVMe device", dev.dev_path);
+        }
+        let mut t = LinuxTransport::open_device(&dev).with_context(|| format!("opening {}", dev.dev_path))?;
+        nvme::exit_failure_mode(&mut t).context("Exit Failure Mode failed (without AUSE only a new sanitize clears the failure)")?;
+        println!("{} left sanitize failure mode", dev.dev_path);
+        return Ok(());
+    }
+    if args.ata_security_recover {
+        if dev.devtype != DeviceType::Sata {
+            bail!("{} is not an ATA device", dev.dev_path);
+        }
+        let mut t = LinuxTransport::open_device(&dev).with_context(|| format!("opening {}", dev.dev_path))?;
+        ata::security_recover(&mut t, &dev.id).context("ATA security recovery failed")?;
+        println!("{} is unlocked with security disabled, the interrupted erase has to be run again", dev.dev_path);
+        return Ok(());
+    }
+    if let Some(psid) = &args.psid_revert {
+        if !args.yes {
+            confirm_wipe(&dev)?;
+        }
+        let mut t = LinuxTransport::open_device(&dev).with_context(|| format!("opening {}", dev.dev_path))?;
+        let mut opts = WipeOptions { force: args.force, ..Default::default() };
+        opts.progress.subscribe(progress_printer());
+        let ev = wipe::psid_revert_with(&dev, &mut t, psid, &opts).context("PSID revert failed")?;
+        for line in &ev.logs {
+            println!("{}", line);
+        }
+        println!("Reverted {} with {} ({})", dev.dev_path, ev.method, ev.nist_level);
+        save_evidence(&ev, args.evidence.as_deref())?;
+        return Ok(());
+    }
+
+    // wipes refuse in-use disks, say so before anything else
+    match safety::preflight(&dev.dev_path) {
+        Ok(report) if !report.is_clear() => {
+            println!("{} is in use and will be refused without an override:", dev.dev_path);
+            for issue in &report.issues {
+                println!("    {}", issue);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => println!("Safety check failed: {}", e),
+    }
 
     // Check what kind of wiping device supports
 
     check_firmware_sanitize(&mut dev);
+    if let Some(sed) = &dev.sed {
+        println!("Self-encrypting drive: {}", sed.summary());
+    }
+    if let Some(status) = &dev.sanitize_status {
+        println!("Sanitize status: {}", status.summary());
+    }
+    println!("Sanitize mechanisms:");
+    for c in &dev.capabilities.capabilities {
+        println!("    [{}] {}", if c.available { "x" } else { " " }, c);
+    }
+    if let Some(best) = dev.capabilities.best() {
+        println!("Strongest available: {}", best.mechanism);
+    }
+
+    match LinuxTransport::open_device(&dev) {
+        Ok(mut t) => {
+            println!("Health: {}", health::snapshot(&dev, &mut t).summary());
+            match partition::read_inventory(&mut t) {
+                Ok(inv) => {
+                    println!("Contents: {}", inv.summary());
+                    for p in &inv.partitions {
+                        println!("    #{} {} sectors at {} {} {}", p.number, p.sectors, p.start_lba,
+                            p.type_name.as_deref().unwrap_or(&p.type_id),
+                            p.filesystem.map(|f| f.to_string()).unwrap_or_default());
+                    }
+                }
+                Err(e) => println!("Contents: could not read partition table: {}", e),
+            }
+        }
+        Err(e) => println!("Health: could not open {}: {}", dev.dev_path, e),
+    }
+
+    if args.wipe {
+        if !args.yes {
+            confirm_wipe(&dev)?;
+        }
+        let mut opts = WipeOptions { force: args.force, nvme_lba_format: args.nvme_lba_format, ..Default::default() };
+        opts.progress.subscribe(progress_printer());
+        let ev = wipe::wipe_device_opts(&mut dev, &opts).with_context(|| format!("wiping {}", dev.dev_path))?;
+        for line in &ev.logs {
+            println!("{}", line);
+        }
+        println!("Wiped {} with {} ({})", dev.dev_path, ev.method, ev.nist_level);
+        save_evidence(&ev, args.evidence.as_deref())?;
+    }
+    Ok(())
 }
@@ -0,0 +1,719 @@
+// ATA command helpers and IDENTIFY DEVICE decoding (word numbers per ACS-3).
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use crate::progress::ProgressReporter;
+use crate::transport::{AtaTaskfile, DriveTransport};
+
+pub const ATA_IDENTIFY_DEVICE: u8 = 0xEC;
+pub const ATA_SECURITY_SET_PASSWORD: u8 = 0xF1;
+pub const ATA_SECURITY_UNLOCK: u8 = 0xF2;
+pub const ATA_SECURITY_ERASE_PREPARE: u8 = 0xF3;
+pub const ATA_SECURITY_ERASE_UNIT: u8 = 0xF4;
+pub const ATA_SECURITY_DISABLE_PASSWORD: u8 = 0xF6;
+pub const ATA_READ_NATIVE_MAX_ADDRESS: u8 = 0xF8;
+pub const ATA_SET_MAX_ADDRESS: u8 = 0xF9;
+pub const ATA_READ_NATIVE_MAX_ADDRESS_EXT: u8 = 0x27;
+pub const ATA_SET_MAX_ADDRESS_EXT: u8 = 0x37;
+pub const ATA_DEVICE_CONFIGURATION: u8 = 0xB1;
+pub const ATA_TRUSTED_RECEIVE: u8 = 0x5C;
+pub const ATA_TRUSTED_SEND: u8 = 0x5E;
+pub const ATA_SANITIZE_DEVICE: u8 = 0xB4;
+
+// DEVICE CONFIGURATION sub-commands (features register)
+pub const DCO_RESTORE: u16 = 0xC0;
+pub const DCO_IDENTIFY: u16 = 0xC2;
+
+// SANITIZE DEVICE feature codes and the LBA signature each of them needs
+const SANITIZE_STATUS_EXT: u16 = 0x0000;
+const CRYPTO_SCRAMBLE_EXT: u16 = 0x0011;
+const BLOCK_ERASE_EXT: u16 = 0x0012;
+const OVERWRITE_EXT: u16 = 0x0014;
+const SANITIZE_FREEZE_LOCK_EXT: u16 = 0x0020;
+const SANITIZE_ANTIFREEZE_LOCK_EXT: u16 = 0x0040;
+const CRYPTO_SCRAMBLE_KEY: u64 = 0x4372_7970; // "Cryp"
+const BLOCK_ERASE_KEY: u64 = 0x426B_4572; // "BkEr"
+const OVERWRITE_KEY: u64 = 0x4F57; // "OW", LBA 47:32
+const FREEZE_LOCK_KEY: u64 = 0x4672_4C6B; // "FrLk"
+const ANTIFREEZE_LOCK_KEY: u64 = 0x416E_7469; // "Anti"
+const SANITIZE_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+// SECURITY ERASE UNIT doesn't return until the erase is done
+pub const ERASE_UNIT_TIMEOUT_SECS: u32 = 12 * 3600;
+
+// The per-run erase password is kept here until security is disabled again, so a
+// drive locked by an interrupted erase can be recovered.
+pub const DEFAULT_PASSWORD_DIR: &str = "/var/lib/cwe/ata-passwords";
+const PASSWORD_DIR_ENV: &str = "CWE_ATA_PASSWORD_DIR";
+
+/// Word 128, Security status.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AtaSecurity {
+    pub supported: bool,
+    pub enabled: bool,
+    pub locked: bool,
+    pub frozen: bool,
+    pub count_expired: bool,
+    pub enhanced_erase_supported: bool,
+    /// Master password capability: false = High, true = Maximum.
+    pub master_password_maximum: bool,
+}
+
+/// Word 59, SANITIZE feature set sub-commands. `frozen` and `antifreeze_locked`
+/// come from SANITIZE STATUS EXT and are filled in by the device probe.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AtaSanitize {
+    pub supported: bool,
+    pub crypto_scramble: bool,
+    pub overwrite: bool,
+    pub block_erase: bool,
+    pub antifreeze_lock: bool,
+    /// SANITIZE FREEZE LOCK was issued since power on, sanitize commands abort.
+    pub frozen: bool,
+    /// SANITIZE ANTIFREEZE LOCK was issued since power on, freeze locks abort.
+    pub antifreeze_locked: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AtaIdentify {
+    pub model: String,
+    #[serde(skip_serializing, default)]
+    pub serial: String,
+    pub firmware: String,
+    pub lba28_sectors: u32,
+    pub lba48_sectors: Option<u64>,
+    pub logical_sector_size: u32,
+    pub security: AtaSecurity,
+    /// Words 89/90, None when the drive doesn't report an estimate.
+    pub erase_time_minutes: Option<u32>,
+    pub enhanced_erase_time_minutes: Option<u32>,
+    pub sanitize: AtaSanitize,
+    /// Word 82 bit 10, Host Protected Area feature set.
+    pub hpa_supported: bool,
+    /// Word 83 bit 11, Device Configuration Overlay feature set.
+    pub dco_supported: bool,
+    /// Word 48 bit 0, Trusted Computing feature set (TRUSTED SEND/RECEIVE).
+    pub trusted_computing: bool,
+}
+
+impl AtaIdentify {
+    /// Decode a 512 byte IDENTIFY DEVICE page.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < 512 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "IDENTIFY DEVICE data shorter than 512 bytes"));
+        }
+        let w = |n: usize| u16::from_le_bytes([data[n * 2], data[n * 2 + 1]]);
+        let bit = |n: usize, b: u32| w(n) & (1 << b) != 0;
+
+        let lba28_sectors = (w(60) as u32) | ((w(61) as u32) << 16);
+        // word 83 bit 10: 48-bit Address feature set supported
+        let lba48_sectors = if bit(83, 10) {
+            Some((0..4).fold(0u64, |acc, i| acc | ((w(100 + i) as u64) << (16 * i))))
+        } else {
+            None
+        };
+
+        // word 106: bit 14 set / bit 15 clear means the word is valid, bit 12 = logical sector > 256 words
+        let w106 = w(106);
+        let logical_sector_size = if w106 & 0xC000 == 0x4000 && w106 & (1 << 12) != 0 {
+            ((w(117) as u32) | ((w(118) as u32) << 16)) * 2
+        } else {
+            512
+        };
+
+        let w128 = w(128);
+        let security = AtaSecurity {
+            supported: bit(82, 1) || w128 & 1 != 0,
+            enabled: w128 & (1 << 1) != 0,
+            locked: w128 & (1 << 2) != 0,
+            frozen: w128 & (1 << 3) != 0,
+            count_expired: w128 & (1 << 4) != 0,
+            enhanced_erase_supported: w128 & (1 << 5) != 0,
+            master_password_maximum: w128 & (1 << 8) != 0,
+        };
+
+        let sanitize = AtaSanitize {
+            supported: bit(59, 12),
+            crypto_scramble: bit(59, 13),
+            overwrite: bit(59, 14),
+            block_erase: bit(59, 15),
+            antifreeze_lock: bit(59, 10),
+            ..Default::default()
+        };
+
+        Ok(AtaIdentify {
+            model: ata_string(&data[27 * 2..47 * 2]),
+            serial: ata_string(&data[10 * 2..20 * 2]),
+            firmware: ata_string(&data[23 * 2..27 * 2]),
+            lba28_sectors,
+            lba48_sectors,
+            logical_sector_size,
+            security,
+            erase_time_minutes: erase_time(w(89)),
+            enhanced_erase_time_minutes: erase_time(w(90)),
+            sanitize,
+            hpa_supported: bit(82, 10),
+            dco_supported: bit(83, 11),
+            // word 48 is only valid with bit 14 set and bit 15 clear
+            trusted_computing: w(48) & 0xC001 == 0x4001,
+        })
+    }
+
+    /// Addressable sectors, preferring the 48-bit count.
+    pub fn sectors(&self) -> u64 {
+        match self.lba48_sectors {
+            Some(s) if s > 0 => s,
+            _ => self.lba28_sectors as u64,
+        }
+    }
+
+    pub fn capacity_bytes(&self) -> u64 {
+        self.sectors() * self.logical_sector_size as u64
+    }
+
+    pub fn supports_sanitize(&self, action: AtaSanitizeAction) -> bool {
+        let s = &self.sanitize;
+        s.supported && match action {
+            AtaSanitizeAction::CryptoScramble => s.crypto_scramble,
+            AtaSanitizeAction::BlockErase => s.block_erase,
+            AtaSanitizeAction::Overwrite => s.overwrite,
+        }
+    }
+
+    /// Strongest sanitize the drive supports: crypto scramble, then block erase,
+    /// then overwrite. None when there is none or the drive is sanitize frozen.
+    pub fn preferred_sanitize(&self) -> Option<AtaSanitizeAction> {
+        if self.sanitize.frozen {
+            return None;
+        }
+        [AtaSanitizeAction::CryptoScramble, AtaSanitizeAction::BlockErase, AtaSanitizeAction::Overwrite]
+            .into_iter()
+            .find(|a| self.supports_sanitize(*a))
+    }
+
+    /// Why SECURITY ERASE UNIT can't run right now, None if it can.
+    pub fn security_erase_blocker(&self) -> Option<&'static str> {
+        let s = &self.security;
+        if !s.supported {
+            Some("security feature set not supported")
+        } else if s.frozen {
+            Some("drive is security frozen (power cycle or hot-plug the drive to unfreeze)")
+        } else if s.count_expired {
+            Some("password attempt counter expired (power cycle required)")
+        } else if s.locked {
+            Some("drive is locked with an unknown password")
+        } else {
+            None
+        }
+    }
+}
+
+// Words 89/90: bit 15 selects the extended format (bits 14:0), otherwise bits 7:0.
+// The value is in 2 minute units, 0 = not reported, all ones = more than the max.
+fn erase_time(word: u16) -> Option<u32> {
+    let (value, max) = if word & 0x8000 != 0 {
+        (word & 0x7FFF, 0x7FFF)
+    } else {
+        (word & 0x00FF, 0x00FF)
+    };
+    match value {
+        0 => None,
+        v if v == max => Some(max as u32 * 2), // "more than", treat as the maximum
+        v => Some(v as u32 * 2),
+    }
+}
+
+// ATA strings store two characters per word, high byte first
+fn ata_string(raw: &[u8]) -> String {
+    let mut bytes = Vec::with_capacity(raw.len());
+    for pair in raw.chunks(2) {
+        bytes.push(pair[1]);
+        bytes.push(pair[0]);
+    }
+    String::from_utf8_lossy(&bytes).trim().trim_matches('\0').to_string()
+}
+
+/// Run IDENTIFY DEVICE and decode it.
+pub fn identify_device(t: &mut dyn DriveTransport) -> io::Result<AtaIdentify> {
+    let mut data = [0u8; 512];
+    let mut tf = AtaTaskfile::new(ATA_IDENTIFY_DEVICE).count(1).data_in();
+    t.ata_command(&mut tf, &mut data)?;
+    AtaIdentify::parse(&data)
+}
+
+// ---------- HPA / DCO ----------
+
+/// Capacity hidden from the host by a Host Protected Area and/or a Device
+/// Configuration Overlay, in sectors. Goes into the evidence as is.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AtaHiddenArea {
+    /// What IDENTIFY reports as addressable right now.
+    pub reported_sectors: u64,
+    /// READ NATIVE MAX ADDRESS + 1, the limit an HPA can be removed up to.
+    pub native_sectors: Option<u64>,
+    /// DEVICE CONFIGURATION IDENTIFY maximum + 1, the factory capacity.
+    pub dco_sectors: Option<u64>,
+    pub hpa_removed: bool,
+    pub dco_restored: bool,
+    /// Why a detection or restore step didn't happen.
+    pub notes: Vec<String>,
+}
+
+impl AtaHiddenArea {
+    pub fn hpa_hidden(&self) -> u64 {
+        self.native_sectors.map_or(0, |n| n.saturating_sub(self.reported_sectors))
+    }
+
+    pub fn dco_hidden(&self) -> u64 {
+        match (self.dco_sectors, self.native_sectors) {
+            (Some(d), Some(n)) => d.saturating_sub(n),
+            (Some(d), None) => d.saturating_sub(self.reported_sectors),
+            _ => 0,
+        }
+    }
+
+    pub fn hidden_sectors(&self) -> u64 {
+        self.hpa_hidden() + self.dco_hidden()
+    }
+}
+
+/// Decoded DEVICE CONFIGURATION IDENTIFY data (ACS-2 table "DCO data structure").
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DcoIdentify {
+    pub revision: u16,
+    /// Words 3-6, maximum LBA the overlay can allow.
+    pub max_lba: u64,
+}
+
+impl DcoIdentify {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < 512 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "DCO IDENTIFY data shorter than 512 bytes"));
+        }
+        let w = |n: usize| u16::from_le_bytes([data[n * 2], data[n * 2 + 1]]);
+        // word 255: 0xA5 signature in the low byte, checksum makes the block sum to 0
+        if data[510] == 0xA5 && data.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "DCO IDENTIFY checksum mismatch"));
+        }
+        Ok(DcoIdentify {
+            revision: w(0),
+            max_lba: (0..4).fold(0u64, |acc, i| acc | ((w(3 + i) as u64) << (16 * i))),
+        })
+    }
+}
+
+/// READ NATIVE MAX ADDRESS (EXT), returns the native max LBA (not a count).
+pub fn read_native_max(t: &mut dyn DriveTransport, lba48: bool) -> io::Result<u64> {
+    if lba48 {
+        let mut tf = AtaTaskfile::new(ATA_READ_NATIVE_MAX_ADDRESS_EXT).lba(0);
+        t.ata_command(&mut tf, &mut [])?;
+        Ok(tf.lba & 0xFFFF_FFFF_FFFF)
+    } else {
+        let mut tf = AtaTaskfile::new(ATA_READ_NATIVE_MAX_ADDRESS).lba(0);
+        t.ata_command(&mut tf, &mut [])?;
+        // 28-bit: LBA 27:24 come back in the device register
+        Ok((tf.lba & 0xFF_FFFF) | ((tf.device as u64 & 0x0F) << 24))
+    }
+}
+
+/// SET MAX ADDRESS (EXT). Must directly follow READ NATIVE MAX ADDRESS.
+/// `persistent` sets the VV bit so the limit survives a power cycle.
+pub fn set_max_address(t: &mut dyn DriveTransport, max_lba: u64, lba48: bool, persistent: bool) -> io::Result<()> {
+    let count = persistent as u16;
+    let mut tf = if lba48 {
+        AtaTaskfile::new(ATA_SET_MAX_ADDRESS_EXT).lba(max_lba).count(count)
+    } else {
+        let mut tf = AtaTaskfile::new(ATA_SET_MAX_ADDRESS).lba(max_lba & 0xFF_FFFF).count(count);
+        tf.device |= ((max_lba >> 24) & 0x0F) as u8;
+        tf
+    };
+    t.ata_command(&mut tf, &mut [])
+}
+
+pub fn dco_identify(t: &mut dyn DriveTransport) -> io::Result<DcoIdentify> {
+    let mut data = [0u8; 512];
+    let mut tf = AtaTaskfile::new(ATA_DEVICE_CONFIGURATION).features(DCO_IDENTIFY).data_in();
+    t.ata_command(&mut tf, &mut data)?;
+    DcoIdentify::parse(&data)
+}
+
+/// DEVICE CONFIGURATION RESTORE. The drive refuses it while an HPA is set.
+pub fn dco_restore(t: &mut dyn DriveTransport) -> io::Result<()> {
+    let mut tf = AtaTaskfile::new(ATA_DEVICE_CONFIGURATION).features(DCO_RESTORE);
+    t.ata_command(&mut tf, &mut [])
+}
+
+/// Find out how much capacity IDENTIFY isn't showing. Commands the drive rejects
+/// are noted, not treated as errors: most drives don't implement DCO at all.
+pub fn detect_hidden_area(t: &mut dyn DriveTransport, id: &AtaIdentify) -> AtaHiddenArea {
+    let lba48 = id.lba48_sectors.is_some();
+    let mut area = AtaHiddenArea { reported_sectors: id.sectors(), ..Default::default() };
+
+    if id.hpa_supported {
+        match read_native_max(t, lba48) {
+            Ok(max) => area.native_sectors = Some(max + 1),
+            Err(e) => area.notes.push(format!("READ NATIVE MAX ADDRESS failed: {}", e)),
+        }
+    }
+    if id.dco_supported {
+        match dco_identify(t) {
+            Ok(dco) => area.dco_sectors = Some(dco.max_lba + 1),
+            // a BIOS DCO FREEZE LOCK makes this abort
+            Err(e) => area.notes.push(format!("DEVICE CONFIGURATION IDENTIFY failed: {}", e)),
+        }
+    }
+    area
+}
+
+/// Remove the HPA, then restore the DCO, so the full factory capacity is addressable.
+pub fn restore_native_capacity(t: &mut dyn DriveTransport, id: &AtaIdentify, area: &mut AtaHiddenArea) -> io::Result<()> {
+    let lba48 = id.lba48_sectors.is_some();
+    if area.hpa_hidden() > 0 {
+        let native = read_native_max(t, lba48)?;
+        set_max_address(t, native, lba48, true)?;
+        area.hpa_removed = true;
+    }
+    if area.dco_hidden() > 0 {
+        dco_restore(t)?;
+        area.dco_restored = true;
+    }
+    Ok(())
+}
+
+// ---------- Security erase ----------
+
+/// Erase mode bit of the SECURITY ERASE UNIT control word.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SecurityEraseMode {
+    Normal,
+    Enhanced,
+}
+
+impl AtaIdentify {
+    /// How long the drive says SECURITY ERASE UNIT takes, in seconds.
+    pub fn erase_unit_estimate(&self, mode: SecurityEraseMode) -> Option<u32> {
+        let minutes = match mode {
+            SecurityEraseMode::Normal => self.erase_time_minutes,
+            SecurityEraseMode::Enhanced => self.enhanced_erase_time_minutes,
+        };
+        minutes.map(|m| m * 60)
+    }
+
+    /// Timeout for SECURITY ERASE UNIT: twice the drive's estimate plus half an hour,
+    /// `ERASE_UNIT_TIMEOUT_SECS` when it gives none.
+    pub fn erase_unit_timeout(&self, mode: SecurityEraseMode) -> u32 {
+        self.erase_unit_estimate(mode).map(|s| s * 2 + 1800).unwrap_or(ERASE_UNIT_TIMEOUT_SECS)
+    }
+}
+
+/// 512 byte data-out block used by the SECURITY commands: word 0 is the control
+/// word (identifier, erase mode, master capability), words 1-16 the password.
+pub fn security_block(password: &str, control: u16) -> [u8; 512] {
+    let mut data = [0u8; 512];
+    data[0..2].copy_from_slice(&control.to_le_bytes());
+    let pwd = password.as_bytes();
+    let n = pwd.len().min(32);
+    data[2..2 + n].copy_from_slice(&pwd[..n]);
+    data
+}
+
+/// SECURITY SET PASSWORD, user password, high security.
+pub fn security_set_password(t: &mut dyn DriveTransport, password: &str) -> io::Result<()> {
+    let mut data = security_block(password, 0);
+    let mut tf = AtaTaskfile::new(ATA_SECURITY_SET_PASSWORD).count(1).data_out();
+    t.ata_command(&mut tf, &mut data)
+}
+
+/// SECURITY UNLOCK with the user password.
+pub fn security_unlock(t: &mut dyn DriveTransport, password: &str) -> io::Result<()> {
+    let mut data = security_block(password, 0);
+    let mut tf = AtaTaskfile::new(ATA_SECURITY_UNLOCK).count(1).data_out();
+    t.ata_command(&mut tf, &mut data)
+}
+
+/// SECURITY DISABLE PASSWORD with the user password.
+pub fn security_disable_password(t: &mut dyn DriveTransport, password: &str) -> io::Result<()> {
+    let mut data = security_block(password, 0);
+    let mut tf = AtaTaskfile::new(ATA_SECURITY_DISABLE_PASSWORD).count(1).data_out();
+    t.ata_command(&mut tf, &mut data)
+}
+
+/// SECURITY ERASE PREPARE, must come right before SECURITY ERASE UNIT.
+pub fn security_erase_prepare(t: &mut dyn DriveTransport) -> io::Result<()> {
+    let mut tf = AtaTaskfile::new(ATA_SECURITY_ERASE_PREPARE);
+    t.ata_command(&mut tf, &mut [])
+}
+
+/// SECURITY ERASE UNIT with the user password. Blocks until the erase is done.
+pub fn security_erase_unit(t: &mut dyn DriveTransport, password: &str, mode: SecurityEraseMode, timeout_secs: u32) -> io::Result<()> {
+    let control = match mode {
+        SecurityEraseMode::Normal => 0,
+        SecurityEraseMode::Enhanced => 1 << 1,
+    };
+    let mut data = security_block(password, control);
+    let mut tf = AtaTaskfile::new(ATA_SECURITY_ERASE_UNIT).count(1).data_out().timeout(timeout_secs);
+    t.ata_command(&mut tf, &mut data)
+}
+
+fn password_file(device_id: &str) -> PathBuf {
+    let dir = std::env::var_os(PASSWORD_DIR_ENV).map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_PASSWORD_DIR));
+    dir.join(device_id)
+}
+
+// written and synced before the drive ever sees the password
+fn store_password(device_id: &str, password: &str) -> io::Result<()> {
+    let path = password_file(device_id);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut f = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("storing erase password in {}: {}", path.display(), e)))?;
+    writeln!(f, "{}", password)?;
+    f.sync_all()
+}
+
+/// The password a previous erase of this drive left behind, if any.
+pub fn stored_password(device_id: &str) -> Option<String> {
+    fs::read_to_string(password_file(device_id)).ok().map(|s| s.trim().to_string())
+}
+
+/// Full SECURITY ERASE UNIT workflow: check the drive can take it, set a random
+/// password for this run only, ERASE PREPARE + ERASE UNIT, then make sure security
+/// ended up disabled. Until it has, the password stays in the password directory
+/// under `device_id` for `security_recover`.
+pub fn security_erase(t: &mut dyn DriveTransport, device_id: &str, mode: SecurityEraseMode) -> io::Result<()> {
+    let id = identify_device(t)?;
+    if id.security.locked && stored_password(device_id).is_some() {
+        return Err(io::Error::other("drive is locked by an interrupted erase, run the security recovery first"));
+    }
+    if let Some(blocker) = id.security_erase_blocker() {
+        return Err(io::Error::other(blocker));
+    }
+    if mode == SecurityEraseMode::Enhanced && !id.security.enhanced_erase_supported {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "enhanced security erase not supported"));
+    }
+
+    let password = hex::encode(rand::rng().random::<[u8; 16]>());
+    store_password(device_id, &password)?;
+    security_set_password(t, &password)?;
+    security_erase_prepare(t)?;
+    security_erase_unit(t, &password, mode, id.erase_unit_timeout(mode)).map_err(|e| io::Error::new(e.kind(), format!(
+        "SECURITY ERASE UNIT failed, the drive may be left locked (recover with the password in {}): {}",
+        password_file(device_id).display(), e)))?;
+
+    // the erase should disable security by itself, not every drive does
+    let after = identify_device(t)?;
+    if after.security.enabled {
+        if after.security.locked {
+            security_unlock(t, &password)?;
+        }
+        security_disable_password(t, &password)?;
+        if identify_device(t)?.security.enabled {
+            return Err(io::Error::other("security still enabled after the erase"));
+        }
+    }
+    let _ = fs::remove_file(password_file(device_id));
+    Ok(())
+}
+
+/// Unlock a drive an interrupted erase left locked and disable its password, using
+/// the stored password of that run. The erase itself is not finished, wipe again.
+pub fn security_recover(t: &mut dyn DriveTransport, device_id: &str) -> io::Result<()> {
+    let id = identify_device(t)?;
+    if !id.security.enabled {
+        let _ = fs::remove_file(password_file(device_id));
+        return Ok(());
+    }
+    let password = stored_password(device_id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!(
+        "no stored erase password for {}, the drive needs its owner's or the master password", device_id)))?;
+    if id.security.frozen {
+        return Err(io::Error::other("drive is security frozen (power cycle or hot-plug the drive to unfreeze)"));
+    }
+    if id.security.locked {
+        security_unlock(t, &password)?;
+    }
+    security_disable_password(t, &password)?;
+    if identify_device(t)?.security.enabled {
+        return Err(io::Error::other("security still enabled after DISABLE PASSWORD"));
+    }
+    let _ = fs::remove_file(password_file(device_id));
+    Ok(())
+}
+
+// ---------- SANITIZE feature set ----------
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AtaSanitizeAction {
+    CryptoScramble,
+    BlockErase,
+    Overwrite,
+}
+
+/// A SANITIZE DEVICE command with its options, goes into the evidence.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AtaSanitizeCommand {
+    pub action: AtaSanitizeAction,
+    /// FAILURE MODE: a failed sanitize may be cleared through SANITIZE STATUS EXT.
+    /// Off means only another successful sanitize clears it.
+    pub allow_unrestricted_exit: bool,
+    /// OVERWRITE PASS COUNT, 1 to 16. Overwrite only.
+    pub overwrite_passes: u8,
+    /// INVERT PATTERN BETWEEN OVERWRITE PASSES. Overwrite only.
+    pub invert_between_passes: bool,
+    /// Overwrite pattern (LBA 31:0).
+    pub pattern: u32,
+}
+
+impl AtaSanitizeCommand {
+    /// Restricted failure mode, one overwrite pass of zeros.
+    pub fn new(action: AtaSanitizeAction) -> Self {
+        AtaSanitizeCommand { action, allow_unrestricted_exit: false, overwrite_passes: 1, invert_between_passes: false, pattern: 0 }
+    }
+
+    /// Refuse what the drive would reject anyway, before it gets the command.
+    pub fn check(&self, id: &AtaIdentify) -> io::Result<()> {
+        if !id.supports_sanitize(self.action) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("drive does not support sanitize {:?}", self.action)));
+        }
+        if id.sanitize.frozen {
+            return Err(io::Error::other("drive is sanitize frozen (power cycle to clear the freeze lock)"));
+        }
+        if self.action == AtaSanitizeAction::Overwrite && !(1..=16).contains(&self.overwrite_passes) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} overwrite passes, 1 to 16 allowed", self.overwrite_passes)));
+        }
+        Ok(())
+    }
+
+    // COUNT: FAILURE MODE bit 4, for overwrite INVERT bit 7 and the pass count in 3:0 (0 means 16)
+    fn taskfile(&self) -> AtaTaskfile {
+        let mut count = (self.allow_unrestricted_exit as u16) << 4;
+        let (feature, lba) = match self.action {
+            AtaSanitizeAction::CryptoScramble => (CRYPTO_SCRAMBLE_EXT, CRYPTO_SCRAMBLE_KEY),
+            AtaSanitizeAction::BlockErase => (BLOCK_ERASE_EXT, BLOCK_ERASE_KEY),
+            AtaSanitizeAction::Overwrite => {
+                count |= (self.overwrite_passes & 0xF) as u16 | (self.invert_between_passes as u16) << 7;
+                (OVERWRITE_EXT, OVERWRITE_KEY << 32 | self.pattern as u64)
+            }
+        };
+        AtaTaskfile::new(ATA_SANITIZE_DEVICE).features(feature).count(count).lba(lba)
+    }
+}
+
+/// SANITIZE STATUS EXT output.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AtaSanitizeStatus {
+    pub in_progress: bool,
+    /// The last sanitize completed without error.
+    pub completed: bool,
+    /// The last sanitize failed and the drive is in the failure state (error output, reason 1).
+    pub failed: bool,
+    pub frozen: bool,
+    pub antifreeze_locked: bool,
+    /// Progress of the running sanitize, 0xFFFF = 100%.
+    pub progress: u16,
+}
+
+// error output of a SANITIZE DEVICE command, reason in LBA 7:0
+fn sanitize_error(tf: &AtaTaskfile, e: io::Error) -> io::Error {
+    let reason = match tf.failed() {
+        true => match tf.lba & 0xFF {
+            1 => "sanitize operation failed",
+            2 => "sanitize command not supported",
+            3 => "drive is sanitize frozen",
+            4 => "antifreeze lock is set",
+            _ => return e,
+        },
+        false => return e,
+    };
+    io::Error::new(e.kind(), format!("{} ({})", e, reason))
+}
+
+/// SANITIZE STATUS EXT. `clear_failure` takes the drive out of the failure state of a
+/// sanitize issued with FAILURE MODE set; without it the drive keeps reporting the failure.
+pub fn sanitize_status(t: &mut dyn DriveTransport, clear_failure: bool) -> io::Result<AtaSanitizeStatus> {
+    let mut tf = AtaTaskfile::new(ATA_SANITIZE_DEVICE).features(SANITIZE_STATUS_EXT).count(clear_failure as u16).lba(0);
+    match t.ata_command(&mut tf, &mut []) {
+        // a failed sanitize is a status, not an error of the status command
+        Err(_) if tf.failed() && tf.lba & 0xFF == 1 => return Ok(AtaSanitizeStatus { failed: true, ..Default::default() }),
+        res => res.map_err(|e| sanitize_error(&tf, e))?,
+    }
+    Ok(AtaSanitizeStatus {
+        failed: false,
+        completed: tf.count & (1 << 15) != 0,
+        in_progress: tf.count & (1 << 14) != 0,
+        frozen: tf.count & (1 << 13) != 0,
+        antifreeze_locked: tf.count & (1 << 12) != 0,
+        progress: tf.lba as u16,
+    })
+}
+
+/// Start a sanitize. Returns once the drive has accepted it, see `sanitize_wait`.
+pub fn sanitize(t: &mut dyn DriveTransport, cmd: &AtaSanitizeCommand) -> io::Result<()> {
+    let mut tf = cmd.taskfile();
+    t.ata_command(&mut tf, &mut []).map_err(|e| sanitize_error(&tf, e))
+}
+
+/// Poll SANITIZE STATUS EXT until the running sanitize is done.
+pub fn sanitize_wait(t: &mut dyn DriveTransport, timeout_secs: u64, progress: &ProgressReporter) -> io::Result<()> {
+    let start = Instant::now();
+    loop {
+        let status = sanitize_status(t, false)?;
+        if status.failed {
+            return Err(io::Error::other("sanitize failed, the drive is in the sanitize failure state"));
+        }
+        if !status.in_progress {
+            return match status.completed {
+                true => Ok(()),
+                false => Err(io::Error::other("sanitize did not complete")),
+            };
+        }
+        progress.drive(status.progress);
+        if start.elapsed() > Duration::from_secs(timeout_secs) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for ATA sanitize"));
+        }
+        thread::sleep(SANITIZE_POLL_INTERVAL);
+    }
+}
+
+/// SANITIZE FREEZE LOCK EXT: sanitize commands abort until the next power cycle.
+pub fn sanitize_freeze_lock(t: &mut dyn DriveTransport) -> io::Result<()> {
+    let mut tf = AtaTaskfile::new(ATA_SANITIZE_DEVICE).features(SANITIZE_FREEZE_LOCK_EXT).lba(FREEZE_LOCK_KEY);
+    t.ata_command(&mut tf, &mut []).map_err(|e| sanitize_error(&tf, e))
+}
+
+/// SANITIZE ANTIFREEZE LOCK EXT: freeze locks abort until the next power cycle, so
+/// nothing can freeze the drive between the probe and the wipe.
+pub fn sanitize_antifreeze_lock(t: &mut dyn DriveTransport) -> io::Result<()> {
+    let mut tf = AtaTaskfile::new(ATA_SANITIZE_DEVICE).features(SANITIZE_ANTIFREEZE_LOCK_EXT).lba(ANTIFREEZE_LOCK_KEY);
+    t.ata_command(&mut tf, &mut []).map_err(|e| sanitize_error(&tf, e))
+}
+
+// ---------- Trusted Computing ----------
+
+// Count = transfer length 7:0 and LBA 7:0 = transfer length 15:8, in 512 byte
+// blocks; LBA 23:8 carry the protocol specific field (the TCG ComID).
+fn trusted_taskfile(command: u8, protocol: u8, comid: u16, len: usize) -> AtaTaskfile {
+    let blocks = (len / 512) as u64;
+    AtaTaskfile::new(command)
+        .features(protocol as u16)
+        .count((blocks & 0xFF) as u16)
+        .lba(((comid as u64) << 8) | (blocks >> 8))
+}
+
+/// TRUSTED RECEIVE (IF-RECV). `buf` must be a multiple of 512 bytes.
+pub fn trusted_receive(t: &mut dyn DriveTransport, protocol: u8, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    let mut tf = trusted_taskfile(ATA_TRUSTED_RECEIVE, protocol, comid, buf.len()).data_in();
+    t.ata_command(&mut tf, buf)
+}
+
+/// TRUSTED SEND (IF-SEND). `buf` must be a multiple of 512 bytes.
+pub fn trusted_send(t: &mut dyn DriveTransport, protocol: u8, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    let mut tf = trusted_taskfile(ATA_TRUSTED_SEND, protocol, comid, buf.len()).data_out();
+    t.ata_command(&mut tf, buf)
+}
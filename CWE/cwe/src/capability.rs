@@ -0,0 +1,307 @@
+// Per-device capability matrix: every sanitize mechanism the device could run,
+// whether it is usable right now, what blocks it and roughly how long it takes.
+//
+// Built by `check_firmware_sanitize` from the decoded IDENTIFY / Identify
+// Controller / INQUIRY / EXT_CSD data and the TCG discovery, and read by the planner and both UIs.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use crate::device::{Device, DeviceType};
+use crate::mmc::MmcErase;
+use crate::nvme::{self, NvmeSanitizeEstimates};
+use crate::sysfs;
+use crate::transport::DriveTransport;
+
+// rough sequential write rates for the overwrite estimate
+const HDD_BYTES_PER_SEC: u64 = 150 << 20;
+const SSD_BYTES_PER_SEC: u64 = 450 << 20;
+const NVME_BYTES_PER_SEC: u64 = 1500 << 20;
+// crypto erase only throws a key away
+const CRYPTO_ERASE_SECS: u64 = 10;
+
+/// NIST SP 800-88 Rev. 1 sanitization level.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NistLevel {
+    /// Not a sanitization method by itself (discard).
+    None,
+    Clear,
+    Purge,
+}
+
+impl fmt::Display for NistLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NistLevel::None => "no NIST level",
+            NistLevel::Clear => "Clear",
+            NistLevel::Purge => "Purge",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Mechanism {
+    NvmeSanitizeCrypto,
+    NvmeSanitizeBlock,
+    NvmeSanitizeOverwrite,
+    NvmeFormatCrypto,
+    NvmeFormatUserData,
+    AtaSanitizeCrypto,
+    AtaSanitizeBlock,
+    AtaSanitizeOverwrite,
+    AtaSecurityErase,
+    AtaEnhancedSecurityErase,
+    ScsiSanitizeCrypto,
+    ScsiSanitizeBlock,
+    ScsiSanitizeOverwrite,
+    ScsiFormatUnit,
+    MmcSanitize,
+    MmcSecureErase,
+    MmcSecureTrim,
+    MmcErase,
+    TcgCryptoErase,
+    Discard,
+    Overwrite,
+}
+
+impl Mechanism {
+    /// A sanitize / secure erase command, the family `WipeMethod::FirmwareErase` runs.
+    /// Format, TCG, plain MMC erase, discard and overwrite are not.
+    pub fn is_firmware_sanitize(&self) -> bool {
+        !matches!(
+            self,
+            Mechanism::NvmeFormatCrypto
+                | Mechanism::NvmeFormatUserData
+                | Mechanism::ScsiFormatUnit
+                | Mechanism::MmcErase
+                | Mechanism::TcgCryptoErase
+                | Mechanism::Discard
+                | Mechanism::Overwrite
+        )
+    }
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Mechanism::NvmeSanitizeCrypto => "NVMe sanitize, crypto erase",
+            Mechanism::NvmeSanitizeBlock => "NVMe sanitize, block erase",
+            Mechanism::NvmeSanitizeOverwrite => "NVMe sanitize, overwrite",
+            Mechanism::NvmeFormatCrypto => "NVMe format, crypto erase",
+            Mechanism::NvmeFormatUserData => "NVMe format, user data erase",
+            Mechanism::AtaSanitizeCrypto => "ATA sanitize, crypto scramble",
+            Mechanism::AtaSanitizeBlock => "ATA sanitize, block erase",
+            Mechanism::AtaSanitizeOverwrite => "ATA sanitize, overwrite",
+            Mechanism::AtaSecurityErase => "ATA security erase",
+            Mechanism::AtaEnhancedSecurityErase => "ATA enhanced security erase",
+            Mechanism::ScsiSanitizeCrypto => "SCSI sanitize, crypto erase",
+            Mechanism::ScsiSanitizeBlock => "SCSI sanitize, block erase",
+            Mechanism::ScsiSanitizeOverwrite => "SCSI sanitize, overwrite",
+            Mechanism::ScsiFormatUnit => "SCSI format unit",
+            Mechanism::MmcSanitize => "eMMC sanitize",
+            Mechanism::MmcSecureErase => "eMMC secure erase",
+            Mechanism::MmcSecureTrim => "eMMC secure trim",
+            Mechanism::MmcErase => "MMC/SD erase",
+            Mechanism::TcgCryptoErase => "TCG crypto erase",
+            Mechanism::Discard => "Discard (TRIM/UNMAP)",
+            Mechanism::Overwrite => "Software overwrite",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Capability {
+    pub mechanism: Mechanism,
+    pub nist_level: NistLevel,
+    pub available: bool,
+    /// Why it can't run, set whenever `available` is false.
+    pub blocker: Option<String>,
+    pub estimated_secs: Option<u64>,
+}
+
+impl Capability {
+    fn new(mechanism: Mechanism, nist_level: NistLevel, blocker: Option<&str>, estimated_secs: Option<u64>) -> Self {
+        Capability { mechanism, nist_level, available: blocker.is_none(), blocker: blocker.map(str::to_string), estimated_secs }
+    }
+}
+
+fn duration(secs: u64) -> String {
+    match secs {
+        0..=119 => format!("{} s", secs),
+        120..=7199 => format!("{} min", secs / 60),
+        _ => format!("{:.1} h", secs as f64 / 3600.0),
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}", self.mechanism, self.nist_level)?;
+        if let Some(secs) = self.estimated_secs {
+            write!(f, ", ~{}", duration(secs))?;
+        }
+        write!(f, ")")?;
+        if let Some(b) = &self.blocker {
+            write!(f, ": unavailable, {}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CapabilityReport {
+    pub capabilities: Vec<Capability>,
+}
+
+impl CapabilityReport {
+    pub fn available(&self) -> impl Iterator<Item = &Capability> {
+        self.capabilities.iter().filter(|c| c.available)
+    }
+
+    pub fn get(&self, m: Mechanism) -> Option<&Capability> {
+        self.capabilities.iter().find(|c| c.mechanism == m)
+    }
+
+    pub fn is_available(&self, m: Mechanism) -> bool {
+        self.available().any(|c| c.mechanism == m)
+    }
+
+    /// Some firmware mechanism can run, what `firmsan` used to say.
+    pub fn firmware_sanitize(&self) -> bool {
+        self.available().any(|c| c.mechanism.is_firmware_sanitize())
+    }
+
+    /// Strongest usable mechanism, the quickest one among equals.
+    pub fn best(&self) -> Option<&Capability> {
+        self.available().max_by(|a, b| {
+            a.nist_level.cmp(&b.nist_level).then_with(|| b.estimated_secs.unwrap_or(u64::MAX).cmp(&a.estimated_secs.unwrap_or(u64::MAX)))
+        })
+    }
+}
+
+fn overwrite_secs(dev: &Device) -> Option<u64> {
+    if dev.size_bytes == 0 {
+        return None;
+    }
+    let rate = match dev.devtype {
+        DeviceType::Nvme => NVME_BYTES_PER_SEC,
+        _ if dev.rotational => HDD_BYTES_PER_SEC,
+        _ => SSD_BYTES_PER_SEC,
+    };
+    Some(dev.size_bytes / rate)
+}
+
+fn nvme_capabilities(dev: &Device, est: &NvmeSanitizeEstimates, out: &mut Vec<Capability>) {
+    let Some(id) = &dev.nvme else { return };
+    let cap = &id.sanicap;
+    let secs = |v: Option<u32>| v.map(|s| s as u64);
+    out.push(Capability::new(Mechanism::NvmeSanitizeCrypto, NistLevel::Purge,
+        (!cap.crypto_erase).then_some("not in SANICAP"), secs(est.crypto_erase_secs).or(Some(CRYPTO_ERASE_SECS))));
+    out.push(Capability::new(Mechanism::NvmeSanitizeBlock, NistLevel::Purge,
+        (!cap.block_erase).then_some("not in SANICAP"), secs(est.block_erase_secs)));
+    out.push(Capability::new(Mechanism::NvmeSanitizeOverwrite, NistLevel::Purge,
+        (!cap.overwrite).then_some("not in SANICAP"), secs(est.overwrite_secs).or(overwrite_secs(dev))));
+
+    let no_format = (!id.oacs.format_nvm).then_some("Format NVM not supported");
+    out.push(Capability::new(Mechanism::NvmeFormatCrypto, NistLevel::Purge,
+        no_format.or((!id.fna.crypto_erase_supported).then_some("FNA reports no cryptographic erase")), Some(CRYPTO_ERASE_SECS)));
+    // user data erase is vendor defined, may be a mapping reset only
+    out.push(Capability::new(Mechanism::NvmeFormatUserData, NistLevel::Clear, no_format, None));
+}
+
+fn ata_capabilities(dev: &Device, out: &mut Vec<Capability>) {
+    let Some(id) = &dev.ata else { return };
+    let minutes = |m: Option<u32>| m.map(|m| m as u64 * 60);
+
+    let s = &id.sanitize;
+    let no_sanitize = (!s.supported).then_some("SANITIZE feature set not supported")
+        .or(s.frozen.then_some("sanitize frozen (power cycle to clear)"));
+    out.push(Capability::new(Mechanism::AtaSanitizeCrypto, NistLevel::Purge,
+        no_sanitize.or((!s.crypto_scramble).then_some("CRYPTO SCRAMBLE not supported")), Some(CRYPTO_ERASE_SECS)));
+    out.push(Capability::new(Mechanism::AtaSanitizeBlock, NistLevel::Purge,
+        no_sanitize.or((!s.block_erase).then_some("BLOCK ERASE not supported")), None));
+    out.push(Capability::new(Mechanism::AtaSanitizeOverwrite, NistLevel::Purge,
+        no_sanitize.or((!s.overwrite).then_some("OVERWRITE not supported")), overwrite_secs(dev)));
+
+    // SP 800-88 takes security erase as Purge on hard disks; on SSDs only the
+    // enhanced variant is trusted to reach every cell
+    let blocker = id.security_erase_blocker();
+    let normal_level = if dev.rotational { NistLevel::Purge } else { NistLevel::Clear };
+    out.push(Capability::new(Mechanism::AtaSecurityErase, normal_level, blocker, minutes(id.erase_time_minutes)));
+    out.push(Capability::new(Mechanism::AtaEnhancedSecurityErase, NistLevel::Purge,
+        (!id.security.enhanced_erase_supported).then_some("enhanced erase not supported").or(blocker),
+        minutes(id.enhanced_erase_time_minutes)));
+}
+
+fn scsi_capabilities(dev: &Device, out: &mut Vec<Capability>) {
+    let Some(info) = &dev.scsi else { return };
+    let s = &info.sanitize;
+    // devices that don't report their actions get them tried, they count as available
+    let blocker = |supported: bool| (s.reported && !supported).then_some("not reported by the device");
+    out.push(Capability::new(Mechanism::ScsiSanitizeCrypto, NistLevel::Purge, blocker(s.crypto_erase), Some(CRYPTO_ERASE_SECS)));
+    out.push(Capability::new(Mechanism::ScsiSanitizeBlock, NistLevel::Purge, blocker(s.block_erase), None));
+    out.push(Capability::new(Mechanism::ScsiSanitizeOverwrite, NistLevel::Purge, blocker(s.overwrite), overwrite_secs(dev)));
+    // rewrites the medium on most disks, nothing is promised for reallocated blocks
+    out.push(Capability::new(Mechanism::ScsiFormatUnit, NistLevel::Clear, None, overwrite_secs(dev)));
+}
+
+fn mmc_capabilities(dev: &Device, out: &mut Vec<Capability>) {
+    let Some(info) = &dev.mmc else { return };
+    let methods = info.methods();
+    let has = |m: MmcErase| methods.contains(&m);
+    let no_ext_csd = info.ext_csd.is_none().then_some("SD cards have no EXT_CSD");
+    out.push(Capability::new(Mechanism::MmcSanitize, NistLevel::Purge,
+        no_ext_csd.or((!has(MmcErase::Sanitize)).then_some("SANITIZE not supported")), None));
+    out.push(Capability::new(Mechanism::MmcSecureErase, NistLevel::Purge,
+        no_ext_csd.or((!has(MmcErase::SecureErase)).then_some("secure erase not supported")), None));
+    out.push(Capability::new(Mechanism::MmcSecureTrim, NistLevel::Purge,
+        no_ext_csd.or((!has(MmcErase::SecureTrim)).then_some("secure trim not supported")), None));
+    // plain ERASE resets the mapping, the cells may keep their charge
+    out.push(Capability::new(Mechanism::MmcErase, NistLevel::Clear, None, None));
+}
+
+// The wipe only has the factory credentials (MSID). An owned drive still has the
+// PSID revert, that needs the label and is up to the operator.
+fn tcg_blocker(dev: &Device) -> Option<&'static str> {
+    let Some(sed) = &dev.sed else { return Some("no TCG Opal/Enterprise security subsystem") };
+    if !sed.media_encryption {
+        return Some("drive doesn't encrypt (Pyrite), there is no key to erase");
+    }
+    if sed.is_enterprise() {
+        return None;
+    }
+    if sed.sid_is_msid == Some(false) {
+        return Some("SID password was changed, needs the owner's password or a PSID revert");
+    }
+    if sed.locking_enabled {
+        // activated with SID still at MSID: Admin1 most likely is too
+        return (sed.sid_is_msid != Some(true)).then_some("Locking SP is owned, needs the Admin1 password or a PSID revert");
+    }
+    sed.sid_blocked.then_some("SID authentication is blocked until the next power cycle")
+}
+
+/// Build the report from what the probes stored on `dev`. NVMe sanitize time
+/// estimates are read from the controller on the way.
+pub fn assess(dev: &Device, t: &mut dyn DriveTransport) -> CapabilityReport {
+    let mut caps = Vec::new();
+    match dev.devtype {
+        DeviceType::Nvme => {
+            let est = nvme::sanitize_estimates(t).unwrap_or_default();
+            nvme_capabilities(dev, &est, &mut caps);
+        }
+        DeviceType::Sata => ata_capabilities(dev, &mut caps),
+        DeviceType::Scsi => scsi_capabilities(dev, &mut caps),
+        DeviceType::Mmc => mmc_capabilities(dev, &mut caps),
+        DeviceType::Unknown => {}
+    }
+
+    if matches!(dev.devtype, DeviceType::Nvme | DeviceType::Sata | DeviceType::Scsi) {
+        caps.push(Capability::new(Mechanism::TcgCryptoErase, NistLevel::Purge, tcg_blocker(dev), Some(CRYPTO_ERASE_SECS)));
+    }
+
+    let discard = sysfs::discard_max_bytes(&dev.dev_path) > 0;
+    caps.push(Capability::new(Mechanism::Discard, NistLevel::None,
+        (!discard).then_some("device doesn't accept discards"), Some(CRYPTO_ERASE_SECS)));
+    caps.push(Capability::new(Mechanism::Overwrite, NistLevel::Clear, None, overwrite_secs(dev)));
+
+    CapabilityReport { capabilities: caps }
+}
@@ -1,115 +1,184 @@
 use serde::{Deserialize, Serialize};
-use sha2::{Sha256, Digest};
 use anyhow::Result;
 use std::path::Path;
-use hex;
-use std::fs::File;
 use std::io;
-use std::io::Write;
-use libc::{c_void, ioctl};
-use std::os::unix::io::AsRawFd;
-
-pub const HDIO_DRIVE_CMD: u64 = 0x031f;
-pub const NVME_IOCTL_ADMIN_CMD: u64 = 0xC0484E41; // _IOWR('N', 0x41, struct nvme_admin_cmd)
+use crate::ata::{self, AtaIdentify};
+use crate::capability::{self, CapabilityReport};
+use crate::nvme::{self, NvmeIdController, NvmeNamespace};
+use crate::identity::OrgSecret;
+use crate::mmc::{self, MmcInfo};
+use crate::resume::{self, SanitizePhase, SanitizeReport};
+use crate::scsi::{self, ScsiInfo};
+use crate::sysfs::{self, BusType};
+use crate::tcg::{self, SedStatus};
+use crate::transport::{nvme_ctrl_path, DriveTransport, LinuxTransport};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum DeviceType{
     Nvme,
     Sata,
+    Scsi,   // SAS / parallel SCSI, anything sd* that isn't ATA
+    Mmc,    // eMMC and SD cards
     Unknown,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Device {
-    pub id:         String,        // hash id
+    pub id:         String,        // HMAC of the serial under the org secret, see identity.rs
     pub dev_path:   String,  // /dev/sdX ya fir  /dev/nvme0n1
     pub model:      Option<String>,
+    #[serde(skip_serializing, default)] // only through serialize_with_serial
     pub serial:     Option<String>,
     pub vendor:     Option<String>,
     pub devtype:    DeviceType,
-    pub firmsan:    bool,   
+    pub capabilities: CapabilityReport, // what sanitize mechanisms can run, filled by check_firmware_sanitize
+    pub ata:        Option<AtaIdentify>, // decoded IDENTIFY DEVICE, SATA only
+    pub nvme:       Option<NvmeIdController>, // decoded Identify Controller, NVMe only
+    pub controller: Option<String>, // NVMe controller the namespace lives on, e.g. /dev/nvme0
+    pub nvme_namespaces: Vec<NvmeNamespace>, // every namespace a controller-level sanitize hits
+    pub scsi:       Option<ScsiInfo>, // INQUIRY/capacity/sanitize support, SCSI only
+    pub mmc:        Option<MmcInfo>,  // EXT_CSD erase features, eMMC/SD only
+    pub sed:        Option<SedStatus>, // TCG Level 0 discovery, self-encrypting drives only
+    pub sanitize_status: Option<SanitizeReport>, // running or last sanitize as the drive reports it
+    pub size_bytes: u64,
+    pub logical_block_size:  u32,
+    pub physical_block_size: u32,
+    pub rotational: bool,
+    pub removable:  bool,
+    pub bus:        BusType,          // what the kernel sees the disk on
+    pub firmware:   Option<String>,
+    #[serde(skip_serializing, default)] // identifies the drive like the serial does
+    pub wwn:        Option<String>,
+    #[serde(skip_serializing, default)] // link names embed the serial, ata-MODEL_SERIAL
+    pub by_id:      Vec<String>,      // /dev/disk/by-id links, stable across reboots
 }
 
 
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct nvme_admin_cmd {
-    pub opcode: u8,
-    pub flags: u8,
-    pub rsvd1: u16,
-    pub nsid: u32,
-    pub cdw2: u32,
-    pub cdw3: u32,
-    pub metadata: u64,
-    pub addr: u64,
-    pub metadata_len: u32,
-    pub data_len: u32,
-    pub cdw10: u32,
-    pub cdw11: u32,
-    pub cdw12: u32,
-    pub cdw13: u32,
-    pub cdw14: u32,
-    pub cdw15: u32,
-    pub timeout_ms: u32,
-    pub result: u32,
-}
-
-
 impl Device {
-    /// Create a device from basic info and  hashes serial for privacy.
-    pub fn new(dev_path: &str, model: Option<&str>, serial: Option<&str>, vendor: Option<&str>, devtype: DeviceType, run_salt: &str) -> Self {
-        let mut hasher = Sha256::new();
-        if let Some(s) = serial {
-            hasher.update(s.as_bytes());
-        }
-        hasher.update(run_salt.as_bytes());
-        let id = hex::encode(hasher.finalize());
+    /// Create a device from basic info, the id is keyed on the serial so it stays private.
+    pub fn new(dev_path: &str, model: Option<&str>, serial: Option<&str>, vendor: Option<&str>, devtype: DeviceType, secret: &OrgSecret) -> Self {
+        let id = secret.device_id(serial, dev_path);
         Device {
             id,
             dev_path: dev_path.to_string(),
             model: model.map(|s| s.to_string()),
             serial: serial.map(|s| s.to_string()),
             vendor: vendor.map(|s| s.to_string()),
-            devtype: devtype,
-            firmsan: false,
+            devtype,
+            capabilities: CapabilityReport::default(),
+            ata: None,
+            nvme: None,
+            controller: None,
+            nvme_namespaces: Vec::new(),
+            scsi: None,
+            mmc: None,
+            sed: None,
+            sanitize_status: None,
+            size_bytes: 0,
+            logical_block_size: 512,
+            physical_block_size: 512,
+            rotational: false,
+            removable: false,
+            bus: BusType::Unknown,
+            firmware: None,
+            wwn: None,
+            by_id: Vec::new(),
         }
     }
 
     pub fn exists(&self) -> bool {
         Path::new(&self.dev_path).exists()
     }
+
+    /// Serialize including the serial number, WWN and by-id links. Plain `Serialize`
+    /// leaves them out, call this only where the serial is explicitly wanted.
+    pub fn serialize_with_serial(&self) -> serde_json::Result<serde_json::Value> {
+        let mut v = serde_json::to_value(self)?;
+        if let Some(obj) = v.as_object_mut() {
+            obj.insert("serial".to_string(), serde_json::to_value(&self.serial)?);
+            obj.insert("wwn".to_string(), serde_json::to_value(&self.wwn)?);
+            obj.insert("by_id".to_string(), serde_json::to_value(&self.by_id)?);
+            if let (Some(inquiry), Some(info)) = (obj.get_mut("scsi").and_then(|s| s.get_mut("inquiry")).and_then(|i| i.as_object_mut()), &self.scsi) {
+                inquiry.insert("wwn".to_string(), serde_json::to_value(&info.inquiry.wwn)?);
+            }
+        }
+        Ok(v)
+    }
 }
 
-pub fn enumerate_block_devices_linux(run_salt: &str) -> Result<Vec<Device>> {
+pub fn enumerate_block_devices_linux(secret: &OrgSecret) -> Result<Vec<Device>> {
     let mut devices = Vec::new();
+    let aliases = sysfs::by_id_aliases();
     let sys_block = std::fs::read_dir("/sys/block")?;
     for entry in sys_block {
         let entry = entry?;
-        
         let name = entry.file_name().into_string().unwrap_or_default();
-        if name.starts_with("loop") || name.starts_with("ram") { continue; }
-        
-        let dev_path = format!("/dev/{}", name);
-        
-        let dev_dir = format!("/sys/block/{}/device", name);
-        
-        let model = std::fs::read_to_string(format!("{}/model", dev_dir)).ok().map(|s| s.trim().to_string());
-        
-        let vendor = std::fs::read_to_string(format!("{}/vendor", dev_dir)).ok().map(|s| s.trim().to_string());
-        
-        let serial = std::fs::read_to_string(format!("{}/serial", dev_dir)).ok().map(|s| s.trim().to_string());
-
-        let device_type = device_type(&dev_path);
-        let d = Device::new(&dev_path, model.as_deref(), serial.as_deref(), vendor.as_deref(), device_type, run_salt);
-        devices.push(d);
+        if let Some(d) = device_from_sysfs(&name, secret, &aliases) {
+            devices.push(d);
+        }
     }
+    devices.sort_by(|a, b| a.dev_path.cmp(&b.dev_path));
     Ok(devices)
 }
 
-pub fn find_device_by_path(dev_path: &str, run_salt: &str) -> std::io::Result<Device> {
-    let devices = enumerate_block_devices_linux(run_salt)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+/// Fresh `Device` for one /sys/block entry, None for anything that isn't a wipeable disk.
+pub fn probe_block_device(name: &str, secret: &OrgSecret) -> Option<Device> {
+    device_from_sysfs(name, secret, &sysfs::by_id_aliases())
+}
+
+fn device_from_sysfs(name: &str, secret: &OrgSecret, aliases: &HashMap<String, Vec<String>>) -> Option<Device> {
+    let info = sysfs::read_block(name);
+    // loop/ram/zram/dm/md: nothing physical behind them to sanitize
+    if info.is_virtual() { return None; }
+    // card readers and optical drives with no media
+    if info.size_bytes == 0 { return None; }
+    // eMMC hardware boot partitions belong to the card listed as mmcblkN
+    if info.bus == BusType::Mmc && name.contains("boot") { return None; }
+
+    let dev_path = format!("/dev/{}", name);
+
+    let device_type = device_type(info.bus, info.vendor.as_deref());
+    let mut d = Device::new(&dev_path, info.model.as_deref(), info.serial.as_deref(), info.vendor.as_deref(), device_type, secret);
+    if d.devtype == DeviceType::Nvme {
+        d.controller = Some(nvme_controller_of(name));
+    }
+    d.size_bytes = info.size_bytes;
+    d.logical_block_size = info.logical_block_size;
+    d.physical_block_size = info.physical_block_size;
+    d.rotational = info.rotational;
+    d.removable = info.removable;
+    d.bus = info.bus;
+    d.firmware = info.firmware;
+    d.wwn = info.wwn;
+    d.by_id = aliases.get(name).cloned().unwrap_or_default();
+    Some(d)
+}
+
+// /sys/block/nvme0n1/device links to the controller (.../nvme/nvme0)
+fn nvme_controller_of(name: &str) -> String {
+    std::fs::read_link(format!("/sys/block/{}/device", name))
+        .ok()
+        .and_then(|p| p.file_name().map(|f| format!("/dev/{}", f.to_string_lossy())))
+        .unwrap_or_else(|| nvme_ctrl_path(&format!("/dev/{}", name)))
+}
+
+/// Group NVMe namespaces by controller. A sanitize issued through any of them
+/// destroys all of them.
+pub fn group_by_controller(devices: &[Device]) -> BTreeMap<String, Vec<&Device>> {
+    let mut groups: BTreeMap<String, Vec<&Device>> = BTreeMap::new();
+    for d in devices {
+        if let Some(ctrl) = &d.controller {
+            groups.entry(ctrl.clone()).or_default().push(d);
+        }
+    }
+    groups
+}
+
+pub fn find_device_by_path(dev_path: &str, secret: &OrgSecret) -> std::io::Result<Device> {
+    let devices = enumerate_block_devices_linux(secret)
+        .map_err(std::io::Error::other)?;
     
     for device in devices {
         if device.dev_path == dev_path {
@@ -122,135 +191,156 @@ pub fn find_device_by_path(dev_path: &str, run_salt: &str) -> std::io::Result<De
         format!("Device not found: {}", dev_path)
     ))
 }
-pub fn list_devices() -> Device {
-    
-    let enum_res = enumerate_block_devices_linux("run");
+// The command set follows from the bus, not from the kernel name.
+fn device_type(bus: BusType, vendor: Option<&str>) -> DeviceType {
+    match bus {
+        BusType::Nvme => DeviceType::Nvme,
+        BusType::Ata => DeviceType::Sata,
+        BusType::Mmc => DeviceType::Mmc,
+        // libata reports every ATA disk with the "ATA" vendor string. USB bridges
+        // report their own vendor, those get promoted back to Sata once IDENTIFY works.
+        BusType::Usb | BusType::Scsi => {
+            if vendor == Some("ATA") { DeviceType::Sata } else { DeviceType::Scsi }
+        }
+        // virtio/xen have no sanitize passthrough here, overwrite only
+        _ => DeviceType::Unknown,
+    }
+}
 
-    match enum_res{
-        Ok(devices) => {
-            let mut i : u32 = 0;
-            println!("------------Devices------------");
-            for device in &devices {
-                println!("#{} {} {}",i,device.id,device.dev_path);
-                i+=1;
+fn check_ata_secure_erase(dev: &mut Device, t: &mut dyn DriveTransport) -> io::Result<()> {
+    let mut id = ata::identify_device(t)?;
+    // the freeze state is only in SANITIZE STATUS EXT
+    if id.sanitize.supported {
+        match ata::sanitize_status(t, false) {
+            Ok(status) => {
+                id.sanitize.frozen = status.frozen;
+                id.sanitize.antifreeze_locked = status.antifreeze_locked;
+                if status.frozen {
+                    println!("{}: sanitize frozen by SANITIZE FREEZE LOCK, power cycle the drive to use it", dev.dev_path);
+                }
             }
-
-            print!("Device to wipe: ");
-            io::stdout().flush().unwrap();
-            
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).expect("error: unable to read user input");
-            let choice : usize = input.trim().parse().expect("Please type a valid number");
-
-            devices[choice].clone()
-
+            Err(e) => println!("SANITIZE STATUS EXT on {} failed: {}", dev.dev_path, e),
         }
-        Err(e) => panic!("Error trying to enumerate devices : {}",e),
     }
+    dev.ata = Some(id);
+    Ok(())
 }
 
-fn device_type(dev_path: &str) -> DeviceType {
-    
-    if dev_path.contains("nvme"){
-        DeviceType::Nvme
-    }else if dev_path.contains("sd"){
-        DeviceType::Sata
-    }else{
-        DeviceType::Unknown
+
+fn check_nvme_sanitize(dev: &mut Device, t: &mut dyn DriveTransport) -> io::Result<()> {
+    let ctrl = nvme::scan_controller(t)?;
+    if dev.controller.is_none() {
+        dev.controller = Some(ctrl.ctrl_path);
     }
+    dev.nvme = Some(ctrl.id);
+    dev.nvme_namespaces = ctrl.namespaces;
+    Ok(())
 }
 
-fn check_ata_secure_erase(dev_path: &str) -> io::Result<bool> {   
-    let file = File::open(dev_path)?;
-    let fd = file.as_raw_fd();
+fn check_scsi_sanitize(dev: &mut Device, t: &mut dyn DriveTransport) -> io::Result<()> {
+    let info = scsi::probe(t)?;
+    if !info.sanitize.reported {
+        println!("{}: device doesn't report supported SANITIZE actions, they will be tried", dev.dev_path);
+    }
+    dev.scsi = Some(info);
+    Ok(())
+}
 
-    // buffer: first byte = command, second = sector count, rest = data
-    let mut args: [u8; 4 + 512] = [0; 4 + 512];
-    args[0] = 0xEC; // IDENTIFY DEVICE command
+fn check_mmc_sanitize(dev: &mut Device, t: &mut dyn DriveTransport) -> io::Result<()> {
+    let card_type = mmc::card_type(&dev.dev_path).unwrap_or(mmc::MmcCardType::Mmc);
+    let info = mmc::probe(t, card_type)?;
+    if let Some(e) = &info.ext_csd {
+        println!("{}: eMMC rev {}", dev.dev_path, e.rev);
+    }
+    dev.mmc = Some(info);
+    Ok(())
+}
 
-    let ret = unsafe {
-        ioctl(fd, HDIO_DRIVE_CMD as _, args.as_mut_ptr() as *mut c_void)
+// Only asked where the drive advertises the security commands. SCSI has no such
+// bit, SECURITY PROTOCOL IN is just tried and a rejection means no SED.
+fn check_tcg(dev: &mut Device, t: &mut dyn DriveTransport) {
+    let advertised = match dev.devtype {
+        DeviceType::Sata => dev.ata.as_ref().is_some_and(|id| id.trusted_computing),
+        DeviceType::Nvme => dev.nvme.as_ref().is_some_and(|id| id.oacs.security_send_recv),
+        DeviceType::Scsi => true,
+        _ => false,
     };
-
-    if ret < 0 {
-        return Err(io::Error::last_os_error());
+    if !advertised {
+        return;
+    }
+    match tcg::discover(t, &dev.devtype) {
+        Ok(s) if s.ssc.is_some() => {
+            println!("{}: TCG {}", dev.dev_path, s.summary());
+            dev.sed = Some(s);
+        }
+        Ok(_) => println!("{}: TCG TPer without a known SSC", dev.dev_path),
+        Err(e) if dev.devtype != DeviceType::Scsi => println!("TCG discovery on {} failed: {}", dev.dev_path, e),
+        Err(_) => {}
     }
-
-    // IDENTIFY DEVICE result is at offset 4
-    let data = &args[4..];
-
-    // Word 82-83 = supported features
-    let word82 = u16::from_le_bytes([data[82*2], data[82*2+1]]);
-    let word89 = u16::from_le_bytes([data[89*2], data[89*2+1]]);
-
-    let security_supported = (word82 & (1 << 1)) != 0; // "Security feature set"
-    let sanitize_supported = (word89 & (1 << 13)) != 0; // Sanitize Device feature
-
-    Ok(security_supported || sanitize_supported)
 }
 
-
-fn check_nvme_sanitize(dev_path: &str) -> io::Result<bool> {
-    let file = File::open(dev_path)?;
-    let fd = file.as_raw_fd();
-
-    let mut ctrl_data = vec![0u8; 4096]; // Identify Controller returns 4096 bytes
-
-    let mut cmd = nvme_admin_cmd {
-        opcode: 0x06, // Identify
-        flags: 0,
-        rsvd1: 0,
-        nsid: 0, // CNS=1 => Identify Controller
-        cdw2: 0,
-        cdw3: 0,
-        metadata: 0,
-        addr: ctrl_data.as_mut_ptr() as u64,
-        metadata_len: 0,
-        data_len: ctrl_data.len() as u32,
-        cdw10: 1, // CNS=1 -> controller
-        cdw11: 0,
-        cdw12: 0,
-        cdw13: 0,
-        cdw14: 0,
-        cdw15: 0,
-        timeout_ms: 0,
-        result: 0,
+// A crash or reboot mid-sanitize leaves the drive still at it, say so up front.
+fn check_sanitize_status(dev: &mut Device, t: &mut dyn DriveTransport) {
+    let reports = match dev.devtype {
+        DeviceType::Nvme => dev.nvme.as_ref().is_some_and(|id| id.supports_sanitize()),
+        DeviceType::Sata => dev.ata.as_ref().is_some_and(|id| id.sanitize.supported),
+        DeviceType::Scsi => true,
+        _ => false,
     };
-
-    let ret = unsafe { ioctl(fd, NVME_IOCTL_ADMIN_CMD, &mut cmd) };
-    if ret < 0 {
-        return Err(io::Error::last_os_error());
+    if !reports {
+        return;
+    }
+    match resume::query(t, &dev.devtype) {
+        Ok(Some(report)) => {
+            if report.in_progress() || report.phase == SanitizePhase::Failed {
+                println!("{}: {}", dev.dev_path, report.summary());
+            }
+            dev.sanitize_status = Some(report);
+        }
+        Ok(None) => {}
+        Err(e) => println!("Sanitize status of {} unavailable: {}", dev.dev_path, e),
     }
-
-    // Sanitize Capabilities at byte offset 536 (dword 267)
-    let sanicap_offset = 536;
-    let sanicap = u16::from_le_bytes([
-        ctrl_data[sanicap_offset],
-        ctrl_data[sanicap_offset + 1],
-    ]);
-
-    Ok(sanicap != 0)
 }
 
 pub fn check_firmware_sanitize(dev: &mut Device) {
-    if dev.devtype == DeviceType::Nvme{
-        match check_nvme_sanitize(&dev.dev_path) {
-            Ok(true) =>{
-                println!("{} supports NVMe sanitize",dev.dev_path);
-                dev.firmsan = true;
+    match LinuxTransport::open_device(dev) {
+        Ok(mut t) => check_firmware_sanitize_with(dev, &mut t),
+        Err(e) => println!("Error checking firmware sanitize support {}",e),
+    }
+}
+
+pub fn check_firmware_sanitize_with(dev: &mut Device, t: &mut dyn DriveTransport) {
+    let probed = match dev.devtype {
+        DeviceType::Nvme => check_nvme_sanitize(dev, t),
+        DeviceType::Scsi => {
+            // a SATA drive behind a SAT bridge answers IDENTIFY through ATA PASS-THROUGH
+            if ata::identify_device(t).is_ok() {
+                println!("{} is an ATA drive behind a SCSI/USB bridge", dev.dev_path);
+                dev.devtype = DeviceType::Sata;
+                return check_firmware_sanitize_with(dev, t);
             }
-            Ok(false) => println!("{} doesn't support NVMe sanitize", dev.dev_path),
-            Err(e) => println!("Error checking firmware sanitize support {}",e),
+            check_scsi_sanitize(dev, t)
         }
-    }else if dev.devtype == DeviceType::Sata{
-        match check_ata_secure_erase(&dev.dev_path){
-            Ok(true) =>{
-                println!("{} supports ATA secure erase",dev.dev_path);
-                dev.firmsan = true;
-            }
-            Ok(false) => println!("{} doesn't support ATA secure erase",dev.dev_path),
-            Err(e) => println!("Error checking firmware sanitize support {}",e),
+        DeviceType::Mmc => check_mmc_sanitize(dev, t),
+        DeviceType::Sata => check_ata_secure_erase(dev, t),
+        DeviceType::Unknown => Ok(()),
+    };
+    if let Err(e) = probed {
+        println!("Error checking firmware sanitize support {}",e);
+    }
+    check_tcg(dev, t);
+    check_sanitize_status(dev, t);
+
+    // a failed probe still leaves discard and overwrite in the report
+    dev.capabilities = capability::assess(dev, t);
+    if dev.capabilities.firmware_sanitize() {
+        println!("{} supports firmware sanitize", dev.dev_path);
+    } else {
+        match dev.capabilities.best() {
+            // format or TCG can still purge without a sanitize command
+            Some(c) if c.mechanism != capability::Mechanism::Overwrite =>
+                println!("{} has no usable firmware sanitize, best available is {}", dev.dev_path, c.mechanism),
+            _ => println!("{} has no usable firmware sanitize, overwrite only", dev.dev_path),
         }
     }
 }
-
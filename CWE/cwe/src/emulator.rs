@@ -0,0 +1,1307 @@
+// Software drive emulator.
+//
+// `EmulatedDrive` implements `DriveTransport` on top of a sparse backing file and
+// models enough firmware state to run the real wipe paths end to end:
+//   - ATA Security feature set state machine (SEC1..SEC6, password attempts, erase prepare)
+//   - ATA SANITIZE feature set with SANITIZE STATUS EXT progress and freeze locks
+//   - HPA (READ NATIVE MAX / SET MAX ADDRESS) and DCO IDENTIFY / RESTORE
+//   - NVMe Identify, SANICAP and Sanitize Status log (0x81) with simulated progress, Format NVM
+//   - ATA SMART data/thresholds/status and the NVMe SMART / Health (0x02) and Error (0x01) logs
+//   - an Opal 2 TPer over TRUSTED SEND/RECEIVE and Security Send/Receive: Level 0
+//     discovery, sessions, MSID, Activate, GenKey, RevertSP and PSID revert
+//   - plain block reads and writes, direct writes refused when misaligned like O_DIRECT
+// Faults can be injected to exercise the failure paths (aborted sanitize, power loss
+// during erase, I/O errors).
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use uuid::Uuid;
+use crate::device::{Device, DeviceType};
+use crate::identity::OrgSecret;
+use crate::sysfs::BusType;
+use crate::tcg::{self, Token, Tokens};
+use crate::transport::{AtaTaskfile, DriveTransport, NvmeCommand, SECTOR_SIZE};
+
+const ATA_STATUS_OK: u8 = 0x50; // DRDY | DSC
+const ATA_STATUS_ERR: u8 = 0x51; // DRDY | DSC | ERR
+const ATA_ERROR_ABRT: u8 = 0x04;
+
+const NVME_SC_INVALID_OPCODE: u16 = 0x01;
+const NVME_SC_INVALID_FIELD: u16 = 0x02;
+const NVME_SC_INVALID_NS: u16 = 0x0B;
+const NVME_SC_SANITIZE_FAILED: u16 = 0x1C;
+const NVME_SC_SANITIZE_IN_PROGRESS: u16 = 0x1D;
+
+const TCG_BASE_COMID: u16 = 0x07FE;
+const TCG_NOT_AUTHORIZED: u8 = 0x01;
+const TCG_INVALID_PARAMETER: u8 = 0x0C;
+const TCG_FAIL: u8 = 0x3F;
+// K_AES_256 rows behind the global range and range N
+const TCG_GLOBAL_KEY: u64 = 0x0000_0806_0000_0001;
+const TCG_RANGE_KEY: u64 = 0x0000_0806_0003_0000;
+const TCG_RANGE: u64 = 0x0000_0802_0003_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmulatedKind {
+    Ata,
+    Nvme,
+}
+
+/// Failures that can be armed on the emulator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// The next NVMe or ATA sanitize fails and the drive enters sanitize failure mode.
+    AbortSanitize,
+    /// Power is lost while the next ATA SECURITY ERASE UNIT is running: the command
+    /// fails, the drive is left locked with the password set and the erase incomplete.
+    PowerLossDuringErase,
+    /// Reads touching [start, end) fail.
+    ReadError { start: u64, end: u64 },
+    /// Writes touching [start, end) fail.
+    WriteError { start: u64, end: u64 },
+}
+
+/// ATA security state, see ACS-3 "Security feature set" (SEC0..SEC6).
+#[derive(Debug, Clone, Default)]
+pub struct AtaSecurityState {
+    pub supported: bool,
+    pub enabled: bool,
+    pub locked: bool,
+    pub frozen: bool,
+    pub count_expired: bool,
+    pub enhanced_erase_supported: bool,
+    pub erase_in_progress: bool,
+    pub master_capability_maximum: bool,
+    pub failed_attempts: u8,
+    pub user_password: Option<[u8; 32]>,
+    pub master_password: [u8; 32],
+    erase_prepared: bool,
+}
+
+/// ATA SANITIZE feature set state as reported through SANITIZE STATUS EXT.
+#[derive(Debug, Clone, Default)]
+pub struct AtaSanitizeState {
+    /// Crypto scramble, block erase, overwrite and antifreeze lock in IDENTIFY word 59.
+    pub supported: bool,
+    pub frozen: bool,
+    pub antifreeze_locked: bool,
+    pub in_progress: bool,
+    pub failure_mode: bool,
+    /// SANITIZE OPERATION COMPLETED WITHOUT ERROR.
+    pub completed: bool,
+    pub progress: u16,
+    remaining_polls: u32,
+    total_polls: u32,
+    // the sanitize command in flight
+    features: u16,
+    count: u16,
+    lba: u64,
+}
+
+/// NVMe sanitize state as reported through log page 0x81.
+#[derive(Debug, Clone, Default)]
+pub struct NvmeSanitizeState {
+    pub sanicap: u32,
+    pub sprog: u16,
+    pub sstat: u16,
+    pub scdw10: u32,
+    pub in_progress: bool,
+    pub failure_mode: bool,
+    remaining_polls: u32,
+    total_polls: u32,
+    overwrite_pattern: u32,
+}
+
+/// Opal 2 TPer: Admin SP plus one Locking SP with the global range and
+/// `max_ranges` further ranges. Disabled unless `enable_opal` was called.
+#[derive(Debug, Clone, Default)]
+pub struct TcgState {
+    pub enabled: bool,
+    pub msid: Vec<u8>,
+    pub sid: Vec<u8>,
+    pub psid: String,
+    pub locking_active: bool,
+    pub admin1: Vec<u8>,
+    pub max_ranges: u32,
+    /// Bumped by every GenKey and revert, the media key generation.
+    pub key_generation: u32,
+    next_tsn: u32,
+    // open session: SP and the authority it was started as
+    session: Option<(u64, Option<u64>)>,
+    response: Option<Vec<u8>>,
+}
+
+/// Namespace on an emulated NVMe controller. Only namespace 1 is backed by the
+/// file, the others exist so Identify reports them.
+#[derive(Debug, Clone)]
+pub struct EmulatedNamespace {
+    pub nsid: u32,
+    pub blocks: u64,
+    pub used_blocks: u64,
+}
+
+pub struct EmulatedDrive {
+    name: String,
+    backing: PathBuf,
+    file: File,
+    remove_on_drop: bool,
+    kind: EmulatedKind,
+    capacity: u64,
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    pub ata: AtaSecurityState,
+    pub ata_sanitize: AtaSanitizeState,
+    pub nvme: NvmeSanitizeState,
+    /// Estimated erase times (minutes) reported in IDENTIFY words 89/90.
+    pub erase_minutes: u16,
+    pub enhanced_erase_minutes: u16,
+    /// Number of 0x81 log / SANITIZE STATUS EXT reads a sanitize takes before it completes.
+    pub sanitize_polls: u32,
+    pub namespaces: Vec<EmulatedNamespace>,
+    /// Sectors hidden behind a Host Protected Area / Device Configuration Overlay.
+    pub hpa_sectors: u64,
+    pub dco_sectors: u64,
+    /// Reported by SMART attribute 9 / the health log.
+    pub power_on_hours: u64,
+    pub tcg: TcgState,
+    native_max_read: bool,
+    faults: Vec<Fault>,
+}
+
+impl EmulatedDrive {
+    /// Create an emulated drive backed by a sparse file at `backing`.
+    pub fn create(backing: impl Into<PathBuf>, kind: EmulatedKind, capacity: u64) -> io::Result<Self> {
+        let backing = backing.into();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&backing)?;
+        file.set_len(capacity)?;
+
+        let (ata, nvme) = match kind {
+            EmulatedKind::Ata => (
+                AtaSecurityState { supported: true, enhanced_erase_supported: true, ..Default::default() },
+                NvmeSanitizeState::default(),
+            ),
+            EmulatedKind::Nvme => (
+                AtaSecurityState::default(),
+                // crypto erase | block erase | overwrite
+                NvmeSanitizeState { sanicap: 0x7, sprog: 0xFFFF, ..Default::default() },
+            ),
+        };
+
+        Ok(EmulatedDrive {
+            name: format!("emulated:{}", backing.display()),
+            backing,
+            file,
+            remove_on_drop: false,
+            kind,
+            capacity,
+            model: "CWE Emulated Drive".to_string(),
+            serial: "EMU0000000001".to_string(),
+            firmware: "EMU1.0".to_string(),
+            ata,
+            ata_sanitize: AtaSanitizeState { supported: kind == EmulatedKind::Ata, ..Default::default() },
+            nvme,
+            erase_minutes: 2,
+            enhanced_erase_minutes: 4,
+            sanitize_polls: 2,
+            namespaces: vec![EmulatedNamespace { nsid: 1, blocks: capacity / 512, used_blocks: capacity / 512 }],
+            hpa_sectors: 0,
+            dco_sectors: 0,
+            power_on_hours: 1234,
+            tcg: TcgState::default(),
+            native_max_read: false,
+            faults: Vec::new(),
+        })
+    }
+
+    /// Create an emulated drive in the temp dir, the backing file is removed on drop.
+    pub fn temp(kind: EmulatedKind, capacity: u64) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("cwe-emu-{}.img", Uuid::new_v4()));
+        let mut d = Self::create(path, kind, capacity)?;
+        d.remove_on_drop = true;
+        Ok(d)
+    }
+
+    pub fn kind(&self) -> EmulatedKind {
+        self.kind
+    }
+
+    pub fn backing_path(&self) -> &PathBuf {
+        &self.backing
+    }
+
+    /// A `Device` describing this drive, for passing to `wipe_device_with` and friends.
+    pub fn device(&self, secret: &OrgSecret) -> Device {
+        let devtype = match self.kind {
+            EmulatedKind::Ata => DeviceType::Sata,
+            EmulatedKind::Nvme => DeviceType::Nvme,
+        };
+        let mut d = Device::new(&self.name, Some(&self.model), Some(&self.serial), None, devtype, secret);
+        d.size_bytes = self.capacity;
+        d.bus = match self.kind {
+            EmulatedKind::Ata => BusType::Ata,
+            EmulatedKind::Nvme => BusType::Nvme,
+        };
+        d.firmware = Some(self.firmware.clone());
+        d
+    }
+
+    /// Turn the drive into an unowned Opal 2 SED: SID is the MSID, Locking SP inactive.
+    pub fn enable_opal(&mut self) {
+        let msid = b"EMUMSID0000000000000000000000001".to_vec();
+        self.tcg = TcgState {
+            enabled: true,
+            sid: msid.clone(),
+            msid,
+            psid: "EMUPSID000000000000000000000001".to_string(),
+            max_ranges: 2,
+            next_tsn: 0x1000,
+            ..Default::default()
+        };
+    }
+
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    /// SECURITY FREEZE LOCK as a BIOS would issue it at boot.
+    pub fn freeze(&mut self) {
+        self.ata.frozen = true;
+    }
+
+    /// Simulate a power cycle: frozen state and attempt counter reset, an enabled
+    /// drive comes back locked, anything in flight is dropped.
+    pub fn power_cycle(&mut self) {
+        self.ata.frozen = false;
+        self.ata.count_expired = false;
+        self.ata.failed_attempts = 0;
+        self.ata.erase_prepared = false;
+        self.ata.locked = self.ata.enabled;
+        self.ata_sanitize.frozen = false;
+        self.ata_sanitize.antifreeze_locked = false;
+    }
+
+    /// Read raw media bypassing the security and sanitize state, for checking results.
+    pub fn media(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    fn take_fault(&mut self, fault: &Fault) -> bool {
+        if let Some(pos) = self.faults.iter().position(|f| f == fault) {
+            self.faults.remove(pos);
+            return true;
+        }
+        false
+    }
+
+    fn io_fault(&self, offset: u64, len: usize, write: bool) -> bool {
+        let end = offset + len as u64;
+        self.faults.iter().any(|f| match *f {
+            Fault::ReadError { start, end: e } if !write => offset < e && end > start,
+            Fault::WriteError { start, end: e } if write => offset < e && end > start,
+            _ => false,
+        })
+    }
+
+    fn fill_media(&mut self, pattern: Option<u32>) -> io::Result<()> {
+        let chunk = 1024 * 1024;
+        let mut buf = vec![0u8; chunk];
+        if let Some(p) = pattern {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = p.to_le_bytes()[i % 4];
+            }
+        }
+        let mut offset = 0;
+        while offset < self.capacity {
+            let n = chunk.min((self.capacity - offset) as usize);
+            self.file.write_all_at(&buf[..n], offset)?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    // ---------- ATA ----------
+
+    fn native_sectors(&self) -> u64 {
+        self.capacity / 512 - self.dco_sectors
+    }
+
+    // what the host can address, the emulated size
+    fn visible_bytes(&self) -> u64 {
+        (self.native_sectors() - self.hpa_sectors) * 512
+    }
+
+    fn dco_identify(&self) -> [u8; 512] {
+        let mut d = [0u8; 512];
+        d[0..2].copy_from_slice(&0x0002u16.to_le_bytes()); // revision
+        d[6..14].copy_from_slice(&(self.capacity / 512 - 1).to_le_bytes()); // words 3-6
+        d[510] = 0xA5;
+        d[511] = 0u8.wrapping_sub(d[..511].iter().fold(0u8, |a, b| a.wrapping_add(*b)));
+        d
+    }
+
+    fn identify_device(&self) -> [u8; 512] {
+        let mut id = [0u16; 256];
+        let sectors = self.visible_bytes() / 512;
+
+        put_ata_string(&mut id[10..20], &self.serial);
+        put_ata_string(&mut id[23..27], &self.firmware);
+        put_ata_string(&mut id[27..47], &self.model);
+
+        id[49] = 1 << 9; // LBA supported
+        let lba28 = sectors.min(0x0FFF_FFFF) as u32;
+        id[60] = lba28 as u16;
+        id[61] = (lba28 >> 16) as u16;
+        id[82] = 1 << 10; // HPA
+        id[83] = (1 << 10) | (1 << 11); // 48-bit address feature set, DCO
+        id[86] = 1 << 10;
+        if self.tcg.enabled {
+            id[48] = 0x4001; // Trusted Computing feature set
+        }
+        for i in 0..4 {
+            id[100 + i] = (sectors >> (16 * i)) as u16;
+        }
+
+        if self.ata_sanitize.supported {
+            // SANITIZE feature set, crypto scramble, overwrite, block erase, antifreeze lock
+            id[59] = (1 << 10) | (1 << 12) | (1 << 13) | (1 << 14) | (1 << 15);
+        }
+
+        let s = &self.ata;
+        if s.supported {
+            id[82] |= 1 << 1;
+            id[89] = self.erase_minutes.div_ceil(2);
+            id[90] = self.enhanced_erase_minutes.div_ceil(2);
+            if s.enabled { id[85] |= 1 << 1; }
+
+            let mut w128 = 1u16;
+            if s.enabled { w128 |= 1 << 1; }
+            if s.locked { w128 |= 1 << 2; }
+            if s.frozen { w128 |= 1 << 3; }
+            if s.count_expired { w128 |= 1 << 4; }
+            if s.enhanced_erase_supported { w128 |= 1 << 5; }
+            if s.master_capability_maximum { w128 |= 1 << 8; }
+            id[128] = w128;
+        }
+
+        let mut out = [0u8; 512];
+        for (i, w) in id.iter().enumerate() {
+            out[i * 2..i * 2 + 2].copy_from_slice(&w.to_le_bytes());
+        }
+        out
+    }
+
+    fn check_password(&mut self, data: &[u8]) -> bool {
+        let master = data.len() >= 2 && data[0] & 0x01 != 0;
+        let mut pwd = [0u8; 32];
+        if data.len() >= 34 {
+            pwd.copy_from_slice(&data[2..34]);
+        }
+        let ok = if master {
+            // with master password capability "maximum" the master password only allows erase
+            pwd == self.ata.master_password
+        } else {
+            self.ata.user_password == Some(pwd)
+        };
+        if !ok {
+            self.ata.failed_attempts += 1;
+            if self.ata.failed_attempts >= 5 {
+                self.ata.count_expired = true;
+            }
+        }
+        ok
+    }
+
+    fn ata_exec(&mut self, tf: &mut AtaTaskfile, data: &mut [u8]) -> Result<(), &'static str> {
+        // only ERASE UNIT may directly follow ERASE PREPARE
+        let prepared = std::mem::take(&mut self.ata.erase_prepared);
+        // and only SET MAX ADDRESS may follow READ NATIVE MAX ADDRESS
+        let native_read = std::mem::take(&mut self.native_max_read);
+
+        match tf.command {
+            0xEC => { // IDENTIFY DEVICE
+                let id = self.identify_device();
+                let n = data.len().min(512);
+                data[..n].copy_from_slice(&id[..n]);
+                Ok(())
+            }
+            0xF1 => { // SECURITY SET PASSWORD
+                if self.ata.frozen || self.ata.locked { return Err("frozen or locked"); }
+                let mut pwd = [0u8; 32];
+                if data.len() >= 34 { pwd.copy_from_slice(&data[2..34]); }
+                if data.first().is_some_and(|c| c & 0x01 != 0) {
+                    self.ata.master_password = pwd;
+                    self.ata.master_capability_maximum = data.get(1).is_some_and(|c| c & 0x01 != 0);
+                } else {
+                    self.ata.user_password = Some(pwd);
+                    self.ata.enabled = true;
+                }
+                Ok(())
+            }
+            0xF2 => { // SECURITY UNLOCK
+                if self.ata.count_expired { return Err("password attempt count expired"); }
+                if self.ata.frozen { return Err("frozen"); }
+                if !self.check_password(data) { return Err("wrong password"); }
+                self.ata.locked = false;
+                self.ata.failed_attempts = 0;
+                Ok(())
+            }
+            0xF3 => { // SECURITY ERASE PREPARE
+                if self.ata.frozen { return Err("frozen"); }
+                self.ata.erase_prepared = true;
+                Ok(())
+            }
+            0xF4 => { // SECURITY ERASE UNIT
+                if !prepared { return Err("not preceded by SECURITY ERASE PREPARE"); }
+                if self.ata.frozen { return Err("frozen"); }
+                if self.ata.count_expired { return Err("password attempt count expired"); }
+                if !self.ata.enabled { return Err("security not enabled"); }
+                if !self.check_password(data) { return Err("wrong password"); }
+                let enhanced = data.first().is_some_and(|c| c & 0x02 != 0);
+                if enhanced && !self.ata.enhanced_erase_supported { return Err("enhanced erase not supported"); }
+
+                if self.take_fault(&Fault::PowerLossDuringErase) {
+                    self.ata.erase_in_progress = true;
+                    self.ata.locked = true;
+                    // half the media got erased before power went away
+                    let half = self.capacity / 2;
+                    let zero = vec![0u8; 1024 * 1024];
+                    let mut off = 0;
+                    while off < half {
+                        let n = zero.len().min((half - off) as usize);
+                        self.file.write_all_at(&zero[..n], off).map_err(|_| "backing write failed")?;
+                        off += n as u64;
+                    }
+                    return Err("power lost during erase");
+                }
+
+                // normal erase writes zeros, enhanced erase a vendor pattern
+                let pattern = if enhanced { Some(0xA5A5_A5A5) } else { None };
+                self.fill_media(pattern).map_err(|_| "backing write failed")?;
+                self.ata.erase_in_progress = false;
+                self.ata.enabled = false;
+                self.ata.locked = false;
+                self.ata.user_password = None;
+                self.ata.failed_attempts = 0;
+                Ok(())
+            }
+            0x27 | 0xF8 => { // READ NATIVE MAX ADDRESS (EXT)
+                let max = self.native_sectors() - 1;
+                if tf.command == 0xF8 {
+                    tf.lba = max & 0xFF_FFFF;
+                    tf.device = (tf.device & 0xF0) | ((max >> 24) & 0x0F) as u8;
+                } else {
+                    tf.lba = max;
+                }
+                self.native_max_read = true;
+                Ok(())
+            }
+            0x37 | 0xF9 => { // SET MAX ADDRESS (EXT)
+                if !native_read { return Err("not preceded by READ NATIVE MAX ADDRESS"); }
+                let max = if tf.command == 0xF9 {
+                    (tf.lba & 0xFF_FFFF) | ((tf.device as u64 & 0x0F) << 24)
+                } else {
+                    tf.lba
+                };
+                if max >= self.native_sectors() { return Err("address beyond native max"); }
+                self.hpa_sectors = self.native_sectors() - (max + 1);
+                Ok(())
+            }
+            0xB0 => { // SMART
+                if (tf.lba >> 8) & 0xFFFF != 0xC24F { return Err("bad SMART signature"); }
+                match tf.features {
+                    0xD0 | 0xD1 => {
+                        let page = self.smart_page(tf.features == 0xD1);
+                        let n = data.len().min(512);
+                        data[..n].copy_from_slice(&page[..n]);
+                        Ok(())
+                    }
+                    0xDA => Ok(()), // lba mid/high stay 4F/C2: no threshold exceeded
+                    _ => Err("unsupported SMART feature"),
+                }
+            }
+            0xB1 => { // DEVICE CONFIGURATION
+                match tf.features {
+                    0xC2 => {
+                        let dco = self.dco_identify();
+                        let n = data.len().min(512);
+                        data[..n].copy_from_slice(&dco[..n]);
+                        Ok(())
+                    }
+                    0xC0 => {
+                        if self.hpa_sectors > 0 { return Err("HPA is set"); }
+                        self.dco_sectors = 0;
+                        Ok(())
+                    }
+                    _ => Err("unsupported DCO feature"),
+                }
+            }
+            0x5C => { // TRUSTED RECEIVE
+                let comid = ((tf.lba >> 8) & 0xFFFF) as u16;
+                self.tcg_recv(tf.features as u8, comid, data)
+            }
+            0x5E => { // TRUSTED SEND
+                let comid = ((tf.lba >> 8) & 0xFFFF) as u16;
+                self.tcg_send(tf.features as u8, comid, data)
+            }
+            0xB4 => self.ata_sanitize_exec(tf),
+            0xF5 => { // SECURITY FREEZE LOCK
+                if self.ata.locked { return Err("locked"); }
+                self.ata.frozen = true;
+                Ok(())
+            }
+            0xF6 => { // SECURITY DISABLE PASSWORD
+                if self.ata.frozen || self.ata.locked { return Err("frozen or locked"); }
+                if !self.check_password(data) { return Err("wrong password"); }
+                self.ata.enabled = false;
+                self.ata.user_password = None;
+                Ok(())
+            }
+            _ => Err("unsupported command"),
+        }
+    }
+
+    // SANITIZE DEVICE. Errors put their reason in LBA 7:0 like a real drive.
+    fn ata_sanitize_exec(&mut self, tf: &mut AtaTaskfile) -> Result<(), &'static str> {
+        let fail = |tf: &mut AtaTaskfile, reason: u64| {
+            tf.lba = reason;
+            Err("sanitize error, reason in LBA 7:0")
+        };
+        if !self.ata_sanitize.supported {
+            return fail(tf, 2);
+        }
+        let signature = match tf.features {
+            0x0000 => None,
+            0x0011 => Some(0x4372_7970),
+            0x0012 => Some(0x426B_4572),
+            0x0014 => Some(0x4F57 << 32 | (tf.lba & 0xFFFF_FFFF)),
+            0x0020 => Some(0x4672_4C6B),
+            0x0040 => Some(0x416E_7469),
+            _ => return fail(tf, 2),
+        };
+        if signature.is_some_and(|sig| sig != tf.lba & 0xFFFF_FFFF_FFFF) {
+            return fail(tf, 0);
+        }
+
+        match tf.features {
+            0x0000 => { // SANITIZE STATUS EXT
+                let st = &mut self.ata_sanitize;
+                if st.in_progress {
+                    if st.remaining_polls > 0 {
+                        st.remaining_polls -= 1;
+                        let done = st.total_polls - st.remaining_polls;
+                        st.progress = ((done as u64 * 0xFFFF) / (st.total_polls as u64 + 1)) as u16;
+                    } else {
+                        self.complete_ata_sanitize().map_err(|_| "backing write failed")?;
+                    }
+                }
+                let st = &mut self.ata_sanitize;
+                if st.failure_mode {
+                    // FAILURE MODE set on the failed command lets the host clear it
+                    if tf.count & 1 != 0 && st.count & (1 << 4) != 0 {
+                        st.failure_mode = false;
+                    } else {
+                        return fail(tf, 1);
+                    }
+                }
+                let st = &self.ata_sanitize;
+                tf.count = (st.completed as u16) << 15 | (st.in_progress as u16) << 14
+                    | (st.frozen as u16) << 13 | (st.antifreeze_locked as u16) << 12;
+                tf.lba = if st.in_progress { st.progress as u64 } else { 0xFFFF };
+                Ok(())
+            }
+            0x0011 | 0x0012 | 0x0014 => {
+                if self.ata_sanitize.frozen { return fail(tf, 3); }
+                if self.ata_sanitize.in_progress { return Err("sanitize in progress"); }
+                let total_polls = self.sanitize_polls;
+                self.ata_sanitize = AtaSanitizeState {
+                    in_progress: true,
+                    remaining_polls: total_polls,
+                    total_polls,
+                    features: tf.features,
+                    count: tf.count,
+                    lba: tf.lba,
+                    ..self.ata_sanitize.clone()
+                };
+                Ok(())
+            }
+            0x0020 => { // SANITIZE FREEZE LOCK EXT
+                if self.ata_sanitize.antifreeze_locked { return fail(tf, 4); }
+                self.ata_sanitize.frozen = true;
+                Ok(())
+            }
+            _ => { // SANITIZE ANTIFREEZE LOCK EXT
+                self.ata_sanitize.antifreeze_locked = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn complete_ata_sanitize(&mut self) -> io::Result<()> {
+        self.ata_sanitize.in_progress = false;
+        if self.take_fault(&Fault::AbortSanitize) {
+            self.ata_sanitize.failure_mode = true;
+            self.ata_sanitize.completed = false;
+            return Ok(());
+        }
+        let st = &self.ata_sanitize;
+        match st.features {
+            0x0014 => {
+                let passes = match st.count & 0xF { 0 => 16, n => n };
+                let invert = st.count & (1 << 7) != 0 && passes % 2 == 0;
+                let pattern = st.lba as u32;
+                self.fill_media(Some(if invert { !pattern } else { pattern }))?
+            }
+            _ => self.fill_media(None)?, // block erase and crypto scramble read back as zeros
+        }
+        self.ata_sanitize.failure_mode = false;
+        self.ata_sanitize.completed = true;
+        Ok(())
+    }
+
+    // SMART READ DATA / READ THRESHOLDS: power-on hours, power cycles, reallocated sectors
+    fn smart_page(&self, thresholds: bool) -> [u8; 512] {
+        let mut d = [0u8; 512];
+        d[0] = 0x10; // revision
+        let attrs: [(u8, u8, u64, u8); 3] = [(9, 100, self.power_on_hours, 0), (12, 100, 42, 0), (5, 100, 0, 10)];
+        for (i, (id, value, raw, threshold)) in attrs.iter().enumerate() {
+            let e = &mut d[2 + i * 12..2 + (i + 1) * 12];
+            e[0] = *id;
+            if thresholds {
+                e[1] = *threshold;
+            } else {
+                e[3] = *value;
+                e[4] = *value;
+                e[5..11].copy_from_slice(&raw.to_le_bytes()[..6]);
+            }
+        }
+        let sum = d[..511].iter().fold(0u8, |a, b| a.wrapping_add(*b));
+        d[511] = sum.wrapping_neg();
+        d
+    }
+
+    // ---------- NVMe ----------
+
+    fn health_log(&self) -> [u8; 512] {
+        let mut d = [0u8; 512];
+        d[1..3].copy_from_slice(&308u16.to_le_bytes()); // 35 C in Kelvin
+        d[3] = 100; // available spare
+        d[4] = 10;
+        d[5] = 3; // percentage used
+        d[112..120].copy_from_slice(&42u64.to_le_bytes());
+        d[128..136].copy_from_slice(&self.power_on_hours.to_le_bytes());
+        d
+    }
+
+
+    fn identify_controller(&self) -> Vec<u8> {
+        let mut d = vec![0u8; 4096];
+        d[0..2].copy_from_slice(&0x1b36u16.to_le_bytes()); // VID
+        put_nvme_string(&mut d[4..24], &self.serial);
+        put_nvme_string(&mut d[24..64], &self.model);
+        put_nvme_string(&mut d[64..72], &self.firmware);
+        // OACS: format nvm, security send/receive with the TPer
+        let oacs = 0x0002u16 | self.tcg.enabled as u16;
+        d[256..258].copy_from_slice(&oacs.to_le_bytes());
+        d[328..332].copy_from_slice(&self.nvme.sanicap.to_le_bytes());
+        let nn = self.namespaces.iter().map(|n| n.nsid).max().unwrap_or(0);
+        d[516..520].copy_from_slice(&nn.to_le_bytes()); // NN
+        d[524] = 0x04; // FNA: crypto erase supported as part of format
+        d
+    }
+
+    fn identify_namespace(&self, nsid: u32) -> Option<Vec<u8>> {
+        let ns = self.namespaces.iter().find(|n| n.nsid == nsid)?;
+        let mut d = vec![0u8; 4096];
+        d[0..8].copy_from_slice(&ns.blocks.to_le_bytes()); // NSZE
+        d[8..16].copy_from_slice(&ns.blocks.to_le_bytes()); // NCAP
+        d[16..24].copy_from_slice(&ns.used_blocks.to_le_bytes()); // NUSE
+        d[25] = 0; // NLBAF (0's based)
+        d[26] = 0; // FLBAS
+        d[128..132].copy_from_slice(&(9u32 << 16).to_le_bytes()); // LBAF0: 512 byte data
+        Some(d)
+    }
+
+    fn sanitize_log(&mut self) -> io::Result<[u8; 512]> {
+        if self.nvme.in_progress {
+            if self.nvme.remaining_polls > 0 {
+                self.nvme.remaining_polls -= 1;
+                let done = self.nvme.total_polls - self.nvme.remaining_polls;
+                self.nvme.sprog = ((done as u64 * 0xFFFF) / (self.nvme.total_polls as u64 + 1)) as u16;
+            } else {
+                self.complete_sanitize()?;
+            }
+        }
+
+        let mut log = [0u8; 512];
+        log[0..2].copy_from_slice(&self.nvme.sprog.to_le_bytes());
+        log[2..4].copy_from_slice(&self.nvme.sstat.to_le_bytes());
+        log[4..8].copy_from_slice(&self.nvme.scdw10.to_le_bytes());
+        // estimated times: overwrite, block erase, crypto erase
+        log[8..12].copy_from_slice(&600u32.to_le_bytes());
+        log[12..16].copy_from_slice(&60u32.to_le_bytes());
+        log[16..20].copy_from_slice(&5u32.to_le_bytes());
+        Ok(log)
+    }
+
+    fn complete_sanitize(&mut self) -> io::Result<()> {
+        self.nvme.in_progress = false;
+        self.nvme.sprog = 0xFFFF;
+
+        if self.take_fault(&Fault::AbortSanitize) {
+            self.nvme.sstat = 0x3; // sanitize operation failed
+            self.nvme.failure_mode = true;
+            return Ok(());
+        }
+
+        match self.nvme.scdw10 & 0x7 {
+            3 => {
+                // the last pass is what stays on the media, with OIPBP every other pass is inverted
+                let passes = match (self.nvme.scdw10 >> 4) & 0xF { 0 => 16, n => n };
+                let invert = self.nvme.scdw10 & (1 << 8) != 0 && passes % 2 == 0;
+                let pattern = if invert { !self.nvme.overwrite_pattern } else { self.nvme.overwrite_pattern };
+                self.fill_media(Some(pattern))?
+            }
+            _ => self.fill_media(None)?, // block and crypto erase read back as zeros
+        }
+        self.nvme.failure_mode = false;
+        // completed successfully, global data erased
+        self.nvme.sstat = 0x1 | (1 << 8);
+        Ok(())
+    }
+
+    fn nvme_exec(&mut self, cmd: &NvmeCommand, data: &mut [u8]) -> Result<u32, u16> {
+        match cmd.opcode {
+            0x06 => { // Identify
+                let page = match cmd.cdw10 & 0xff {
+                    0 => self.identify_namespace(cmd.nsid).ok_or(NVME_SC_INVALID_NS)?,
+                    1 => self.identify_controller(),
+                    2 => {
+                        let mut ids: Vec<u32> = self.namespaces.iter().map(|n| n.nsid).filter(|id| *id > cmd.nsid).collect();
+                        ids.sort();
+                        let mut list = vec![0u8; 4096];
+                        for (i, id) in ids.iter().take(1024).enumerate() {
+                            list[i * 4..i * 4 + 4].copy_from_slice(&id.to_le_bytes());
+                        }
+                        list
+                    }
+                    _ => return Err(NVME_SC_INVALID_FIELD),
+                };
+                let n = data.len().min(page.len());
+                data[..n].copy_from_slice(&page[..n]);
+                Ok(0)
+            }
+            0x02 => { // Get Log Page
+                match cmd.cdw10 & 0xff {
+                    0x01 => { // Error Information, no errors recorded
+                        data.fill(0);
+                        Ok(0)
+                    }
+                    0x02 => {
+                        let log = self.health_log();
+                        let n = data.len().min(log.len());
+                        data[..n].copy_from_slice(&log[..n]);
+                        Ok(0)
+                    }
+                    0x81 => {
+                        let log = self.sanitize_log().map_err(|_| NVME_SC_INVALID_FIELD)?;
+                        let n = data.len().min(log.len());
+                        data[..n].copy_from_slice(&log[..n]);
+                        Ok(0)
+                    }
+                    _ => Err(NVME_SC_INVALID_FIELD),
+                }
+            }
+            0x80 => { // Format NVM, completes synchronously
+                let known = cmd.nsid == 0xFFFF_FFFF || self.namespaces.iter().any(|n| n.nsid == cmd.nsid);
+                if !known { return Err(NVME_SC_INVALID_NS); }
+                if self.nvme.in_progress { return Err(NVME_SC_SANITIZE_IN_PROGRESS); }
+                // after a failed sanitize only another sanitize gets the controller out
+                if self.nvme.failure_mode { return Err(NVME_SC_SANITIZE_FAILED); }
+                let lbaf = (cmd.cdw10 & 0xF) | (((cmd.cdw10 >> 12) & 0x3) << 4);
+                if lbaf != 0 { return Err(NVME_SC_INVALID_FIELD); } // only LBAF0 exists
+                match (cmd.cdw10 >> 9) & 0x7 {
+                    0 => {}
+                    1 | 2 => self.fill_media(None).map_err(|_| NVME_SC_INVALID_FIELD)?,
+                    _ => return Err(NVME_SC_INVALID_FIELD),
+                }
+                Ok(0)
+            }
+            0x84 => { // Sanitize
+                let action = cmd.cdw10 & 0x7;
+                if self.nvme.in_progress { return Err(NVME_SC_SANITIZE_IN_PROGRESS); }
+                let supported = match action {
+                    1 => {
+                        // exit failure mode, refused if the failed sanitize ran restricted (AUSE=0)
+                        if self.nvme.failure_mode && self.nvme.scdw10 & (1 << 3) == 0 {
+                            return Err(NVME_SC_SANITIZE_FAILED);
+                        }
+                        self.nvme.failure_mode = false;
+                        return Ok(0);
+                    }
+                    2 => self.nvme.sanicap & 0x2 != 0, // block erase
+                    3 => self.nvme.sanicap & 0x4 != 0, // overwrite
+                    4 => self.nvme.sanicap & 0x1 != 0, // crypto erase
+                    _ => false,
+                };
+                if !supported { return Err(NVME_SC_INVALID_FIELD); }
+
+                self.nvme.in_progress = true;
+                self.nvme.scdw10 = cmd.cdw10;
+                self.nvme.overwrite_pattern = cmd.cdw11;
+                self.nvme.sprog = 0;
+                self.nvme.sstat = 0x2; // in progress
+                self.nvme.total_polls = self.sanitize_polls;
+                self.nvme.remaining_polls = self.sanitize_polls;
+                Ok(0)
+            }
+            0x81 | 0x82 => { // Security Send / Receive
+                let (protocol, comid) = ((cmd.cdw10 >> 24) as u8, ((cmd.cdw10 >> 8) & 0xFFFF) as u16);
+                let res = if cmd.opcode == 0x81 {
+                    self.tcg_send(protocol, comid, data)
+                } else {
+                    self.tcg_recv(protocol, comid, data)
+                };
+                res.map(|_| 0).map_err(|_| NVME_SC_INVALID_FIELD)
+            }
+            _ => Err(NVME_SC_INVALID_OPCODE),
+        }
+    }
+
+    // ---------- TCG ----------
+
+    fn level0(&self) -> Vec<u8> {
+        let mut d = vec![0u8; 48];
+        // TPer: sync, streaming
+        d.extend_from_slice(&[0x00, 0x01, 0x10, 0x0C, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // Locking: supported, media encryption, enabled once activated
+        let locking = 0x01 | 0x08 | if self.tcg.locking_active { 0x02 } else { 0 };
+        d.extend_from_slice(&[0x00, 0x02, 0x10, 0x0C, locking, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // Opal 2: base ComID, one ComID, 4 admins, 8 users
+        let comid = TCG_BASE_COMID.to_be_bytes();
+        d.extend_from_slice(&[0x02, 0x03, 0x10, 0x10, comid[0], comid[1], 0, 1, 0, 0, 4, 0, 8, 0, 0, 0, 0, 0, 0, 0]);
+        // Block SID: SID value state
+        let sid_changed = (self.tcg.sid != self.tcg.msid) as u8;
+        d.extend_from_slice(&[0x04, 0x02, 0x10, 0x0C, sid_changed, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let len = (d.len() - 4) as u32;
+        d[0..4].copy_from_slice(&len.to_be_bytes());
+        d
+    }
+
+    fn tcg_recv(&mut self, protocol: u8, comid: u16, data: &mut [u8]) -> Result<(), &'static str> {
+        if !self.tcg.enabled || protocol != tcg::SECURITY_PROTOCOL_TCG {
+            return Err("unsupported security protocol");
+        }
+        let resp = match comid {
+            tcg::LEVEL0_COMID => self.level0(),
+            TCG_BASE_COMID => self.tcg.response.take().unwrap_or_else(|| {
+                // ComPacket with length 0: nothing pending
+                let mut empty = vec![0u8; 20];
+                empty[4..6].copy_from_slice(&comid.to_be_bytes());
+                empty
+            }),
+            _ => return Err("invalid ComID"),
+        };
+        data.fill(0);
+        let n = data.len().min(resp.len());
+        data[..n].copy_from_slice(&resp[..n]);
+        Ok(())
+    }
+
+    fn tcg_send(&mut self, protocol: u8, comid: u16, data: &[u8]) -> Result<(), &'static str> {
+        if !self.tcg.enabled || protocol != tcg::SECURITY_PROTOCOL_TCG {
+            return Err("unsupported security protocol");
+        }
+        if comid != TCG_BASE_COMID {
+            return Err("invalid ComID");
+        }
+        let (tsn, hsn, payload) = tcg::parse_compacket(data).ok().flatten().ok_or("malformed ComPacket")?;
+        let tokens = tcg::decode(payload).map_err(|_| "malformed token stream")?;
+
+        let mut reply = Tokens::default();
+        let tsn = if tokens.first() == Some(&Token::Control(tcg::END_OF_SESSION)) {
+            self.tcg.session = None;
+            reply.token(tcg::END_OF_SESSION);
+            tsn
+        } else {
+            match self.tcg_call(&tokens) {
+                Ok((mut body, new_tsn)) => {
+                    reply = std::mem::take(body.status(0));
+                    new_tsn.unwrap_or(tsn)
+                }
+                Err(status) => {
+                    reply.token(tcg::START_LIST).token(tcg::END_LIST).status(status);
+                    tsn
+                }
+            }
+        };
+        self.tcg.response = Some(tcg::compacket(comid, tsn, hsn, &reply.0));
+        Ok(())
+    }
+
+    // One method call. Returns the reply up to the method status and, for
+    // StartSession, the new session number.
+    fn tcg_call(&mut self, tokens: &[Token]) -> Result<(Tokens, Option<u32>), u8> {
+        let (Some(object), Some(method)) = (tokens.get(1).and_then(Token::uid), tokens.get(2).and_then(Token::uid)) else {
+            return Err(TCG_INVALID_PARAMETER);
+        };
+        let named = |name: u64| tokens.windows(3).find_map(|w| {
+            (w[0] == Token::Control(tcg::START_NAME) && w[1] == Token::Uint(name)).then(|| w[2].clone())
+        });
+
+        if object == tcg::SMUID && method == tcg::START_SESSION {
+            let hsn = match tokens.get(4) { Some(Token::Uint(h)) => *h, _ => return Err(TCG_INVALID_PARAMETER) };
+            let sp = tokens.get(5).and_then(Token::uid).ok_or(TCG_INVALID_PARAMETER)?;
+            let authority = named(3).and_then(|t| t.uid());
+            let password = match named(0) { Some(Token::Bytes(b)) => b, _ => Vec::new() };
+            let st = &self.tcg;
+            let ok = match (sp, authority) {
+                (tcg::ADMIN_SP, None) => true,
+                (tcg::ADMIN_SP, Some(tcg::SID)) => password == st.sid,
+                (tcg::ADMIN_SP, Some(tcg::PSID)) => password == st.psid.as_bytes(),
+                (tcg::LOCKING_SP, _) if !st.locking_active => return Err(TCG_INVALID_PARAMETER),
+                (tcg::LOCKING_SP, None) => true,
+                (tcg::LOCKING_SP, Some(tcg::ADMIN1)) => password == st.admin1,
+                _ => false,
+            };
+            if !ok {
+                return Err(TCG_NOT_AUTHORIZED);
+            }
+            let tsn = self.tcg.next_tsn;
+            self.tcg.next_tsn += 1;
+            self.tcg.session = Some((sp, authority));
+            // the session manager answers with a SyncSession call
+            let mut body = Tokens::default();
+            body.token(tcg::CALL).uid(tcg::SMUID).uid(tcg::SYNC_SESSION);
+            body.token(tcg::START_LIST).uint(hsn).uint(tsn as u64).token(tcg::END_LIST);
+            return Ok((body, Some(tsn)));
+        }
+
+        let Some((sp, authority)) = self.tcg.session else { return Err(TCG_FAIL) };
+        let admin1 = sp == tcg::LOCKING_SP && authority == Some(tcg::ADMIN1);
+        let range = match object {
+            tcg::LOCKING_GLOBAL_RANGE => Some(0),
+            o if o & !0xFFFF == TCG_RANGE && ((o & 0xFFFF) as u32) <= self.tcg.max_ranges => Some((o & 0xFFFF) as u32),
+            _ => None,
+        };
+        let is_key = object == TCG_GLOBAL_KEY
+            || (object & !0xFFFF == TCG_RANGE_KEY && ((object & 0xFFFF) as u32) <= self.tcg.max_ranges);
+
+        let mut body = Tokens::default();
+        body.token(tcg::START_LIST);
+        match method {
+            tcg::GET if object == tcg::C_PIN_MSID && sp == tcg::ADMIN_SP => {
+                body.token(tcg::START_LIST).token(tcg::START_NAME).uint(3).bytes(&self.tcg.msid).token(tcg::END_NAME).token(tcg::END_LIST);
+            }
+            tcg::GET if object == tcg::LOCKING_INFO && sp == tcg::LOCKING_SP => {
+                body.token(tcg::START_LIST).token(tcg::START_NAME).uint(4).uint(self.tcg.max_ranges as u64).token(tcg::END_NAME).token(tcg::END_LIST);
+            }
+            tcg::GET if range.is_some() => {
+                if !admin1 {
+                    return Err(TCG_NOT_AUTHORIZED);
+                }
+                let n = range.unwrap_or(0);
+                let key = if n == 0 { TCG_GLOBAL_KEY } else { TCG_RANGE_KEY + n as u64 };
+                body.token(tcg::START_LIST);
+                for col in 3..=8 {
+                    body.token(tcg::START_NAME).uint(col).uint(0).token(tcg::END_NAME);
+                }
+                body.token(tcg::START_NAME).uint(10).uid(key).token(tcg::END_NAME);
+                body.token(tcg::END_LIST);
+            }
+            tcg::ACTIVATE if object == tcg::LOCKING_SP => {
+                if authority != Some(tcg::SID) {
+                    return Err(TCG_NOT_AUTHORIZED);
+                }
+                if !self.tcg.locking_active {
+                    self.tcg.locking_active = true;
+                    self.tcg.admin1 = self.tcg.sid.clone();
+                }
+            }
+            tcg::GEN_KEY if is_key => {
+                if !admin1 {
+                    return Err(TCG_NOT_AUTHORIZED);
+                }
+                self.tcg_rekey()?;
+            }
+            tcg::REVERT_SP if object == tcg::THIS_SP => {
+                if !admin1 {
+                    return Err(TCG_NOT_AUTHORIZED);
+                }
+                self.tcg.locking_active = false;
+                self.tcg.admin1.clear();
+                self.tcg.session = None;
+                self.tcg_rekey()?;
+            }
+            tcg::REVERT if object == tcg::ADMIN_SP => {
+                if sp != tcg::ADMIN_SP || !matches!(authority, Some(tcg::SID) | Some(tcg::PSID)) {
+                    return Err(TCG_NOT_AUTHORIZED);
+                }
+                self.tcg.sid = self.tcg.msid.clone();
+                self.tcg.locking_active = false;
+                self.tcg.admin1.clear();
+                self.tcg.session = None;
+                self.tcg_rekey()?;
+            }
+            _ => return Err(TCG_INVALID_PARAMETER),
+        }
+        body.token(tcg::END_LIST);
+        Ok((body, None))
+    }
+
+    // a new media key: the old ciphertext decrypts to noise, read back as zeros here
+    fn tcg_rekey(&mut self) -> Result<(), u8> {
+        self.tcg.key_generation += 1;
+        self.fill_media(None).map_err(|_| TCG_FAIL)
+    }
+
+    fn media_blocked(&self) -> Option<io::Error> {
+        if self.ata.locked {
+            return Some(io::Error::new(io::ErrorKind::PermissionDenied, "drive is security locked"));
+        }
+        if self.nvme.in_progress {
+            return Some(nvme_error(0x02, NVME_SC_SANITIZE_IN_PROGRESS));
+        }
+        if self.ata_sanitize.in_progress || self.ata_sanitize.failure_mode {
+            return Some(io::Error::other("ATA sanitize in progress or failed"));
+        }
+        if self.nvme.failure_mode {
+            return Some(nvme_error(0x02, NVME_SC_SANITIZE_FAILED));
+        }
+        None
+    }
+}
+
+impl DriveTransport for EmulatedDrive {
+    fn path(&self) -> &str {
+        &self.name
+    }
+
+    fn ata_command(&mut self, tf: &mut AtaTaskfile, data: &mut [u8]) -> io::Result<()> {
+        if self.kind != EmulatedKind::Ata {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not an ATA device"));
+        }
+        match self.ata_exec(tf, data) {
+            Ok(()) => {
+                tf.status = ATA_STATUS_OK;
+                tf.error = 0;
+                Ok(())
+            }
+            Err(reason) => {
+                tf.status = ATA_STATUS_ERR;
+                tf.error = ATA_ERROR_ABRT;
+                Err(io::Error::other(format!("ATA command 0x{:02x} aborted: {}", tf.command, reason)))
+            }
+        }
+    }
+
+    // the backing file is namespace 1
+    fn nvme_nsid(&mut self) -> io::Result<u32> {
+        match self.kind {
+            EmulatedKind::Nvme => Ok(1),
+            EmulatedKind::Ata => Err(io::Error::new(io::ErrorKind::Unsupported, "transport is not an NVMe namespace")),
+        }
+    }
+
+    fn nvme_admin(&mut self, cmd: &NvmeCommand, data: &mut [u8]) -> io::Result<u32> {
+        if self.kind != EmulatedKind::Nvme {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "not an NVMe device"));
+        }
+        self.nvme_exec(cmd, data).map_err(|sc| nvme_error(cmd.opcode, sc))
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if let Some(e) = self.media_blocked() { return Err(e); }
+        if offset + buf.len() as u64 > self.visible_bytes() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "read past end of device"));
+        }
+        if self.io_fault(offset, buf.len(), false) {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if let Some(e) = self.media_blocked() { return Err(e); }
+        if offset + buf.len() as u64 > self.visible_bytes() {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+        if self.io_fault(offset, buf.len(), true) {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn write_direct_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let aligned = |n: u64| n.is_multiple_of(SECTOR_SIZE as u64);
+        if !aligned(offset) || !aligned(buf.len() as u64) || !aligned(buf.as_ptr() as u64) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.write_at(offset, buf)
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.visible_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl Drop for EmulatedDrive {
+    fn drop(&mut self) {
+        if self.remove_on_drop {
+            let _ = std::fs::remove_file(&self.backing);
+        }
+    }
+}
+
+fn nvme_error(opcode: u8, status: u16) -> io::Error {
+    io::Error::other(format!("NVMe admin opcode 0x{:02x} failed: status 0x{:x}", opcode, status))
+}
+
+// ATA strings are space padded with the two bytes of each word swapped
+fn put_ata_string(words: &mut [u16], s: &str) {
+    let mut bytes = vec![b' '; words.len() * 2];
+    let n = s.len().min(bytes.len());
+    bytes[..n].copy_from_slice(&s.as_bytes()[..n]);
+    for (i, w) in words.iter_mut().enumerate() {
+        *w = u16::from_be_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+    }
+}
+
+fn put_nvme_string(field: &mut [u8], s: &str) {
+    field.fill(b' ');
+    let n = s.len().min(field.len());
+    field[..n].copy_from_slice(&s.as_bytes()[..n]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+    use crate::progress::ProgressReporter;
+    use crate::safety::WipeOptions;
+    use crate::evidence::WipeEvidence;
+    use crate::wipe;
+
+    const CAPACITY: u64 = 2 << 20;
+
+    // a drive full of data, sanitizes done on the first status poll
+    fn drive(kind: EmulatedKind) -> EmulatedDrive {
+        let mut d = EmulatedDrive::temp(kind, CAPACITY).unwrap();
+        d.sanitize_polls = 0;
+        d.write_at(0, &vec![0xAA; CAPACITY as usize]).unwrap();
+        d
+    }
+
+    fn wipe(d: &mut EmulatedDrive) -> io::Result<WipeEvidence> {
+        let secret = OrgSecret::new("0123456789abcdef0123").unwrap();
+        let mut dev = d.device(&secret);
+        device::check_firmware_sanitize_with(&mut dev, d);
+        wipe::wipe_device_with(&mut dev, d, &WipeOptions { force: true, ..Default::default() })
+    }
+
+    // no sector anywhere on the device still holds the old data
+    fn erased(d: &EmulatedDrive) -> bool {
+        d.media(0, CAPACITY as usize).unwrap().chunks(SECTOR_SIZE).all(|s| s.iter().any(|&b| b != 0xAA))
+    }
+
+    fn untouched(d: &EmulatedDrive) -> bool {
+        d.media(0, CAPACITY as usize).unwrap().iter().all(|&b| b == 0xAA)
+    }
+
+    #[test]
+    fn nvme_wipe_runs_the_sanitize() {
+        let mut d = drive(EmulatedKind::Nvme);
+        let ev = wipe(&mut d).unwrap();
+        assert_eq!(ev.method, "NVMe sanitize, crypto erase");
+        assert_eq!(ev.nist_level, "Purge");
+        assert!(ev.nvme_sanitize.is_some());
+        assert!(erased(&d));
+    }
+
+    #[test]
+    fn aborted_sanitize_is_retried_by_the_crypto_purge() {
+        let mut d = drive(EmulatedKind::Nvme);
+        d.inject(Fault::AbortSanitize);
+        let ev = wipe(&mut d).unwrap();
+        assert!(ev.logs.iter().any(|l| l.contains("sanitize failure mode")));
+        // the controller refuses a format until a sanitize succeeds
+        assert!(ev.logs.iter().any(|l| l.starts_with("NVMe format, crypto erase failed")));
+        assert_eq!(ev.method, "NVMe sanitize, crypto erase");
+        assert!(!d.nvme.failure_mode);
+        assert!(erased(&d));
+    }
+
+    #[test]
+    fn format_refuses_an_lba_format_the_namespace_lacks() {
+        let mut d = drive(EmulatedKind::Nvme);
+        d.nvme.sanicap = 0;
+        let secret = OrgSecret::new("0123456789abcdef0123").unwrap();
+        let mut dev = d.device(&secret);
+        device::check_firmware_sanitize_with(&mut dev, &mut d);
+        let opts = WipeOptions { force: true, nvme_lba_format: Some(3), ..Default::default() };
+        let ev = wipe::wipe_device_with(&mut dev, &mut d, &opts).unwrap();
+        assert!(ev.logs.iter().any(|l| l.contains("has no LBA format 3")));
+        assert!(ev.nvme_format.is_none());
+        assert_eq!(ev.method, "Software overwrite");
+    }
+
+    #[test]
+    fn frozen_drive_falls_back_to_overwrite() {
+        let mut d = drive(EmulatedKind::Ata);
+        d.ata_sanitize.supported = false;
+        d.freeze();
+        let ev = wipe(&mut d).unwrap();
+        assert!(ev.logs.iter().any(|l| l.contains("frozen")));
+        assert_eq!(ev.method, "Software overwrite");
+        assert_eq!(ev.nist_level, "Clear");
+        assert!(erased(&d));
+    }
+
+    #[test]
+    fn read_error_only_costs_the_partition_inventory() {
+        let mut d = drive(EmulatedKind::Ata);
+        d.inject(Fault::ReadError { start: 0, end: 1 << 20 });
+        let ev = wipe(&mut d).unwrap();
+        assert!(ev.partitions.is_none());
+        assert!(ev.logs.iter().any(|l| l.starts_with("Could not read partition table")));
+        assert_eq!(ev.method, "ATA sanitize, crypto scramble");
+        assert!(erased(&d));
+    }
+
+    #[test]
+    fn write_error_fails_the_overwrite() {
+        let mut d = drive(EmulatedKind::Ata);
+        d.ata.supported = false;
+        d.ata_sanitize.supported = false;
+        d.inject(Fault::WriteError { start: 1 << 20, end: CAPACITY });
+        let err = wipe(&mut d).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert!(untouched(&d));
+    }
+
+    #[test]
+    fn crypto_purge_completes() {
+        let mut d = drive(EmulatedKind::Nvme);
+        wipe::nvme_crypto_purge(&mut d, 60, &ProgressReporter::new()).unwrap();
+        assert!(erased(&d));
+        assert!(!d.nvme.failure_mode);
+    }
+
+    #[test]
+    fn aborted_crypto_purge_leaves_the_drive_in_failure_mode() {
+        let mut d = drive(EmulatedKind::Nvme);
+        d.inject(Fault::AbortSanitize);
+        let err = wipe::nvme_crypto_purge(&mut d, 60, &ProgressReporter::new()).unwrap_err();
+        assert!(err.to_string().contains("failure mode"));
+        assert!(d.nvme.failure_mode);
+        assert!(d.read_at(0, &mut [0u8; 512]).is_err());
+    }
+}
@@ -1,6 +1,13 @@
 use serde::{Serialize, Deserialize};
 use chrono::{Utc, DateTime};
 use uuid::Uuid;
+use crate::ata::{AtaHiddenArea, AtaSanitizeCommand};
+use crate::health::HealthSnapshot;
+use crate::nvme::{NvmeFormat, NvmeSanitize};
+use crate::partition::PartitionInventory;
+use crate::plan::WipePlan;
+use crate::resume::SanitizeReport;
+use crate::tcg::SedStatus;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WipeEvidence {
@@ -15,6 +22,26 @@ pub struct WipeEvidence {
     pub pre_hash: Option<String>,
     pub post_hash: Option<String>,
     pub logs: Vec<String>,
+    pub plan: Option<WipePlan>,
+    /// HPA/DCO findings, ATA only.
+    pub hidden_area: Option<AtaHiddenArea>,
+    /// SMART / health log before and after the wipe.
+    pub health_pre: Option<HealthSnapshot>,
+    pub health_post: Option<HealthSnapshot>,
+    /// Partition tables and filesystems found before wiping.
+    pub partitions: Option<PartitionInventory>,
+    /// TCG state before wiping, self-encrypting drives only. `ranges` is filled
+    /// when the TCG erase got into the Locking SP.
+    pub sed: Option<SedStatus>,
+    /// The Format NVM that did the erase, when one did.
+    pub nvme_format: Option<NvmeFormat>,
+    /// The NVMe Sanitize as issued, action and options.
+    pub nvme_sanitize: Option<NvmeSanitize>,
+    /// The ATA SANITIZE as issued.
+    pub ata_sanitize: Option<AtaSanitizeCommand>,
+    /// Set when the wipe attached to a sanitize that was already running (started by
+    /// an earlier run that crashed or lost power), as the drive reported it then.
+    pub resumed_sanitize: Option<SanitizeReport>,
 }
 
 impl WipeEvidence {
@@ -31,9 +58,24 @@ impl WipeEvidence {
             pre_hash: None,
             post_hash: None,
             logs: Vec::new(),
+            plan: None,
+            hidden_area: None,
+            health_pre: None,
+            health_post: None,
+            partitions: None,
+            sed: None,
+            nvme_format: None,
+            nvme_sanitize: None,
+            ata_sanitize: None,
+            resumed_sanitize: None,
         }
     }
 
+    /// Keep a log line. Nothing is printed, showing `logs` is up to the caller.
+    pub fn log(&mut self, line: impl Into<String>) {
+        self.logs.push(line.into());
+    }
+
     pub fn finish(&mut self) {
         self.timestamp_end = Some(Utc::now());
     }
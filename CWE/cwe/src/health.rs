@@ -0,0 +1,278 @@
+// Drive health snapshots: ATA SMART attributes/thresholds, NVMe SMART / Health
+// Information (log 0x02) and the NVMe Error Information log (0x01).
+//
+// One snapshot is taken before the wipe and one after, both go into the evidence,
+// so a resold drive's power-on hours, media errors and wear sit next to the wipe.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use crate::device::{Device, DeviceType};
+use crate::transport::{AtaTaskfile, DriveTransport};
+use crate::wipe::get_nvme_log_page;
+
+pub const ATA_SMART: u8 = 0xB0;
+const SMART_READ_DATA: u16 = 0xD0;
+const SMART_READ_THRESHOLDS: u16 = 0xD1;
+const SMART_RETURN_STATUS: u16 = 0xDA;
+// LBA mid/high signature every SMART command carries
+const SMART_LBA: u64 = 0xC2_4F00;
+
+const NVME_LOG_ERROR: u32 = 0x01;
+const NVME_LOG_SMART: u32 = 0x02;
+const NVME_ERROR_ENTRIES: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AtaSmartAttribute {
+    pub id: u8,
+    pub name: String,
+    pub current: u8,
+    pub worst: u8,
+    pub threshold: Option<u8>,
+    pub raw: u64,
+    /// Normalized value at or below a non-zero threshold.
+    pub failing: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AtaSmart {
+    /// SMART RETURN STATUS, None if the drive didn't answer it.
+    pub passed: Option<bool>,
+    pub attributes: Vec<AtaSmartAttribute>,
+    pub power_on_hours: Option<u64>,
+    pub power_cycles: Option<u64>,
+    pub reallocated_sectors: Option<u64>,
+    pub pending_sectors: Option<u64>,
+    pub uncorrectable_sectors: Option<u64>,
+    /// Normalized remaining life from whichever wear attribute the vendor uses.
+    pub wear_remaining_percent: Option<u8>,
+    pub temperature_c: Option<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeHealth {
+    pub critical_warning: u8,
+    pub temperature_c: i32,
+    pub available_spare: u8,
+    pub available_spare_threshold: u8,
+    pub percentage_used: u8,
+    /// In units of 1000 512-byte blocks, as the log reports them.
+    pub data_units_read: u64,
+    pub data_units_written: u64,
+    pub power_cycles: u64,
+    pub power_on_hours: u64,
+    pub unsafe_shutdowns: u64,
+    pub media_errors: u64,
+    pub error_log_entries: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeErrorEntry {
+    pub error_count: u64,
+    pub sqid: u16,
+    pub cid: u16,
+    pub status: u16,
+    pub lba: u64,
+    pub nsid: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HealthSnapshot {
+    pub taken_at: DateTime<Utc>,
+    pub ata: Option<AtaSmart>,
+    pub nvme: Option<NvmeHealth>,
+    pub nvme_errors: Vec<NvmeErrorEntry>,
+    /// What couldn't be read and why.
+    pub notes: Vec<String>,
+}
+
+impl HealthSnapshot {
+    /// One line for the pre-wipe scan: hours, media errors, wear.
+    pub fn summary(&self) -> String {
+        let opt = |v: Option<u64>| v.map_or("?".to_string(), |v| v.to_string());
+        if let Some(a) = &self.ata {
+            let status = match a.passed {
+                Some(true) => "PASSED",
+                Some(false) => "FAILING",
+                None => "unknown",
+            };
+            format!("SMART {}, {} power-on hours, {} reallocated, {} pending, wear left {}",
+                status, opt(a.power_on_hours), opt(a.reallocated_sectors), opt(a.pending_sectors),
+                a.wear_remaining_percent.map_or("?".to_string(), |w| format!("{}%", w)))
+        } else if let Some(n) = &self.nvme {
+            format!("critical warning 0x{:02x}, {} power-on hours, {} media errors, {}% used, {}% spare",
+                n.critical_warning, n.power_on_hours, n.media_errors, n.percentage_used, n.available_spare)
+        } else {
+            format!("no health data ({})", self.notes.join("; "))
+        }
+    }
+}
+
+fn attribute_name(id: u8) -> &'static str {
+    match id {
+        1 => "Raw_Read_Error_Rate",
+        5 => "Reallocated_Sector_Ct",
+        9 => "Power_On_Hours",
+        12 => "Power_Cycle_Count",
+        177 => "Wear_Leveling_Count",
+        187 => "Reported_Uncorrect",
+        194 => "Temperature_Celsius",
+        196 => "Reallocated_Event_Count",
+        197 => "Current_Pending_Sector",
+        198 => "Offline_Uncorrectable",
+        199 => "UDMA_CRC_Error_Count",
+        231 => "SSD_Life_Left",
+        233 => "Media_Wearout_Indicator",
+        241 => "Total_LBAs_Written",
+        242 => "Total_LBAs_Read",
+        _ => "Unknown_Attribute",
+    }
+}
+
+fn le_u64(b: &[u8]) -> u64 {
+    b.iter().rev().fold(0u64, |acc, x| (acc << 8) | *x as u64)
+}
+
+// NVMe counters are 128-bit, nothing real gets near u64::MAX
+fn le_u128_sat(b: &[u8]) -> u64 {
+    if b[8..16].iter().any(|x| *x != 0) { u64::MAX } else { le_u64(&b[..8]) }
+}
+
+impl AtaSmart {
+    /// Decode SMART READ DATA and (optionally) READ THRESHOLDS. Both are 30
+    /// 12-byte entries starting at offset 2.
+    pub fn parse(data: &[u8], thresholds: Option<&[u8]>) -> io::Result<Self> {
+        if data.len() < 512 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SMART data shorter than 512 bytes"));
+        }
+        let mut smart = AtaSmart::default();
+        for entry in data[2..2 + 30 * 12].chunks(12) {
+            let id = entry[0];
+            if id == 0 {
+                continue;
+            }
+            let threshold = thresholds.and_then(|t| {
+                t[2..2 + 30 * 12].chunks(12).find(|e| e[0] == id).map(|e| e[1])
+            });
+            let raw = le_u64(&entry[5..11]);
+            let current = entry[3];
+            smart.attributes.push(AtaSmartAttribute {
+                id,
+                name: attribute_name(id).to_string(),
+                current,
+                worst: entry[4],
+                threshold,
+                raw,
+                failing: threshold.is_some_and(|t| t != 0 && current <= t),
+            });
+
+            match id {
+                // some vendors keep minutes/seconds in the upper bytes, the low 32 bits are hours
+                9 => smart.power_on_hours = Some(raw & 0xFFFF_FFFF),
+                12 => smart.power_cycles = Some(raw),
+                5 => smart.reallocated_sectors = Some(raw),
+                197 => smart.pending_sectors = Some(raw),
+                198 => smart.uncorrectable_sectors = Some(raw),
+                177 | 231 | 233 => smart.wear_remaining_percent = Some(current.min(100)),
+                194 => smart.temperature_c = Some(raw as u8),
+                _ => {}
+            }
+        }
+        Ok(smart)
+    }
+}
+
+impl NvmeHealth {
+    pub fn parse(d: &[u8]) -> io::Result<Self> {
+        if d.len() < 512 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SMART / Health log shorter than 512 bytes"));
+        }
+        Ok(NvmeHealth {
+            critical_warning: d[0],
+            temperature_c: u16::from_le_bytes([d[1], d[2]]) as i32 - 273,
+            available_spare: d[3],
+            available_spare_threshold: d[4],
+            percentage_used: d[5],
+            data_units_read: le_u128_sat(&d[32..48]),
+            data_units_written: le_u128_sat(&d[48..64]),
+            power_cycles: le_u128_sat(&d[112..128]),
+            power_on_hours: le_u128_sat(&d[128..144]),
+            unsafe_shutdowns: le_u128_sat(&d[144..160]),
+            media_errors: le_u128_sat(&d[160..176]),
+            error_log_entries: le_u128_sat(&d[176..192]),
+        })
+    }
+}
+
+/// SMART READ DATA + READ THRESHOLDS + RETURN STATUS.
+pub fn read_ata_smart(t: &mut dyn DriveTransport) -> io::Result<AtaSmart> {
+    let mut data = [0u8; 512];
+    let mut tf = AtaTaskfile::new(ATA_SMART).features(SMART_READ_DATA).lba(SMART_LBA).count(1).data_in();
+    t.ata_command(&mut tf, &mut data)?;
+
+    // thresholds are obsolete in ACS-3, plenty of drives still answer
+    let mut thr = [0u8; 512];
+    let mut tf = AtaTaskfile::new(ATA_SMART).features(SMART_READ_THRESHOLDS).lba(SMART_LBA).count(1).data_in();
+    let thresholds = t.ata_command(&mut tf, &mut thr).ok().map(|_| &thr[..]);
+
+    let mut smart = AtaSmart::parse(&data, thresholds)?;
+
+    // LBA mid/high flip to 0xF4/0x2C when a threshold is exceeded
+    let mut tf = AtaTaskfile::new(ATA_SMART).features(SMART_RETURN_STATUS).lba(SMART_LBA);
+    if t.ata_command(&mut tf, &mut []).is_ok() {
+        smart.passed = match (tf.lba >> 8) & 0xFFFF {
+            0xC24F => Some(true),
+            0x2CF4 => Some(false),
+            _ => None, // backend didn't hand the registers back
+        };
+    }
+    Ok(smart)
+}
+
+pub fn read_nvme_health(t: &mut dyn DriveTransport) -> io::Result<NvmeHealth> {
+    let mut buf = vec![0u8; 512];
+    get_nvme_log_page(t, NVME_LOG_SMART, &mut buf)?;
+    NvmeHealth::parse(&buf)
+}
+
+/// The newest entries of the Error Information log, empty slots dropped.
+pub fn read_nvme_errors(t: &mut dyn DriveTransport) -> io::Result<Vec<NvmeErrorEntry>> {
+    let mut buf = vec![0u8; 64 * NVME_ERROR_ENTRIES];
+    get_nvme_log_page(t, NVME_LOG_ERROR, &mut buf)?;
+    Ok(buf
+        .chunks(64)
+        .map(|e| NvmeErrorEntry {
+            error_count: le_u64(&e[0..8]),
+            sqid: u16::from_le_bytes([e[8], e[9]]),
+            cid: u16::from_le_bytes([e[10], e[11]]),
+            status: u16::from_le_bytes([e[12], e[13]]) >> 1,
+            lba: le_u64(&e[16..24]),
+            nsid: u32::from_le_bytes([e[24], e[25], e[26], e[27]]),
+        })
+        .filter(|e| e.error_count != 0)
+        .collect())
+}
+
+/// Read whatever health data the device type offers. Failures become notes,
+/// a drive without SMART must not stop the wipe.
+pub fn snapshot(dev: &Device, t: &mut dyn DriveTransport) -> HealthSnapshot {
+    let mut snap = HealthSnapshot { taken_at: Utc::now(), ata: None, nvme: None, nvme_errors: Vec::new(), notes: Vec::new() };
+    match dev.devtype {
+        DeviceType::Sata => match read_ata_smart(t) {
+            Ok(s) => snap.ata = Some(s),
+            Err(e) => snap.notes.push(format!("SMART read failed: {}", e)),
+        },
+        DeviceType::Nvme => {
+            match read_nvme_health(t) {
+                Ok(h) => snap.nvme = Some(h),
+                Err(e) => snap.notes.push(format!("SMART / Health log read failed: {}", e)),
+            }
+            match read_nvme_errors(t) {
+                Ok(errors) => snap.nvme_errors = errors,
+                Err(e) => snap.notes.push(format!("Error Information log read failed: {}", e)),
+            }
+        }
+        _ => snap.notes.push(format!("no health data for {:?} devices", dev.devtype)),
+    }
+    snap
+}
@@ -0,0 +1,111 @@
+// Stable, privacy-preserving device identifiers.
+//
+// Device::id is HMAC-SHA256(org secret, serial). The secret is per organization,
+// so the same drive gets the same id on every run and every machine sharing the
+// secret (certificates correlate), while nobody without the secret can go from a
+// serial to an id or back.
+//
+// The secret comes from, in order:
+//   CWE_ORG_SECRET        the secret itself
+//   CWE_ORG_SECRET_FILE   a file holding it
+//   /etc/cwe/org-secret   created with a random secret on first use
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use rand::Rng;
+
+pub const DEFAULT_SECRET_PATH: &str = "/etc/cwe/org-secret";
+const SECRET_ENV: &str = "CWE_ORG_SECRET";
+const SECRET_FILE_ENV: &str = "CWE_ORG_SECRET_FILE";
+// anything shorter is guessable
+const MIN_SECRET_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct OrgSecret(Vec<u8>);
+
+// never print the key
+impl fmt::Debug for OrgSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OrgSecret(..)")
+    }
+}
+
+impl OrgSecret {
+    pub fn new(secret: impl Into<Vec<u8>>) -> io::Result<Self> {
+        let secret = secret.into();
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("organization secret must be at least {} bytes", MIN_SECRET_LEN),
+            ));
+        }
+        Ok(OrgSecret(secret))
+    }
+
+    /// Load the configured secret, creating the default file if nothing is configured.
+    pub fn load() -> io::Result<Self> {
+        Self::load_noting_creation().map(|(secret, _)| secret)
+    }
+
+    /// Like `load`, plus the path of the default file if this call created it.
+    /// Telling the operator to copy it around is up to the caller.
+    pub fn load_noting_creation() -> io::Result<(Self, Option<PathBuf>)> {
+        if let Ok(s) = std::env::var(SECRET_ENV) {
+            return Ok((Self::new(s.trim())?, None));
+        }
+        match std::env::var_os(SECRET_FILE_ENV) {
+            Some(path) => Ok((Self::from_file(path)?, None)),
+            None => {
+                let (secret, created) = Self::load_or_create(DEFAULT_SECRET_PATH)?;
+                Ok((secret, created.then(|| PathBuf::from(DEFAULT_SECRET_PATH))))
+            }
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("reading organization secret {}: {}", path.display(), e)))?;
+        Self::new(text.trim())
+    }
+
+    /// Read `path`, or write a fresh random secret there (mode 0600) if it doesn't exist.
+    /// The flag is set when the file was just created.
+    pub fn load_or_create(path: impl Into<PathBuf>) -> io::Result<(Self, bool)> {
+        let path = path.into();
+        if path.exists() {
+            return Ok((Self::from_file(&path)?, false));
+        }
+        let secret = hex::encode(rand::rng().random::<[u8; 32]>());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut f = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("creating organization secret {}: {}", path.display(), e)))?;
+        writeln!(f, "{}", secret)?;
+        Ok((Self::new(secret)?, true))
+    }
+
+    /// Hex HMAC-SHA256 of the device's serial. Devices without a serial fall back
+    /// to their path, which is only stable until the next reboot.
+    pub fn device_id(&self, serial: Option<&str>, dev_path: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes any key length");
+        mac.update(b"cwe-device-id/v1\0");
+        match serial.map(str::trim).filter(|s| !s.is_empty()) {
+            Some(s) => {
+                mac.update(b"serial\0");
+                mac.update(s.as_bytes());
+            }
+            None => {
+                mac.update(b"path\0");
+                mac.update(dev_path.as_bytes());
+            }
+        }
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
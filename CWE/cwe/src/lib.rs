@@ -1,6 +1,26 @@
 pub mod device;
 pub mod wipe;
-// pub mod evidence;
+pub mod transport;
+pub mod emulator;
+pub mod ata;
+pub mod nvme;
+pub mod sgio;
+pub mod scsi;
+pub mod mmc;
+pub mod safety;
+pub mod select;
+pub mod plan;
+pub mod capability;
+pub mod sysfs;
+pub mod evidence;
+pub mod health;
+pub mod identity;
+pub mod partition;
+pub mod watch;
+pub mod tcg;
+pub mod resume;
+pub mod progress;
+pub mod overwrite;
 // pub mod signer;
 // pub mod runner;
 
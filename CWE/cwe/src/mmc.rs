@@ -0,0 +1,284 @@
+// eMMC / SD command helpers: EXT_CSD decoding, ERASE, secure erase/trim, SANITIZE
+// and card status polling (byte and bit numbers per JESD84-B51).
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::transport::{DriveTransport, MmcCommand};
+
+pub const MMC_SWITCH: u32 = 6;
+pub const MMC_SEND_EXT_CSD: u32 = 8;
+pub const MMC_SEND_STATUS: u32 = 13;
+pub const SD_ERASE_WR_BLK_START: u32 = 32;
+pub const SD_ERASE_WR_BLK_END: u32 = 33;
+pub const MMC_ERASE_GROUP_START: u32 = 35;
+pub const MMC_ERASE_GROUP_END: u32 = 36;
+pub const MMC_ERASE: u32 = 38;
+
+// response/command type flags, linux/mmc/core.h
+pub const MMC_RSP_PRESENT: u32 = 1 << 0;
+pub const MMC_RSP_CRC: u32 = 1 << 2;
+pub const MMC_RSP_BUSY: u32 = 1 << 3;
+pub const MMC_RSP_OPCODE: u32 = 1 << 4;
+pub const MMC_CMD_AC: u32 = 0;
+pub const MMC_CMD_ADTC: u32 = 1 << 5;
+pub const MMC_RSP_R1: u32 = MMC_RSP_PRESENT | MMC_RSP_CRC | MMC_RSP_OPCODE;
+pub const MMC_RSP_R1B: u32 = MMC_RSP_R1 | MMC_RSP_BUSY;
+
+// CMD38 arguments
+pub const MMC_ERASE_ARG: u32 = 0x0000_0000;
+pub const MMC_SECURE_ERASE_ARG: u32 = 0x8000_0000;
+pub const MMC_SECURE_TRIM1_ARG: u32 = 0x8000_0001;
+pub const MMC_SECURE_TRIM2_ARG: u32 = 0x8000_8000;
+
+// EXT_CSD byte offsets
+const EXT_CSD_SANITIZE_START: u8 = 165;
+const EXT_CSD_ERASE_GROUP_DEF: usize = 175;
+const EXT_CSD_REV: usize = 192;
+const EXT_CSD_SEC_CNT: usize = 212;
+const EXT_CSD_HC_ERASE_TIMEOUT: usize = 223;
+const EXT_CSD_HC_ERASE_GRP_SIZE: usize = 224;
+const EXT_CSD_SEC_TRIM_MULT: usize = 229;
+const EXT_CSD_SEC_ERASE_MULT: usize = 230;
+const EXT_CSD_SEC_FEATURE_SUPPORT: usize = 231;
+
+// R1 card status: CURRENT_STATE in bits 12:9, 4 = tran, 7 = prg
+const R1_READY_FOR_DATA: u32 = 1 << 8;
+const R1_STATE_TRAN: u32 = 4;
+// OUT_OF_RANGE .. ERROR, WP_ERASE_SKIP, SWITCH_ERROR
+const R1_ERROR_MASK: u32 = 0xFDF8_8080;
+
+// the kernel's own sanitize timeout is 240s, real parts take much longer on big cards
+const SANITIZE_TIMEOUT_MS: u32 = 4 * 3600 * 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MmcCardType {
+    Mmc,
+    Sd,
+}
+
+/// The parts of EXT_CSD that decide how a card can be purged.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct MmcExtCsd {
+    pub rev: u8,
+    pub sec_count: u32,
+    pub sanitize: bool,
+    pub secure_erase: bool,
+    /// SEC_GB_CL_EN: secure trim and TRIM are available.
+    pub secure_trim: bool,
+    pub high_capacity_erase_groups: bool,
+    /// Erase group in 512 byte sectors.
+    pub erase_group_sectors: u32,
+    /// Erase timeout per group in ms.
+    pub erase_timeout_ms: u32,
+    pub sec_erase_mult: u8,
+    pub sec_trim_mult: u8,
+}
+
+impl MmcExtCsd {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if data.len() < 512 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "EXT_CSD shorter than 512 bytes"));
+        }
+        let sec = data[EXT_CSD_SEC_FEATURE_SUPPORT];
+        let hc = data[EXT_CSD_ERASE_GROUP_DEF] & 1 != 0;
+        // high capacity groups are in 512 KiB units, otherwise assume the usual 512 KiB too
+        let grp = data[EXT_CSD_HC_ERASE_GRP_SIZE] as u32;
+        let erase_group_sectors = if hc && grp > 0 { grp * 1024 } else { 1024 };
+        let tmo = data[EXT_CSD_HC_ERASE_TIMEOUT] as u32;
+
+        Ok(MmcExtCsd {
+            rev: data[EXT_CSD_REV],
+            sec_count: u32::from_le_bytes(data[EXT_CSD_SEC_CNT..EXT_CSD_SEC_CNT + 4].try_into().unwrap()),
+            // SANITIZE arrived with eMMC 4.5 (rev 6)
+            sanitize: data[EXT_CSD_REV] >= 6 && sec & (1 << 6) != 0,
+            secure_erase: sec & 1 != 0,
+            secure_trim: sec & 1 != 0 && sec & (1 << 4) != 0,
+            high_capacity_erase_groups: hc,
+            erase_group_sectors,
+            erase_timeout_ms: if tmo > 0 { tmo * 300 } else { 300 },
+            sec_erase_mult: data[EXT_CSD_SEC_ERASE_MULT],
+            sec_trim_mult: data[EXT_CSD_SEC_TRIM_MULT],
+        })
+    }
+}
+
+/// Ways to erase a card, strongest first.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum MmcErase {
+    /// ERASE the user area, then SANITIZE purges the unmapped blocks.
+    Sanitize,
+    SecureErase,
+    SecureTrim,
+    /// Plain ERASE. Clears the mapping, doesn't guarantee the cells are purged.
+    Erase,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MmcInfo {
+    pub card_type: MmcCardType,
+    /// None for SD cards, they have no EXT_CSD.
+    pub ext_csd: Option<MmcExtCsd>,
+    pub sectors: u64,
+}
+
+impl MmcInfo {
+    /// Every erase the card supports, strongest first. Plain ERASE is always there.
+    pub fn methods(&self) -> Vec<MmcErase> {
+        let mut v = Vec::new();
+        if let Some(e) = &self.ext_csd {
+            if e.sanitize { v.push(MmcErase::Sanitize); }
+            if e.secure_erase { v.push(MmcErase::SecureErase); }
+            if e.secure_trim { v.push(MmcErase::SecureTrim); }
+        }
+        v.push(MmcErase::Erase);
+        v
+    }
+
+    /// True when something stronger than plain ERASE is available.
+    pub fn purge_capable(&self) -> bool {
+        self.methods()[0] != MmcErase::Erase
+    }
+
+    // cards above 2 GB take sector addresses, smaller ones byte addresses
+    fn address(&self, sector: u64) -> u32 {
+        if self.sectors > (2u64 << 30) / 512 { sector as u32 } else { (sector * 512) as u32 }
+    }
+
+    fn erase_group_sectors(&self) -> u64 {
+        self.ext_csd.as_ref().map(|e| e.erase_group_sectors as u64).unwrap_or(1024)
+    }
+}
+
+/// "MMC" or "SD" from /sys/block/<name>/device/type.
+pub fn card_type(dev_path: &str) -> Option<MmcCardType> {
+    let name = dev_path.rsplit('/').next()?;
+    let t = std::fs::read_to_string(format!("/sys/block/{}/device/type", name)).ok()?;
+    match t.trim() {
+        "MMC" => Some(MmcCardType::Mmc),
+        "SD" => Some(MmcCardType::Sd),
+        _ => None,
+    }
+}
+
+/// CMD8 SEND_EXT_CSD.
+pub fn read_ext_csd(t: &mut dyn DriveTransport) -> io::Result<MmcExtCsd> {
+    let mut data = [0u8; 512];
+    let mut cmd = MmcCommand::new(MMC_SEND_EXT_CSD, 0, MMC_RSP_R1 | MMC_CMD_ADTC);
+    cmd.blksz = 512;
+    cmd.blocks = 1;
+    t.mmc_command(&mut cmd, &mut data)?;
+    MmcExtCsd::parse(&data)
+}
+
+/// CMD13 SEND_STATUS. Through the ioctl the card is always RCA 1.
+pub fn send_status(t: &mut dyn DriveTransport) -> io::Result<u32> {
+    let mut cmd = MmcCommand::new(MMC_SEND_STATUS, 1 << 16, MMC_RSP_R1 | MMC_CMD_AC);
+    t.mmc_command(&mut cmd, &mut [])?;
+    Ok(cmd.response[0])
+}
+
+/// Poll card status until the card is back in tran state and ready for data.
+pub fn wait_ready(t: &mut dyn DriveTransport, timeout: Duration) -> io::Result<()> {
+    let start = Instant::now();
+    loop {
+        let status = send_status(t)?;
+        if status & R1_ERROR_MASK != 0 {
+            return Err(io::Error::other(format!("MMC card status reports error 0x{:08x}", status)));
+        }
+        let state = (status >> 9) & 0xF;
+        if state == R1_STATE_TRAN && status & R1_READY_FOR_DATA != 0 {
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("MMC card still busy (state {})", state)));
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Probe a card: EXT_CSD for eMMC, nothing to read for SD.
+pub fn probe(t: &mut dyn DriveTransport, card_type: MmcCardType) -> io::Result<MmcInfo> {
+    let ext_csd = match card_type {
+        MmcCardType::Mmc => Some(read_ext_csd(t)?),
+        MmcCardType::Sd => None,
+    };
+    let sectors = match &ext_csd {
+        Some(e) if e.sec_count > 0 => e.sec_count as u64,
+        _ => t.size()? / 512,
+    };
+    Ok(MmcInfo { card_type, ext_csd, sectors })
+}
+
+// One CMD35/36/38 (CMD32/33/38 on SD) over [start, end] sectors. The three go out
+// in one MMC_IOC_MULTI_CMD so nothing lands between them.
+fn erase_range(t: &mut dyn DriveTransport, info: &MmcInfo, start: u64, end: u64, arg: u32, timeout_ms: u32) -> io::Result<()> {
+    let (start_op, end_op) = match info.card_type {
+        MmcCardType::Mmc => (MMC_ERASE_GROUP_START, MMC_ERASE_GROUP_END),
+        MmcCardType::Sd => (SD_ERASE_WR_BLK_START, SD_ERASE_WR_BLK_END),
+    };
+    let mut erase = MmcCommand::new(MMC_ERASE, arg, MMC_RSP_R1B | MMC_CMD_AC);
+    erase.timeout_ms = timeout_ms;
+    let mut cmds = [
+        MmcCommand::new(start_op, info.address(start), MMC_RSP_R1 | MMC_CMD_AC),
+        MmcCommand::new(end_op, info.address(end), MMC_RSP_R1 | MMC_CMD_AC),
+        erase,
+    ];
+    t.mmc_sequence(&mut cmds)?;
+    wait_ready(t, Duration::from_millis(timeout_ms as u64 + 60_000))
+}
+
+/// Erase the whole user area with the given CMD38 argument, a chunk of erase groups
+/// at a time so every command stays inside its busy timeout.
+pub fn erase_all(t: &mut dyn DriveTransport, info: &MmcInfo, arg: u32) -> io::Result<()> {
+    let group = info.erase_group_sectors();
+    let chunk = group * 2048;
+    let (per_group_ms, mult) = match &info.ext_csd {
+        Some(e) => {
+            let mult = match arg {
+                MMC_SECURE_ERASE_ARG => e.sec_erase_mult.max(1),
+                MMC_SECURE_TRIM1_ARG | MMC_SECURE_TRIM2_ARG => e.sec_trim_mult.max(1),
+                _ => 1,
+            };
+            (e.erase_timeout_ms, mult as u32)
+        }
+        None => (250, 1), // SD: 250ms per AU is the spec default
+    };
+    let timeout_ms = (2048 * per_group_ms as u64 * mult as u64).clamp(60_000, u32::MAX as u64) as u32;
+
+    let mut start = 0u64;
+    while start < info.sectors {
+        let end = (start + chunk).min(info.sectors) - 1;
+        erase_range(t, info, start, end, arg, timeout_ms)?;
+        start = end + 1;
+    }
+    Ok(())
+}
+
+/// Write SANITIZE_START and wait for the card to finish.
+pub fn sanitize(t: &mut dyn DriveTransport) -> io::Result<()> {
+    // CMD6 SWITCH, access mode 3 = write byte
+    let arg = (3 << 24) | ((EXT_CSD_SANITIZE_START as u32) << 16) | (1 << 8);
+    let mut cmd = MmcCommand::new(MMC_SWITCH, arg, MMC_RSP_R1B | MMC_CMD_AC);
+    cmd.timeout_ms = SANITIZE_TIMEOUT_MS;
+    t.mmc_command(&mut cmd, &mut [])?;
+    wait_ready(t, Duration::from_millis(SANITIZE_TIMEOUT_MS as u64))
+}
+
+/// Run one erase method over the whole card.
+pub fn purge(t: &mut dyn DriveTransport, info: &MmcInfo, method: MmcErase) -> io::Result<()> {
+    match method {
+        MmcErase::Sanitize => {
+            // SANITIZE only purges unmapped blocks, unmap everything first
+            erase_all(t, info, MMC_ERASE_ARG)?;
+            sanitize(t)
+        }
+        MmcErase::SecureErase => erase_all(t, info, MMC_SECURE_ERASE_ARG),
+        MmcErase::SecureTrim => {
+            erase_all(t, info, MMC_SECURE_TRIM1_ARG)?;
+            erase_all(t, info, MMC_SECURE_TRIM2_ARG)
+        }
+        MmcErase::Erase => erase_all(t, info, MMC_ERASE_ARG),
+    }
+}
@@ -0,0 +1,525 @@
+// NVMe admin helpers and Identify decoding (byte offsets per NVMe Base Spec 2.0).
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::progress::ProgressReporter;
+use crate::transport::{nvme_ctrl_path, DriveTransport, NvmeCommand};
+
+pub const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+pub const NVME_ADMIN_SANITIZE: u8 = 0x84;
+pub const NVME_ADMIN_FORMAT_NVM: u8 = 0x80;
+pub const NVME_ADMIN_SECURITY_SEND: u8 = 0x81;
+pub const NVME_ADMIN_SECURITY_RECEIVE: u8 = 0x82;
+pub const NVME_LOG_SANITIZE_STATUS: u32 = 0x81;
+/// Broadcast NSID: the command addresses every namespace on the controller.
+pub const NVME_NSID_ALL: u32 = 0xFFFF_FFFF;
+
+const SANITIZE_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+// Format NVM completes only when the erase is done
+const FORMAT_CRYPTO_TIMEOUT_MS: u32 = 10 * 60 * 1000;
+const FORMAT_ERASE_TIMEOUT_MS: u32 = 24 * 3600 * 1000;
+
+/// SANACT field of the Sanitize command (CDW10 bits 2:0).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SanitizeAction {
+    ExitFailureMode = 1,
+    BlockErase = 2,
+    Overwrite = 3,
+    CryptoErase = 4,
+}
+
+/// A Sanitize command with its CDW10/CDW11 options, goes into the evidence.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NvmeSanitize {
+    pub action: SanitizeAction,
+    /// AUSE: after a failed sanitize the controller may be recovered with Exit Failure
+    /// Mode. Off (restricted) means only another successful sanitize clears the failure.
+    pub allow_unrestricted_exit: bool,
+    /// OWPASS, 1 to 16. Overwrite only.
+    pub overwrite_passes: u8,
+    /// OIPBP, invert the pattern between passes. Overwrite only.
+    pub invert_between_passes: bool,
+    /// Overwrite pattern (CDW11).
+    pub pattern: u32,
+    /// NDAS, leave the media as sanitized instead of deallocating it afterwards.
+    pub no_deallocate: bool,
+}
+
+impl NvmeSanitize {
+    /// Restricted completion, one overwrite pass of zeros, deallocate after.
+    pub fn new(action: SanitizeAction) -> Self {
+        NvmeSanitize {
+            action,
+            allow_unrestricted_exit: false,
+            overwrite_passes: 1,
+            invert_between_passes: false,
+            pattern: 0,
+            no_deallocate: false,
+        }
+    }
+
+    // SANACT 2:0, AUSE 3, OWPASS 7:4 (0 means 16), OIPBP 8, NDAS 9
+    fn cdw10(&self) -> u32 {
+        let mut cdw10 = self.action as u32 | (self.allow_unrestricted_exit as u32) << 3 | (self.no_deallocate as u32) << 9;
+        if self.action == SanitizeAction::Overwrite {
+            cdw10 |= ((self.overwrite_passes & 0xF) as u32) << 4 | (self.invert_between_passes as u32) << 8;
+        }
+        cdw10
+    }
+
+    /// Refuse what the controller would reject anyway, before it gets the command.
+    pub fn check(&self, id: &NvmeIdController) -> io::Result<()> {
+        if !id.supports(self.action) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("controller does not support sanitize {:?}", self.action)));
+        }
+        if self.action == SanitizeAction::Overwrite && !(1..=16).contains(&self.overwrite_passes) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} overwrite passes, 1 to 16 allowed", self.overwrite_passes)));
+        }
+        Ok(())
+    }
+}
+
+/// SES field of Format NVM (CDW10 bits 11:9).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SecureErase {
+    None = 0,
+    UserData = 1,
+    Crypto = 2,
+}
+
+/// One Format NVM as issued, goes into the evidence.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NvmeFormat {
+    /// Namespace addressed, `NVME_NSID_ALL` when FNA makes the format controller wide.
+    pub nsid: u32,
+    /// Index into the namespace's LBA format table.
+    pub lba_format: u8,
+    pub ses: SecureErase,
+}
+
+impl NvmeFormat {
+    // LBAF bits 3:0 in 3:0 and bits 5:4 in 13:12, MSET/PI/PIL left 0
+    fn cdw10(&self) -> u32 {
+        let lbaf = self.lba_format as u32;
+        (lbaf & 0x0F) | ((self.ses as u32) << 9) | (((lbaf >> 4) & 0x3) << 12)
+    }
+}
+
+/// SANICAP, Sanitize Capabilities (bytes 331:328).
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeSanicap {
+    pub crypto_erase: bool,
+    pub block_erase: bool,
+    pub overwrite: bool,
+    /// No-Deallocate Inhibited: the No-Deallocate After Sanitize bit is ignored.
+    pub no_dealloc_inhibited: bool,
+    /// No-Deallocate Modifies Media After Sanitize (0 = not reported, 1 = no, 2 = yes).
+    pub nodmmas: u8,
+}
+
+/// OACS, Optional Admin Command Support (bytes 257:256).
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeOacs {
+    pub security_send_recv: bool,
+    pub format_nvm: bool,
+    pub firmware_download: bool,
+    pub ns_management: bool,
+}
+
+/// FNA, Format NVM Attributes (byte 524).
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeFna {
+    /// Format applies to all namespaces, not just the one addressed.
+    pub format_all_namespaces: bool,
+    /// Secure erase applies to all namespaces.
+    pub erase_all_namespaces: bool,
+    /// Cryptographic erase supported as part of Format NVM secure erase.
+    pub crypto_erase_supported: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeIdController {
+    pub vid: u16,
+    #[serde(skip_serializing, default)]
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+    pub oacs: NvmeOacs,
+    pub namespaces: u32,
+    pub fna: NvmeFna,
+    pub sanicap: NvmeSanicap,
+}
+
+impl NvmeIdController {
+    /// Decode the 4096 byte Identify Controller data structure (CNS 01h).
+    pub fn parse(d: &[u8]) -> io::Result<Self> {
+        if d.len() < 4096 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Identify Controller data shorter than 4096 bytes"));
+        }
+        let le16 = |o: usize| u16::from_le_bytes([d[o], d[o + 1]]);
+        let le32 = |o: usize| u32::from_le_bytes([d[o], d[o + 1], d[o + 2], d[o + 3]]);
+
+        let oacs = le16(256);
+        let sanicap = le32(328);
+        let fna = d[524];
+
+        Ok(NvmeIdController {
+            vid: le16(0),
+            serial: nvme_string(&d[4..24]),
+            model: nvme_string(&d[24..64]),
+            firmware: nvme_string(&d[64..72]),
+            oacs: NvmeOacs {
+                security_send_recv: oacs & (1 << 0) != 0,
+                format_nvm: oacs & (1 << 1) != 0,
+                firmware_download: oacs & (1 << 2) != 0,
+                ns_management: oacs & (1 << 3) != 0,
+            },
+            namespaces: le32(516),
+            fna: NvmeFna {
+                format_all_namespaces: fna & (1 << 0) != 0,
+                erase_all_namespaces: fna & (1 << 1) != 0,
+                crypto_erase_supported: fna & (1 << 2) != 0,
+            },
+            sanicap: NvmeSanicap {
+                crypto_erase: sanicap & (1 << 0) != 0,
+                block_erase: sanicap & (1 << 1) != 0,
+                overwrite: sanicap & (1 << 2) != 0,
+                no_dealloc_inhibited: sanicap & (1 << 29) != 0,
+                nodmmas: ((sanicap >> 30) & 0x3) as u8,
+            },
+        })
+    }
+
+    pub fn supports_sanitize(&self) -> bool {
+        self.sanicap.crypto_erase || self.sanicap.block_erase || self.sanicap.overwrite
+    }
+
+    pub fn supports(&self, action: SanitizeAction) -> bool {
+        match action {
+            SanitizeAction::ExitFailureMode => self.supports_sanitize(),
+            SanitizeAction::BlockErase => self.sanicap.block_erase,
+            SanitizeAction::Overwrite => self.sanicap.overwrite,
+            SanitizeAction::CryptoErase => self.sanicap.crypto_erase,
+        }
+    }
+
+    /// Strongest sanitize action the controller supports: crypto erase, then
+    /// block erase, then overwrite.
+    pub fn preferred_sanitize(&self) -> Option<SanitizeAction> {
+        [SanitizeAction::CryptoErase, SanitizeAction::BlockErase, SanitizeAction::Overwrite]
+            .into_iter()
+            .find(|a| self.supports(*a))
+    }
+}
+
+/// One entry of the LBA Format table.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeLbaFormat {
+    pub data_size: u32,
+    pub metadata_size: u16,
+    /// Relative performance, 0 = best.
+    pub relative_performance: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeNamespace {
+    pub nsid: u32,
+    /// Block device for the namespace when it can be derived, e.g. /dev/nvme0n2
+    pub block_device: Option<String>,
+    pub size_blocks: u64,
+    pub capacity_blocks: u64,
+    pub utilization_blocks: u64,
+    /// Index of the LBA format in use (FLBAS).
+    pub lba_format_index: u8,
+    pub lba_formats: Vec<NvmeLbaFormat>,
+}
+
+impl NvmeNamespace {
+    /// Decode the 4096 byte Identify Namespace data structure (CNS 00h).
+    pub fn parse(nsid: u32, d: &[u8]) -> io::Result<Self> {
+        if d.len() < 4096 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Identify Namespace data shorter than 4096 bytes"));
+        }
+        let le64 = |o: usize| u64::from_le_bytes(d[o..o + 8].try_into().unwrap());
+
+        let nlbaf = d[25] as usize + 1; // 0's based
+        let flbas = d[26];
+        // bits 3:0 are the low index bits, bits 6:5 the high ones when more than 16 formats exist
+        let lba_format_index = (flbas & 0x0F) | ((flbas >> 1) & 0x30);
+
+        let lba_formats = (0..nlbaf.min(64))
+            .map(|i| {
+                let o = 128 + i * 4;
+                let v = u32::from_le_bytes([d[o], d[o + 1], d[o + 2], d[o + 3]]);
+                let lbads = (v >> 16) & 0xFF;
+                NvmeLbaFormat {
+                    data_size: if (9..32).contains(&lbads) { 1 << lbads } else { 0 },
+                    metadata_size: v as u16,
+                    relative_performance: ((v >> 24) & 0x3) as u8,
+                }
+            })
+            .collect();
+
+        Ok(NvmeNamespace {
+            nsid,
+            block_device: None,
+            size_blocks: le64(0),
+            capacity_blocks: le64(8),
+            utilization_blocks: le64(16),
+            lba_format_index,
+            lba_formats,
+        })
+    }
+
+    pub fn lba_format(&self) -> Option<&NvmeLbaFormat> {
+        self.lba_formats.get(self.lba_format_index as usize)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.lba_format().map(|f| f.data_size as u64).unwrap_or(512)
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.size_blocks * self.block_size()
+    }
+
+    pub fn in_use_bytes(&self) -> u64 {
+        self.utilization_blocks * self.block_size()
+    }
+}
+
+/// SSTAT bits 2:0, status of the most recent sanitize.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SanitizeStatus {
+    NeverSanitized,
+    Completed,
+    InProgress,
+    Failed,
+    /// Completed successfully with deallocation inhibited (No-Deallocate).
+    CompletedNoDeallocate,
+    Reserved(u8),
+}
+
+/// Sanitize Status log (0x81), bytes 7:0.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NvmeSanitizeLog {
+    /// SPROG, fraction done out of 65536. 0xFFFF when nothing is running.
+    pub sprog: u16,
+    pub status: SanitizeStatus,
+    /// Overwrite passes completed so far (SSTAT bits 7:3).
+    pub overwrite_passes_done: u8,
+    /// SSTAT bit 8, nothing written to the NVM since the last successful sanitize.
+    pub global_data_erased: bool,
+    /// CDW10 of the sanitize the status belongs to.
+    pub scdw10: u32,
+}
+
+impl NvmeSanitizeLog {
+    pub fn parse(log: &[u8]) -> Self {
+        let sstat = u16::from_le_bytes([log[2], log[3]]);
+        NvmeSanitizeLog {
+            sprog: u16::from_le_bytes([log[0], log[1]]),
+            status: match sstat & 0x7 {
+                0 => SanitizeStatus::NeverSanitized,
+                1 => SanitizeStatus::Completed,
+                2 => SanitizeStatus::InProgress,
+                3 => SanitizeStatus::Failed,
+                4 => SanitizeStatus::CompletedNoDeallocate,
+                n => SanitizeStatus::Reserved(n as u8),
+            },
+            overwrite_passes_done: ((sstat >> 3) & 0x1F) as u8,
+            global_data_erased: sstat & (1 << 8) != 0,
+            scdw10: u32::from_le_bytes([log[4], log[5], log[6], log[7]]),
+        }
+    }
+
+    /// The action in SCDW10, None for values outside SANACT.
+    pub fn action(&self) -> Option<SanitizeAction> {
+        match self.scdw10 & 0x7 {
+            1 => Some(SanitizeAction::ExitFailureMode),
+            2 => Some(SanitizeAction::BlockErase),
+            3 => Some(SanitizeAction::Overwrite),
+            4 => Some(SanitizeAction::CryptoErase),
+            _ => None,
+        }
+    }
+}
+
+/// Read the status half of log page 0x81.
+pub fn sanitize_log(t: &mut dyn DriveTransport) -> io::Result<NvmeSanitizeLog> {
+    let mut log = vec![0u8; 512];
+    crate::wipe::get_nvme_log_page(t, NVME_LOG_SANITIZE_STATUS, &mut log)?;
+    Ok(NvmeSanitizeLog::parse(&log))
+}
+
+/// Poll the Sanitize Status log until the running sanitize is done.
+pub fn sanitize_wait(t: &mut dyn DriveTransport, timeout_secs: u64, progress: &ProgressReporter) -> io::Result<()> {
+    let start = Instant::now();
+    loop {
+        let log = sanitize_log(t)?;
+        match log.status {
+            SanitizeStatus::InProgress => progress.drive(log.sprog),
+            SanitizeStatus::Completed | SanitizeStatus::CompletedNoDeallocate => return Ok(()),
+            SanitizeStatus::Failed => return Err(io::Error::other(format!(
+                "NVMe sanitize failed (SCDW10 0x{:x}), controller is in sanitize failure mode", log.scdw10))),
+            SanitizeStatus::NeverSanitized | SanitizeStatus::Reserved(_) => return Err(io::Error::other(format!(
+                "controller reports no sanitize after one was started ({:?})", log.status))),
+        }
+        if start.elapsed() > Duration::from_secs(timeout_secs) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for NVMe sanitize"));
+        }
+        thread::sleep(SANITIZE_POLL_INTERVAL);
+    }
+}
+
+/// Estimated sanitize durations from the Sanitize Status log (bytes 19:8).
+/// None when the controller doesn't report one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeSanitizeEstimates {
+    pub overwrite_secs: Option<u32>,
+    pub block_erase_secs: Option<u32>,
+    pub crypto_erase_secs: Option<u32>,
+}
+
+impl NvmeSanitizeEstimates {
+    pub fn parse(log: &[u8]) -> Self {
+        let est = |o: usize| match u32::from_le_bytes([log[o], log[o + 1], log[o + 2], log[o + 3]]) {
+            0xFFFF_FFFF => None,
+            v => Some(v),
+        };
+        NvmeSanitizeEstimates { overwrite_secs: est(8), block_erase_secs: est(12), crypto_erase_secs: est(16) }
+    }
+}
+
+/// Read the estimates out of log page 0x81.
+pub fn sanitize_estimates(t: &mut dyn DriveTransport) -> io::Result<NvmeSanitizeEstimates> {
+    let mut log = vec![0u8; 512];
+    crate::wipe::get_nvme_log_page(t, NVME_LOG_SANITIZE_STATUS, &mut log)?;
+    Ok(NvmeSanitizeEstimates::parse(&log))
+}
+
+// SECP in bits 31:24, SPSP (the TCG ComID) in 23:8, NSSF left 0
+fn security_cdw10(protocol: u8, comid: u16) -> u32 {
+    ((protocol as u32) << 24) | ((comid as u32) << 8)
+}
+
+/// Security Receive (IF-RECV), CDW11 is the allocation length.
+pub fn security_receive(t: &mut dyn DriveTransport, protocol: u8, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_SECURITY_RECEIVE);
+    cmd.cdw10 = security_cdw10(protocol, comid);
+    cmd.cdw11 = buf.len() as u32;
+    t.nvme_admin(&cmd, buf)?;
+    Ok(())
+}
+
+/// Security Send (IF-SEND), CDW11 is the transfer length.
+pub fn security_send(t: &mut dyn DriveTransport, protocol: u8, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_SECURITY_SEND);
+    cmd.cdw10 = security_cdw10(protocol, comid);
+    cmd.cdw11 = buf.len() as u32;
+    t.nvme_admin(&cmd, buf)?;
+    Ok(())
+}
+
+/// Start a sanitize. Returns as soon as the controller accepted it, progress is in
+/// the Sanitize Status log.
+pub fn sanitize(t: &mut dyn DriveTransport, op: &NvmeSanitize) -> io::Result<()> {
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_SANITIZE);
+    cmd.cdw10 = op.cdw10();
+    if op.action == SanitizeAction::Overwrite {
+        cmd.cdw11 = op.pattern;
+    }
+    t.nvme_admin(&cmd, &mut [])?;
+    Ok(())
+}
+
+/// Take a controller out of the failure state a failed sanitize left it in. Only
+/// allowed when that sanitize ran with AUSE set, otherwise a new sanitize is needed.
+pub fn exit_failure_mode(t: &mut dyn DriveTransport) -> io::Result<()> {
+    sanitize(t, &NvmeSanitize::new(SanitizeAction::ExitFailureMode))
+}
+
+/// Format NVM. Blocks until the controller has finished, the erase included.
+pub fn format_nvm(t: &mut dyn DriveTransport, format: &NvmeFormat) -> io::Result<()> {
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_FORMAT_NVM);
+    cmd.nsid = format.nsid;
+    cmd.cdw10 = format.cdw10();
+    cmd.timeout_ms = match format.ses {
+        SecureErase::Crypto => FORMAT_CRYPTO_TIMEOUT_MS,
+        _ => FORMAT_ERASE_TIMEOUT_MS,
+    };
+    t.nvme_admin(&cmd, &mut [])?;
+    Ok(())
+}
+
+/// A controller together with every active namespace behind it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NvmeController {
+    pub ctrl_path: String,
+    pub id: NvmeIdController,
+    pub namespaces: Vec<NvmeNamespace>,
+}
+
+fn nvme_string(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw).trim().trim_matches('\0').to_string()
+}
+
+/// Identify Controller (CNS 01h).
+pub fn identify_controller(t: &mut dyn DriveTransport) -> io::Result<NvmeIdController> {
+    let mut data = vec![0u8; 4096];
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_IDENTIFY);
+    cmd.cdw10 = 1; // CNS=1 -> controller
+    t.nvme_admin(&cmd, &mut data)?;
+    NvmeIdController::parse(&data)
+}
+
+/// Identify Namespace (CNS 00h) for one namespace.
+pub fn identify_namespace(t: &mut dyn DriveTransport, nsid: u32) -> io::Result<NvmeNamespace> {
+    let mut data = vec![0u8; 4096];
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_IDENTIFY);
+    cmd.nsid = nsid;
+    cmd.cdw10 = 0; // CNS=0 -> namespace
+    t.nvme_admin(&cmd, &mut data)?;
+    NvmeNamespace::parse(nsid, &data)
+}
+
+/// Active namespace IDs (CNS 02h). Controllers older than NVMe 1.1 don't
+/// implement the list, for those every ID up to NN is tried instead.
+pub fn active_namespace_ids(t: &mut dyn DriveTransport, nn: u32) -> io::Result<Vec<u32>> {
+    let mut data = vec![0u8; 4096];
+    let mut cmd = NvmeCommand::new(NVME_ADMIN_IDENTIFY);
+    cmd.nsid = 0; // list IDs greater than this
+    cmd.cdw10 = 2; // CNS=2 -> active namespace ID list
+    match t.nvme_admin(&cmd, &mut data) {
+        Ok(_) => Ok(data
+            .chunks(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .take_while(|id| *id != 0)
+            .collect()),
+        Err(_) => Ok((1..=nn).collect()),
+    }
+}
+
+/// Identify the controller and every active namespace on it.
+pub fn scan_controller(t: &mut dyn DriveTransport) -> io::Result<NvmeController> {
+    let id = identify_controller(t)?;
+    let ctrl_path = nvme_ctrl_path(t.path());
+
+    let mut namespaces = Vec::new();
+    for nsid in active_namespace_ids(t, id.namespaces)? {
+        // an inactive ID in the fallback range fails Identify, skip it
+        let Ok(mut ns) = identify_namespace(t, nsid) else { continue };
+        if ns.size_blocks == 0 {
+            continue;
+        }
+        if ctrl_path.starts_with("/dev/nvme") {
+            ns.block_device = Some(format!("{}n{}", ctrl_path, nsid));
+        }
+        namespaces.push(ns);
+    }
+
+    Ok(NvmeController { ctrl_path, id, namespaces })
+}
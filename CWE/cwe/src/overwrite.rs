@@ -0,0 +1,249 @@
+// Software overwrite of a whole device.
+//
+// The data is a ChaCha20 keystream under a key made up for the run. The stream can
+// be entered at any offset, so worker threads fill chunks in parallel while the
+// caller's thread writes the ones that are ready. Buffers are page aligned and
+// chunks are whole logical blocks, which is what O_DIRECT wants.
+
+use std::alloc::{self, Layout};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use crate::progress::{ProgressReporter, WipePhase};
+use crate::transport::DriveTransport;
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// enough for any logical block size and the DMA alignment O_DIRECT asks for
+const BUF_ALIGN: usize = 4096;
+const MAX_WORKERS: usize = 8;
+// chunks in flight on top of one per worker, so the writer never waits on a fill
+const SPARE_BUFFERS: usize = 2;
+
+/// ChaCha20 keystream under a fixed key. Any offset can be produced on its own.
+pub struct KeyStream {
+    key: [u8; 32],
+}
+
+impl KeyStream {
+    /// Keyed from the OS generator, nobody ever learns the key.
+    pub fn random() -> Self {
+        Self::from_key(rand::rng().random())
+    }
+
+    pub fn from_key(key: [u8; 32]) -> Self {
+        KeyStream { key }
+    }
+
+    /// Keystream bytes from `offset` on. `offset` must be a multiple of 4.
+    pub fn fill(&self, offset: u64, buf: &mut [u8]) {
+        let mut rng = ChaCha20Rng::from_seed(self.key);
+        rng.set_word_pos((offset / 4) as u128);
+        rng.fill_bytes(buf);
+    }
+}
+
+// heap buffer with page alignment, Vec<u8> only guarantees byte alignment
+struct AlignedBuf {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// the buffer is owned, nothing else points into it
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, BUF_ALIGN).expect("overwrite chunk size");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+struct Chunk {
+    offset: u64,
+    len: usize,
+    buf: AlignedBuf,
+}
+
+// how the device is cut up, and how many chunks may be in flight
+#[derive(Clone, Copy)]
+struct Geometry {
+    size: u64,
+    block: usize,
+    chunk_size: usize,
+    buffers: usize,
+}
+
+/// What an overwrite did, for the evidence log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverwriteStats {
+    pub bytes: u64,
+    pub block_size: u32,
+    pub chunk_size: usize,
+    pub workers: usize,
+    pub elapsed: Duration,
+}
+
+impl OverwriteStats {
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            s if s > 0.0 => self.bytes as f64 / s,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Overwrite the whole device once with `stream` and flush the drive cache.
+/// `block_size` is the logical block size, chunks are whole blocks of it.
+pub fn overwrite(t: &mut dyn DriveTransport, block_size: u32, stream: &KeyStream, progress: &ProgressReporter) -> io::Result<OverwriteStats> {
+    let size = t.size()?;
+    let block = block_size.max(512) as usize;
+    let chunk_size = (CHUNK_SIZE / block).max(1) * block;
+    let workers = thread::available_parallelism().map_or(2, |n| n.get()).min(MAX_WORKERS);
+    let geometry = Geometry { size, block, chunk_size, buffers: workers + SPARE_BUFFERS };
+    let start = Instant::now();
+    progress.phase(t.path(), WipePhase::Overwrite, None);
+
+    let (job_tx, job_rx) = mpsc::channel::<Chunk>();
+    let job_rx = Mutex::new(job_rx);
+    let (done_tx, done_rx) = mpsc::channel::<Chunk>();
+    thread::scope(|s| {
+        for _ in 0..workers {
+            let (jobs, done) = (&job_rx, done_tx.clone());
+            s.spawn(move || fill_worker(stream, jobs, done));
+        }
+        drop(done_tx);
+        // job_tx goes away when this returns, which lets the workers finish
+        write_loop(t, &geometry, job_tx, done_rx, progress)
+    })?;
+
+    progress.phase(t.path(), WipePhase::Flush, None);
+    t.flush()?;
+    Ok(OverwriteStats { bytes: size, block_size: block as u32, chunk_size, workers, elapsed: start.elapsed() })
+}
+
+fn fill_worker(stream: &KeyStream, jobs: &Mutex<Receiver<Chunk>>, done: Sender<Chunk>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok(mut chunk) = job else { return };
+        stream.fill(chunk.offset, &mut chunk.buf[..chunk.len]);
+        if done.send(chunk).is_err() {
+            return;
+        }
+    }
+}
+
+// Hands out chunk offsets in order and writes the filled chunks as they come back,
+// not necessarily in order. The last chunk is cut to what is left of the device.
+fn write_loop(t: &mut dyn DriveTransport, g: &Geometry, jobs: Sender<Chunk>, done: Receiver<Chunk>, progress: &ProgressReporter) -> io::Result<()> {
+    let Geometry { size, block, chunk_size, buffers } = *g;
+    let mut next = 0u64;
+    let mut dispatch = |buf: AlignedBuf| -> bool {
+        if next >= size {
+            return false;
+        }
+        let len = (size - next).min(chunk_size as u64) as usize;
+        let sent = jobs.send(Chunk { offset: next, len, buf }).is_ok();
+        next += len as u64;
+        sent
+    };
+    for _ in 0..buffers {
+        if !dispatch(AlignedBuf::new(chunk_size)) {
+            break;
+        }
+    }
+
+    let mut written = 0u64;
+    while written < size {
+        let chunk = done.recv().map_err(|_| io::Error::other("overwrite workers stopped"))?;
+        let data = &chunk.buf[..chunk.len];
+        // a device that isn't a whole number of blocks gets its tail written buffered
+        if chunk.len.is_multiple_of(block) {
+            t.write_direct_at(chunk.offset, data)?;
+        } else {
+            t.write_at(chunk.offset, data)?;
+        }
+        written += chunk.len as u64;
+        progress.bytes(written, size);
+        dispatch(chunk.buf);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    const KEY: [u8; 32] = [0x5C; 32];
+
+    fn media(t: &mut MemoryTransport) -> Vec<u8> {
+        let mut buf = vec![0u8; t.size().unwrap() as usize];
+        t.read_at(0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn fill_at_an_offset_continues_the_stream() {
+        let stream = KeyStream::from_key(KEY);
+        let mut whole = vec![0u8; 8192];
+        stream.fill(0, &mut whole);
+        // offsets inside a 64 byte ChaCha block and past the first one
+        for offset in [4usize, 60, 64, 100, 4100] {
+            let mut part = vec![0u8; 1000];
+            stream.fill(offset as u64, &mut part);
+            assert_eq!(part, whole[offset..offset + 1000], "offset {}", offset);
+        }
+        let mut other = vec![0u8; 8192];
+        KeyStream::from_key([0xC5; 32]).fill(0, &mut other);
+        assert_ne!(other, whole);
+    }
+
+    #[test]
+    fn overwrite_writes_the_keystream_everywhere() {
+        // several chunks, so they come back out of order, and a tail that isn't a whole block
+        for (block, size) in [(512, 2 * CHUNK_SIZE + 3 * 512 + 100), (4096, CHUNK_SIZE + 4096 + 512)] {
+            let mut t = MemoryTransport::new("mem", size);
+            t.write_at(0, &vec![0xAA; size]).unwrap();
+            let stream = KeyStream::from_key(KEY);
+            let stats = overwrite(&mut t, block, &stream, &ProgressReporter::new()).unwrap();
+            assert_eq!(stats.bytes, size as u64);
+            assert_eq!(stats.block_size, block);
+            assert_eq!(stats.chunk_size % block as usize, 0);
+
+            let mut expected = vec![0u8; size];
+            stream.fill(0, &mut expected);
+            let got = media(&mut t);
+            if let Some(at) = got.iter().zip(&expected).position(|(a, b)| a != b) {
+                panic!("block size {}: media differs from the keystream at byte {} of {}", block, at, size);
+            }
+        }
+    }
+}
@@ -0,0 +1,706 @@
+// Partition table and filesystem signature inventory, read before anything is wiped.
+//
+// Parses MBR (with the EBR chain for logical partitions), protective MBR and both
+// GPT headers with their entry arrays, then probes the start of every partition
+// for filesystem / LUKS / LVM / RAID magic. The raw table sectors are hashed in
+// read order so the evidence pins down exactly what layout was destroyed.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io;
+use crate::transport::DriveTransport;
+
+// enough to reach the btrfs superblock at 64K
+const PROBE_BYTES: u64 = 0x11000;
+// broken EBR chains can loop
+const MAX_LOGICAL: usize = 128;
+const MAX_GPT_ENTRIES: u32 = 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TableKind {
+    None,
+    Mbr,
+    Gpt,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FsKind {
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Ntfs,
+    Fat,
+    Exfat,
+    HfsPlus,
+    Apfs,
+    Iso9660,
+    Swap,
+    Luks1,
+    Luks2,
+    BitLocker,
+    LvmPv,
+    MdRaid,
+}
+
+impl fmt::Display for FsKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            FsKind::Ext2 => "ext2",
+            FsKind::Ext3 => "ext3",
+            FsKind::Ext4 => "ext4",
+            FsKind::Xfs => "XFS",
+            FsKind::Btrfs => "Btrfs",
+            FsKind::Ntfs => "NTFS",
+            FsKind::Fat => "FAT",
+            FsKind::Exfat => "exFAT",
+            FsKind::HfsPlus => "HFS+",
+            FsKind::Apfs => "APFS",
+            FsKind::Iso9660 => "ISO 9660",
+            FsKind::Swap => "Linux swap",
+            FsKind::Luks1 => "LUKS1",
+            FsKind::Luks2 => "LUKS2",
+            FsKind::BitLocker => "BitLocker",
+            FsKind::LvmPv => "LVM PV",
+            FsKind::MdRaid => "Linux RAID member",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Partition {
+    /// 1-based, as the kernel numbers them. Logical MBR partitions start at 5.
+    pub number: u32,
+    pub start_lba: u64,
+    pub sectors: u64,
+    /// MBR type byte ("0x83") or GPT type GUID.
+    pub type_id: String,
+    /// Human name of the type when known.
+    pub type_name: Option<String>,
+    /// GPT partition GUID and name.
+    pub guid: Option<String>,
+    pub name: Option<String>,
+    pub filesystem: Option<FsKind>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PartitionInventory {
+    pub table: TableKind,
+    pub sector_size: u32,
+    pub disk_guid: Option<String>,
+    /// None when there is no GPT.
+    pub gpt_primary_valid: Option<bool>,
+    pub gpt_backup_valid: Option<bool>,
+    pub partitions: Vec<Partition>,
+    /// Filesystem directly on the disk, no table (superfloppy, whole-disk LUKS/PV).
+    pub whole_disk: Option<FsKind>,
+    /// SHA-256 of the raw MBR/EBR/GPT sectors, None without a table.
+    pub table_hash: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl PartitionInventory {
+    /// "GPT, 3 partitions: ext4, NTFS" for dialogs and the scan output.
+    pub fn summary(&self) -> String {
+        let mut found: Vec<String> = self.partitions.iter().filter_map(|p| p.filesystem).map(|f| f.to_string()).collect();
+        if let Some(fs) = self.whole_disk {
+            found.push(fs.to_string());
+        }
+        let table = match self.table {
+            TableKind::None => "no partition table".to_string(),
+            TableKind::Mbr => format!("MBR, {} partitions", self.partitions.len()),
+            TableKind::Gpt => format!("GPT, {} partitions", self.partitions.len()),
+        };
+        if found.is_empty() {
+            format!("{}, no known filesystems", table)
+        } else {
+            format!("{}: {}", table, found.join(", "))
+        }
+    }
+}
+
+// ---------- helpers ----------
+
+fn le_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn le_u64(b: &[u8]) -> u64 {
+    u64::from_le_bytes(b[..8].try_into().unwrap())
+}
+
+// the CRC32 GPT uses (IEEE, reflected)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// GUIDs are stored mixed-endian: first three fields little endian
+fn guid_string(b: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        le_u32(&b[0..4]),
+        u16::from_le_bytes([b[4], b[5]]),
+        u16::from_le_bytes([b[6], b[7]]),
+        b[8],
+        b[9],
+        b[10..16].iter().map(|x| format!("{:02X}", x)).collect::<String>()
+    )
+}
+
+fn gpt_type_name(guid: &str) -> Option<&'static str> {
+    Some(match guid {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI System",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft reserved",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows recovery",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "A19D880F-05FC-4D3B-A006-743F0F84911E" => "Linux RAID",
+        "CA7D7CCB-63ED-4C53-861C-1742536059CC" => "Linux LUKS",
+        "7C3457EF-0000-11AA-AA11-00306543ECAC" => "Apple APFS",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "Apple HFS+",
+        _ => return None,
+    })
+}
+
+fn mbr_type_name(t: u8) -> Option<&'static str> {
+    Some(match t {
+        0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E => "FAT",
+        0x05 | 0x0F | 0x85 => "Extended",
+        0x07 => "NTFS/exFAT",
+        0x27 => "Windows recovery",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xAF => "Apple HFS+",
+        0xEE => "GPT protective",
+        0xEF => "EFI System",
+        0xFD => "Linux RAID",
+        _ => return None,
+    })
+}
+
+fn is_extended(t: u8) -> bool {
+    matches!(t, 0x05 | 0x0F | 0x85)
+}
+
+// ---------- signatures ----------
+
+fn at(buf: &[u8], off: usize, magic: &[u8]) -> bool {
+    buf.get(off..off + magic.len()) == Some(magic)
+}
+
+/// Identify whatever sits at the start of `buf`, the first bytes of a partition.
+/// MD 0.90/1.0 superblocks live at the end and aren't looked for, the partition
+/// type usually gives those away.
+pub fn detect_signature(buf: &[u8]) -> Option<FsKind> {
+    if at(buf, 0, b"LUKS\xba\xbe") {
+        return Some(if buf.get(6..8) == Some(&[0, 2]) { FsKind::Luks2 } else { FsKind::Luks1 });
+    }
+    if at(buf, 3, b"-FVE-FS-") {
+        return Some(FsKind::BitLocker);
+    }
+    if at(buf, 512 + 24, b"LVM2 001") && at(buf, 512, b"LABELONE") {
+        return Some(FsKind::LvmPv);
+    }
+    // MD 1.1 at 0, 1.2 at 4K
+    let md = 0xa92b_4efcu32.to_le_bytes();
+    if at(buf, 0, &md) || at(buf, 4096, &md) {
+        return Some(FsKind::MdRaid);
+    }
+    if at(buf, 0, b"XFSB") {
+        return Some(FsKind::Xfs);
+    }
+    if at(buf, 65536 + 64, b"_BHRfS_M") {
+        return Some(FsKind::Btrfs);
+    }
+    if at(buf, 3, b"NTFS    ") {
+        return Some(FsKind::Ntfs);
+    }
+    if at(buf, 3, b"EXFAT   ") {
+        return Some(FsKind::Exfat);
+    }
+    if at(buf, 4096 - 10, b"SWAPSPACE2") || at(buf, 4096 - 10, b"SWAP-SPACE") {
+        return Some(FsKind::Swap);
+    }
+    if at(buf, 1080, &[0x53, 0xEF]) {
+        let compat = le_u32(&buf[1024 + 92..]);
+        let incompat = le_u32(&buf[1024 + 96..]);
+        // extents or 64bit or flex_bg
+        return Some(if incompat & (0x40 | 0x80 | 0x200) != 0 {
+            FsKind::Ext4
+        } else if compat & 0x4 != 0 {
+            FsKind::Ext3
+        } else {
+            FsKind::Ext2
+        });
+    }
+    if at(buf, 32, b"NXSB") {
+        return Some(FsKind::Apfs);
+    }
+    if at(buf, 1024, b"H+") || at(buf, 1024, b"HX") {
+        return Some(FsKind::HfsPlus);
+    }
+    if at(buf, 32769, b"CD001") {
+        return Some(FsKind::Iso9660);
+    }
+    // FAT last, its boot sector shape is the least specific
+    if at(buf, 510, &[0x55, 0xAA]) && (at(buf, 82, b"FAT32   ") || at(buf, 54, b"FAT1")) {
+        return Some(FsKind::Fat);
+    }
+    None
+}
+
+fn probe(t: &mut dyn DriveTransport, offset: u64, len: u64, disk_size: u64) -> io::Result<Option<FsKind>> {
+    let len = len.min(PROBE_BYTES).min(disk_size.saturating_sub(offset));
+    if len < 512 {
+        return Ok(None);
+    }
+    let mut buf = vec![0u8; len as usize];
+    t.read_at(offset, &mut buf)?;
+    Ok(detect_signature(&buf))
+}
+
+// ---------- tables ----------
+
+struct Reader<'a> {
+    t: &'a mut dyn DriveTransport,
+    sector: u64,
+    hasher: Sha256,
+}
+
+impl Reader<'_> {
+    // read sectors that belong to the table, they go into the hash
+    // LBAs come from the tables themselves, a corrupt one can overflow a byte offset
+    fn table(&mut self, lba: u64, count: u64) -> io::Result<Vec<u8>> {
+        let (Some(offset), Some(len)) = (lba.checked_mul(self.sector), count.checked_mul(self.sector)) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("table at LBA {} ({} sectors) is past any disk", lba, count)));
+        };
+        let mut buf = vec![0u8; len as usize];
+        self.t.read_at(offset, &mut buf)?;
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+}
+
+struct GptHeader {
+    valid: bool,
+    alternate_lba: u64,
+    disk_guid: String,
+    entry_count: u32,
+    entry_size: u32,
+    entries: Vec<u8>,
+}
+
+// An entry array that can't be read makes the header invalid, not the inventory:
+// the other header may still be fine.
+fn read_gpt(r: &mut Reader, lba: u64, notes: &mut Vec<String>) -> io::Result<Option<GptHeader>> {
+    let hdr = r.table(lba, 1)?;
+    if &hdr[0..8] != b"EFI PART" {
+        return Ok(None);
+    }
+    let hdr_size = (le_u32(&hdr[12..]) as usize).clamp(92, hdr.len());
+    let mut check = hdr[..hdr_size].to_vec();
+    check[16..20].fill(0);
+    let hdr_ok = crc32(&check) == le_u32(&hdr[16..]) && le_u64(&hdr[24..]) == lba;
+
+    let entry_count = le_u32(&hdr[80..]).min(MAX_GPT_ENTRIES);
+    let entry_size = le_u32(&hdr[84..]);
+    if !(128..=4096).contains(&entry_size) {
+        return Ok(Some(GptHeader { valid: false, alternate_lba: le_u64(&hdr[32..]), disk_guid: guid_string(&hdr[56..72]),
+            entry_count: 0, entry_size: 128, entries: Vec::new() }));
+    }
+    let entries_lba = le_u64(&hdr[72..]);
+    let bytes = entry_count as u64 * entry_size as u64;
+    let (entries, entries_ok) = match r.table(entries_lba, bytes.div_ceil(r.sector)) {
+        Ok(entries) => {
+            let ok = crc32(&entries[..bytes as usize]) == le_u32(&hdr[88..]);
+            (entries, ok)
+        }
+        Err(e) => {
+            notes.push(format!("GPT entries of the header at LBA {} unreadable: {}", lba, e));
+            (Vec::new(), false)
+        }
+    };
+
+    Ok(Some(GptHeader {
+        valid: hdr_ok && entries_ok,
+        alternate_lba: le_u64(&hdr[32..]),
+        disk_guid: guid_string(&hdr[56..72]),
+        entry_count: if entries.is_empty() { 0 } else { entry_count },
+        entry_size,
+        entries,
+    }))
+}
+
+fn gpt_partitions(h: &GptHeader) -> Vec<Partition> {
+    let mut out = Vec::new();
+    for i in 0..h.entry_count as usize {
+        let e = &h.entries[i * h.entry_size as usize..(i + 1) * h.entry_size as usize];
+        if e[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let type_id = guid_string(&e[0..16]);
+        let first = le_u64(&e[32..]);
+        let last = le_u64(&e[40..]);
+        let name: Vec<u16> = e[56..128].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).take_while(|c| *c != 0).collect();
+        let name = String::from_utf16_lossy(&name);
+        out.push(Partition {
+            number: i as u32 + 1,
+            start_lba: first,
+            sectors: last.saturating_sub(first).saturating_add(1),
+            type_name: gpt_type_name(&type_id).map(str::to_string),
+            type_id,
+            guid: Some(guid_string(&e[16..32])),
+            name: if name.is_empty() { None } else { Some(name) },
+            filesystem: None,
+        });
+    }
+    out
+}
+
+fn mbr_entry(number: u32, e: &[u8], base: u64) -> Partition {
+    let t = e[4];
+    Partition {
+        number,
+        start_lba: base + le_u32(&e[8..]) as u64,
+        sectors: le_u32(&e[12..]) as u64,
+        type_id: format!("0x{:02x}", t),
+        type_name: mbr_type_name(t).map(str::to_string),
+        guid: None,
+        name: None,
+        filesystem: None,
+    }
+}
+
+fn mbr_partitions(r: &mut Reader, mbr: &[u8], inv: &mut PartitionInventory) -> io::Result<()> {
+    let mut extended = None;
+    for i in 0..4 {
+        let e = &mbr[446 + i * 16..446 + (i + 1) * 16];
+        if e[4] == 0 {
+            continue;
+        }
+        if is_extended(e[4]) {
+            extended = Some(le_u32(&e[8..]) as u64);
+        }
+        inv.partitions.push(mbr_entry(i as u32 + 1, e, 0));
+    }
+
+    // logical partitions: each EBR holds one entry plus a link, both relative to the extended start
+    let Some(ext_start) = extended else { return Ok(()) };
+    let mut ebr_lba = ext_start;
+    for n in 0..MAX_LOGICAL {
+        let ebr = r.table(ebr_lba, 1)?;
+        if ebr[510..512] != [0x55, 0xAA] {
+            inv.notes.push(format!("broken EBR chain at LBA {}", ebr_lba));
+            break;
+        }
+        if ebr[446 + 4] != 0 {
+            inv.partitions.push(mbr_entry(5 + n as u32, &ebr[446..462], ebr_lba));
+        }
+        let next = &ebr[462..478];
+        if next[4] == 0 || !is_extended(next[4]) {
+            break;
+        }
+        ebr_lba = ext_start + le_u32(&next[8..]) as u64;
+    }
+    Ok(())
+}
+
+/// Read the partition table(s) and probe every partition for signatures.
+pub fn read_inventory(t: &mut dyn DriveTransport) -> io::Result<PartitionInventory> {
+    let disk_size = t.size()?;
+    let mut inv = PartitionInventory {
+        table: TableKind::None,
+        sector_size: 512,
+        disk_guid: None,
+        gpt_primary_valid: None,
+        gpt_backup_valid: None,
+        partitions: Vec::new(),
+        whole_disk: None,
+        table_hash: None,
+        notes: Vec::new(),
+    };
+
+    let mut head = vec![0u8; 8192.min(disk_size as usize)];
+    t.read_at(0, &mut head)?;
+    // GPT header sits in LBA 1, whichever the sector size is
+    if at(&head, 4096, b"EFI PART") && !at(&head, 512, b"EFI PART") {
+        inv.sector_size = 4096;
+    }
+    let has_mbr = at(&head, 510, &[0x55, 0xAA]);
+    let has_gpt = at(&head, inv.sector_size as usize, b"EFI PART");
+
+    let mut r = Reader { t: &mut *t, sector: inv.sector_size as u64, hasher: Sha256::new() };
+    let sectors = disk_size / r.sector;
+
+    if has_gpt {
+        inv.table = TableKind::Gpt;
+        if has_mbr {
+            r.table(0, 1)?; // protective MBR
+        } else {
+            inv.notes.push("GPT without a protective MBR".to_string());
+        }
+        let primary = read_gpt(&mut r, 1, &mut inv.notes)?;
+        let backup_lba = primary.as_ref().map(|p| p.alternate_lba).filter(|l| *l > 1 && *l < sectors).unwrap_or(sectors - 1);
+        let backup = read_gpt(&mut r, backup_lba, &mut inv.notes)?;
+
+        inv.gpt_primary_valid = Some(primary.as_ref().is_some_and(|h| h.valid));
+        inv.gpt_backup_valid = Some(backup.as_ref().is_some_and(|h| h.valid));
+        if backup.is_none() {
+            inv.notes.push(format!("no backup GPT header at LBA {}", backup_lba));
+        }
+        // the backup has to do when the primary is damaged
+        let use_hdr = [&primary, &backup].into_iter().flatten().find(|h| h.valid).or(primary.as_ref());
+        if let Some(h) = use_hdr {
+            inv.disk_guid = Some(h.disk_guid.clone());
+            inv.partitions = gpt_partitions(h);
+        }
+        if let (Some(p), Some(b)) = (&primary, &backup)
+            && p.valid && b.valid
+            && p.entries[..(p.entry_count * p.entry_size) as usize] != b.entries[..(b.entry_count * b.entry_size) as usize]
+        {
+            inv.notes.push("primary and backup GPT entries differ".to_string());
+        }
+    } else if has_mbr && !matches!(detect_signature(&head), Some(FsKind::Fat | FsKind::Ntfs | FsKind::Exfat)) {
+        // a FAT/NTFS boot sector also ends in 55AA, that's a superfloppy, not an MBR
+        inv.table = TableKind::Mbr;
+        let mbr = r.table(0, 1)?;
+        mbr_partitions(&mut r, &mbr, &mut inv)?;
+    }
+
+    if inv.table != TableKind::None {
+        inv.table_hash = Some(hex::encode(r.hasher.finalize()));
+    }
+
+    let sector = inv.sector_size as u64;
+    for p in inv.partitions.iter_mut() {
+        if p.type_name.as_deref() == Some("Extended") || p.type_id == "0xee" {
+            continue;
+        }
+        let (Some(offset), Some(len)) = (p.start_lba.checked_mul(sector), p.sectors.checked_mul(sector)) else {
+            inv.notes.push(format!("partition {} lies past any disk (LBA {}, {} sectors)", p.number, p.start_lba, p.sectors));
+            continue;
+        };
+        match probe(t, offset, len, disk_size) {
+            Ok(fs) => p.filesystem = fs,
+            Err(e) => inv.notes.push(format!("could not read partition {}: {}", p.number, e)),
+        }
+    }
+    if inv.table == TableKind::None {
+        inv.whole_disk = probe(t, 0, disk_size, disk_size)?;
+    }
+    Ok(inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    // 1 MiB disk of 512 byte sectors
+    const SECTORS: u64 = 2048;
+    const ENTRIES: u32 = 128;
+    const ENTRY_SECTORS: u64 = (ENTRIES as u64 * 128) / 512;
+
+    fn disk() -> MemoryTransport {
+        MemoryTransport::new("mem", (SECTORS * 512) as usize)
+    }
+
+    fn put(t: &mut MemoryTransport, lba: u64, data: &[u8]) {
+        t.write_at(lba * 512, data).unwrap();
+    }
+
+    fn mbr_entry_bytes(e: &mut [u8], kind: u8, start: u32, sectors: u32) {
+        e[4] = kind;
+        e[8..12].copy_from_slice(&start.to_le_bytes());
+        e[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    fn boot_sector(entries: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut s = vec![0u8; 512];
+        for (i, &(kind, start, sectors)) in entries.iter().enumerate() {
+            mbr_entry_bytes(&mut s[446 + i * 16..462 + i * 16], kind, start, sectors);
+        }
+        s[510..512].copy_from_slice(&[0x55, 0xAA]);
+        s
+    }
+
+    fn gpt_entries(parts: &[(u64, u64)]) -> Vec<u8> {
+        let mut e = vec![0u8; ENTRIES as usize * 128];
+        for (i, &(first, last)) in parts.iter().enumerate() {
+            let row = &mut e[i * 128..(i + 1) * 128];
+            row[0..16].fill(0x11);
+            row[16..32].fill(i as u8 + 1);
+            row[32..40].copy_from_slice(&first.to_le_bytes());
+            row[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        e
+    }
+
+    fn gpt_header(my_lba: u64, alternate: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut h = vec![0u8; 512];
+        h[0..8].copy_from_slice(b"EFI PART");
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        h[32..40].copy_from_slice(&alternate.to_le_bytes());
+        h[40..48].copy_from_slice(&(2 + ENTRY_SECTORS).to_le_bytes());
+        h[48..56].copy_from_slice(&(SECTORS - 2 - ENTRY_SECTORS).to_le_bytes());
+        h[56..72].fill(0x42);
+        h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        h[80..84].copy_from_slice(&ENTRIES.to_le_bytes());
+        h[84..88].copy_from_slice(&128u32.to_le_bytes());
+        h[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        h
+    }
+
+    // protective MBR, primary GPT at LBA 1, backup at the last LBA
+    fn gpt_disk(parts: &[(u64, u64)]) -> MemoryTransport {
+        let mut t = disk();
+        let entries = gpt_entries(parts);
+        let backup_entries = SECTORS - 1 - ENTRY_SECTORS;
+        put(&mut t, 0, &boot_sector(&[(0xEE, 1, (SECTORS - 1) as u32)]));
+        put(&mut t, 1, &gpt_header(1, SECTORS - 1, 2, &entries));
+        put(&mut t, 2, &entries);
+        put(&mut t, backup_entries, &entries);
+        put(&mut t, SECTORS - 1, &gpt_header(SECTORS - 1, 1, backup_entries, &entries));
+        t
+    }
+
+    fn spans(inv: &PartitionInventory) -> Vec<(u32, u64, u64)> {
+        inv.partitions.iter().map(|p| (p.number, p.start_lba, p.sectors)).collect()
+    }
+
+    #[test]
+    fn signatures() {
+        let mut buf = vec![0u8; PROBE_BYTES as usize];
+        assert_eq!(detect_signature(&buf), None);
+
+        buf[1080..1082].copy_from_slice(&[0x53, 0xEF]);
+        assert_eq!(detect_signature(&buf), Some(FsKind::Ext2));
+        buf[1024 + 92] = 0x4;
+        assert_eq!(detect_signature(&buf), Some(FsKind::Ext3));
+        buf[1024 + 96] = 0x40;
+        assert_eq!(detect_signature(&buf), Some(FsKind::Ext4));
+
+        let mut luks = vec![0u8; 4096];
+        luks[0..6].copy_from_slice(b"LUKS\xba\xbe");
+        luks[7] = 2;
+        assert_eq!(detect_signature(&luks), Some(FsKind::Luks2));
+
+        let mut swap = vec![0u8; 4096];
+        swap[4086..].copy_from_slice(b"SWAPSPACE2");
+        assert_eq!(detect_signature(&swap), Some(FsKind::Swap));
+
+        let mut fat = boot_sector(&[]);
+        fat[82..90].copy_from_slice(b"FAT32   ");
+        assert_eq!(detect_signature(&fat), Some(FsKind::Fat));
+
+        let mut ntfs = boot_sector(&[]);
+        ntfs[3..11].copy_from_slice(b"NTFS    ");
+        assert_eq!(detect_signature(&ntfs), Some(FsKind::Ntfs));
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut t = disk();
+        put(&mut t, 0, &boot_sector(&[(0x83, 2, 98), (0x0F, 100, 1000)]));
+        // each EBR: the logical partition relative to itself, the link relative to the extended start
+        put(&mut t, 100, &boot_sector(&[(0x83, 1, 49), (0x05, 50, 100)]));
+        put(&mut t, 150, &boot_sector(&[(0x8E, 1, 99)]));
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!(inv.table, TableKind::Mbr);
+        assert_eq!(spans(&inv), vec![(1, 2, 98), (2, 100, 1000), (5, 101, 49), (6, 151, 99)]);
+        assert_eq!(inv.partitions[3].type_name.as_deref(), Some("Linux LVM"));
+        assert!(inv.notes.is_empty());
+        assert!(inv.table_hash.is_some());
+    }
+
+    #[test]
+    fn broken_ebr_chain_is_noted() {
+        let mut t = disk();
+        put(&mut t, 0, &boot_sector(&[(0x05, 100, 1000)]));
+        put(&mut t, 100, &boot_sector(&[(0x83, 1, 49), (0x05, 50, 100)]));
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!(spans(&inv), vec![(1, 100, 1000), (5, 101, 49)]);
+        assert_eq!(inv.notes, vec!["broken EBR chain at LBA 150".to_string()]);
+    }
+
+    #[test]
+    fn gpt_with_both_headers() {
+        let mut t = gpt_disk(&[(34, 1033), (1034, 1900)]);
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!(inv.table, TableKind::Gpt);
+        assert_eq!((inv.gpt_primary_valid, inv.gpt_backup_valid), (Some(true), Some(true)));
+        assert_eq!(spans(&inv), vec![(1, 34, 1000), (2, 1034, 867)]);
+        assert_eq!(inv.disk_guid.as_deref(), Some("42424242-4242-4242-4242-424242424242"));
+        assert!(inv.notes.is_empty());
+    }
+
+    #[test]
+    fn damaged_primary_falls_back_to_the_backup() {
+        let mut t = gpt_disk(&[(34, 1033)]);
+        // a changed entry without a new entries CRC
+        let mut entries = gpt_entries(&[(34, 99)]);
+        entries[0] = 0x22;
+        put(&mut t, 2, &entries);
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!((inv.gpt_primary_valid, inv.gpt_backup_valid), (Some(false), Some(true)));
+        assert_eq!(spans(&inv), vec![(1, 34, 1000)]);
+
+        // and a header whose own CRC is off
+        let mut t = gpt_disk(&[(34, 1033)]);
+        let mut hdr = gpt_header(1, SECTORS - 1, 2, &gpt_entries(&[(34, 1033)]));
+        hdr[40] ^= 1;
+        put(&mut t, 1, &hdr);
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!((inv.gpt_primary_valid, inv.gpt_backup_valid), (Some(false), Some(true)));
+        assert_eq!(spans(&inv), vec![(1, 34, 1000)]);
+    }
+
+    #[test]
+    fn unreadable_backup_entries_keep_the_primary() {
+        let mut t = gpt_disk(&[(34, 1033)]);
+        let entries = gpt_entries(&[(34, 1033)]);
+        put(&mut t, SECTORS - 1, &gpt_header(SECTORS - 1, 1, SECTORS + 10, &entries));
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!((inv.gpt_primary_valid, inv.gpt_backup_valid), (Some(true), Some(false)));
+        assert_eq!(spans(&inv), vec![(1, 34, 1000)]);
+        assert!(inv.notes.iter().any(|n| n.starts_with(&format!("GPT entries of the header at LBA {} unreadable", SECTORS - 1))));
+    }
+
+    #[test]
+    fn overflowing_lbas_are_noted() {
+        // an entries LBA whose byte offset doesn't fit in 64 bits
+        let mut t = gpt_disk(&[(34, 1033)]);
+        let entries = gpt_entries(&[(34, 1033)]);
+        put(&mut t, 1, &gpt_header(1, SECTORS - 1, u64::MAX / 4, &entries));
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!(inv.gpt_primary_valid, Some(false));
+        assert!(inv.notes.iter().any(|n| n.contains("past any disk")));
+        assert_eq!(spans(&inv), vec![(1, 34, 1000)]);
+
+        // a partition that starts and ends past anything addressable
+        let mut t = gpt_disk(&[(1 << 62, u64::MAX)]);
+        let inv = read_inventory(&mut t).unwrap();
+        assert_eq!(spans(&inv), vec![(1, 1 << 62, u64::MAX - (1 << 62) + 1)]);
+        assert!(inv.notes.iter().any(|n| n.starts_with("partition 1 lies past any disk")));
+    }
+}
@@ -0,0 +1,68 @@
+// What a wipe is going to do, decided up front from what the device reported.
+//
+// `prepare` steps always run first and must succeed. `methods` are alternatives,
+// strongest first: the first one that completes is the one that counts.
+
+use serde::{Deserialize, Serialize};
+use crate::capability::Mechanism;
+use crate::device::{Device, DeviceType};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum PrepStep {
+    /// Remove the HPA and restore the DCO so nothing is left outside the wiped range.
+    RestoreNativeCapacity,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum WipeMethod {
+    FirmwareErase,
+    /// Format NVM with SES=2.
+    NvmeFormatCrypto,
+    /// Key erase through the TCG Opal/Enterprise Locking SP.
+    TcgCryptoErase,
+    CryptoPurge,
+    /// Format NVM with SES=1, vendor defined so only a Clear.
+    NvmeFormatUserData,
+    /// FORMAT UNIT, for SCSI disks whose sanitize failed or is missing. Only a Clear.
+    ScsiFormatUnit,
+    Overwrite,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WipePlan {
+    pub prepare: Vec<PrepStep>,
+    pub methods: Vec<WipeMethod>,
+}
+
+/// Build the plan from what `check_firmware_sanitize` found out about the device.
+pub fn plan_for(dev: &Device) -> WipePlan {
+    let mut prepare = Vec::new();
+    if dev.devtype == DeviceType::Sata
+        && dev.ata.as_ref().is_none_or(|id| id.hpa_supported || id.dco_supported)
+    {
+        prepare.push(PrepStep::RestoreNativeCapacity);
+    }
+
+    let mut methods = Vec::new();
+    if dev.capabilities.firmware_sanitize() {
+        methods.push(WipeMethod::FirmwareErase);
+    }
+    if dev.capabilities.is_available(Mechanism::NvmeFormatCrypto) {
+        methods.push(WipeMethod::NvmeFormatCrypto);
+    }
+    if dev.capabilities.is_available(Mechanism::TcgCryptoErase) {
+        methods.push(WipeMethod::TcgCryptoErase);
+    }
+    if matches!(dev.devtype, DeviceType::Nvme | DeviceType::Sata) {
+        methods.push(WipeMethod::CryptoPurge);
+    }
+    if dev.capabilities.is_available(Mechanism::NvmeFormatUserData) {
+        methods.push(WipeMethod::NvmeFormatUserData);
+    }
+    if dev.capabilities.is_available(Mechanism::ScsiFormatUnit) {
+        methods.push(WipeMethod::ScsiFormatUnit);
+    }
+    methods.push(WipeMethod::Overwrite);
+
+    WipePlan { prepare, methods }
+}
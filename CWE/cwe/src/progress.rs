@@ -0,0 +1,215 @@
+// Progress of the long running parts of a wipe.
+//
+// Sanitize and format waits, erases and the overwrite all report through the
+// `ProgressReporter` in `WipeOptions`. Anything can subscribe: a closure, an mpsc
+// channel or a tokio watch channel. With no subscribers the events go nowhere.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// byte counts come in per chunk, don't flood the subscribers with them
+const MIN_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum WipePhase {
+    /// Attached to a sanitize some earlier run started.
+    ResumedSanitize,
+    Sanitize,
+    SecurityErase,
+    Format,
+    CryptoErase,
+    Overwrite,
+    Flush,
+    Done,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProgressEvent {
+    pub device: String,
+    pub phase: WipePhase,
+    /// 0.0 to 1.0, None while nothing says how far along the phase is.
+    pub fraction: Option<f64>,
+    pub bytes_done: Option<u64>,
+    pub bytes_total: Option<u64>,
+    /// Bytes per second since the phase started.
+    pub throughput: Option<f64>,
+    pub eta_secs: Option<u64>,
+    /// Raw progress out of 65536 as the drive reports it (NVMe SPROG, ATA/SCSI progress indicator).
+    pub drive_progress: Option<u16>,
+    pub elapsed_secs: u64,
+}
+
+impl fmt::Display for ProgressEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.phase)?;
+        if let Some(fraction) = self.fraction {
+            write!(f, " {:.1}%", fraction * 100.0)?;
+        }
+        if let (Some(done), Some(total)) = (self.bytes_done, self.bytes_total) {
+            write!(f, " {}/{} MiB", done >> 20, total >> 20)?;
+        }
+        if let Some(bps) = self.throughput {
+            write!(f, " {:.1} MiB/s", bps / (1024.0 * 1024.0))?;
+        }
+        if let Some(eta) = self.eta_secs {
+            write!(f, " ETA {}:{:02}:{:02}", eta / 3600, eta / 60 % 60, eta % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Something that wants progress events. Called on the wiping thread, keep it short.
+pub trait ProgressSink: Send + Sync {
+    fn progress(&self, event: &ProgressEvent);
+}
+
+impl<F: Fn(&ProgressEvent) + Send + Sync> ProgressSink for F {
+    fn progress(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+// a receiver that went away just stops getting events
+impl ProgressSink for mpsc::Sender<ProgressEvent> {
+    fn progress(&self, event: &ProgressEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Watchers only ever see the latest event.
+impl ProgressSink for tokio::sync::watch::Sender<Option<ProgressEvent>> {
+    fn progress(&self, event: &ProgressEvent) {
+        self.send_replace(Some(event.clone()));
+    }
+}
+
+struct PhaseClock {
+    device: String,
+    phase: WipePhase,
+    started: Instant,
+    last_emit: Option<Instant>,
+}
+
+/// Fans progress out to its subscribers. Clones share the subscribers and the
+/// current phase.
+#[derive(Clone, Default)]
+pub struct ProgressReporter {
+    sinks: Vec<Arc<dyn ProgressSink>>,
+    clock: Arc<Mutex<Option<PhaseClock>>>,
+}
+
+impl fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressReporter").field("subscribers", &self.sinks.len()).finish()
+    }
+}
+
+impl ProgressReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, sink: impl ProgressSink + 'static) {
+        self.sinks.push(Arc::new(sink));
+    }
+
+    /// A watch channel subscribed to this reporter, for async consumers.
+    pub fn watch(&mut self) -> tokio::sync::watch::Receiver<Option<ProgressEvent>> {
+        let (tx, rx) = tokio::sync::watch::channel(None);
+        self.subscribe(tx);
+        rx
+    }
+
+    /// Start a phase. `estimate_secs` is what the drive or the caller expects it to
+    /// take, for phases that can't be polled.
+    pub fn phase(&self, device: &str, phase: WipePhase, estimate_secs: Option<u64>) {
+        let now = Instant::now();
+        *self.clock.lock().unwrap() = Some(PhaseClock { device: device.to_string(), phase, started: now, last_emit: Some(now) });
+        let mut event = self.event(device, phase, Duration::ZERO);
+        event.eta_secs = estimate_secs;
+        if phase == WipePhase::Done {
+            event.fraction = Some(1.0);
+        }
+        self.emit(&event);
+    }
+
+    /// Progress out of 65536 as the drive reports it.
+    pub fn drive(&self, progress: u16) {
+        let fraction = progress as f64 / 65536.0;
+        if let Some(mut event) = self.tick(false) {
+            event.fraction = Some(fraction);
+            event.drive_progress = Some(progress);
+            event.eta_secs = eta(event.elapsed_secs as f64, fraction);
+            self.emit(&event);
+        }
+    }
+
+    /// Bytes written so far out of `total`. Rate limited, the last chunk always gets through.
+    pub fn bytes(&self, done: u64, total: u64) {
+        if let Some(mut event) = self.tick(done < total) {
+            let fraction = if total == 0 { 1.0 } else { done as f64 / total as f64 };
+            let elapsed = self.elapsed().as_secs_f64();
+            event.fraction = Some(fraction);
+            event.bytes_done = Some(done);
+            event.bytes_total = Some(total);
+            event.throughput = (elapsed > 0.0).then(|| done as f64 / elapsed);
+            event.eta_secs = eta(elapsed, fraction);
+            self.emit(&event);
+        }
+    }
+
+    /// Wipe finished with the last phase started.
+    pub fn done(&self) {
+        let device = match &*self.clock.lock().unwrap() {
+            Some(clock) => clock.device.clone(),
+            None => return,
+        };
+        self.phase(&device, WipePhase::Done, None);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.lock().unwrap().as_ref().map(|c| c.started.elapsed()).unwrap_or_default()
+    }
+
+    // None when the event is rate limited away or no phase has started
+    fn tick(&self, limit: bool) -> Option<ProgressEvent> {
+        if self.sinks.is_empty() {
+            return None;
+        }
+        let mut clock = self.clock.lock().unwrap();
+        let clock = clock.as_mut()?;
+        let now = Instant::now();
+        if limit && clock.last_emit.is_some_and(|t| now - t < MIN_INTERVAL) {
+            return None;
+        }
+        clock.last_emit = Some(now);
+        Some(self.event(&clock.device, clock.phase, now - clock.started))
+    }
+
+    fn event(&self, device: &str, phase: WipePhase, elapsed: Duration) -> ProgressEvent {
+        ProgressEvent {
+            device: device.to_string(),
+            phase,
+            fraction: None,
+            bytes_done: None,
+            bytes_total: None,
+            throughput: None,
+            eta_secs: None,
+            drive_progress: None,
+            elapsed_secs: elapsed.as_secs(),
+        }
+    }
+
+    fn emit(&self, event: &ProgressEvent) {
+        for sink in &self.sinks {
+            sink.progress(event);
+        }
+    }
+}
+
+fn eta(elapsed_secs: f64, fraction: f64) -> Option<u64> {
+    (fraction > 0.0 && elapsed_secs > 0.0).then(|| (elapsed_secs * (1.0 - fraction) / fraction) as u64)
+}
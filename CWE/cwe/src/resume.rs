@@ -0,0 +1,120 @@
+// Sanitize operations outlive the process that started them.
+//
+// NVMe, ATA and SCSI drives carry on with a sanitize after the host crashes and
+// resume it after a power cycle, so before anything else touches a drive it is asked
+// what it is doing: NVMe through the Sanitize Status log (0x81), ATA through SANITIZE
+// STATUS EXT, SCSI through REQUEST SENSE. A sanitize that is still running is
+// attached to and waited for, never started a second time.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use crate::ata;
+use crate::device::DeviceType;
+use crate::nvme::{self, SanitizeStatus};
+use crate::progress::ProgressReporter;
+use crate::scsi;
+use crate::transport::DriveTransport;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SanitizePhase {
+    NeverRun,
+    InProgress,
+    Completed,
+    Failed,
+    /// The drive doesn't remember its last sanitize (SCSI when idle, ATA before any ran).
+    Unknown,
+}
+
+/// What the drive says about its current or most recent sanitize.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SanitizeReport {
+    pub phase: SanitizePhase,
+    /// Fraction done out of 65536 while in progress.
+    pub progress: Option<u16>,
+    /// Drive specific detail, the NVMe action, ATA flags or SCSI sense.
+    pub detail: String,
+}
+
+impl SanitizeReport {
+    pub fn in_progress(&self) -> bool {
+        self.phase == SanitizePhase::InProgress
+    }
+
+    pub fn summary(&self) -> String {
+        let mut s = match self.phase {
+            SanitizePhase::NeverRun => "never sanitized".to_string(),
+            SanitizePhase::InProgress => format!("sanitize in progress, {:.1}% done",
+                self.progress.unwrap_or(0) as f64 * 100.0 / 65536.0),
+            SanitizePhase::Completed => "last sanitize completed".to_string(),
+            SanitizePhase::Failed => "last sanitize FAILED".to_string(),
+            SanitizePhase::Unknown => "no sanitize running".to_string(),
+        };
+        if !self.detail.is_empty() {
+            s.push_str(&format!(" ({})", self.detail));
+        }
+        s
+    }
+}
+
+/// Ask the drive about its sanitize state. None for device types that can't say.
+pub fn query(t: &mut dyn DriveTransport, devtype: &DeviceType) -> io::Result<Option<SanitizeReport>> {
+    match devtype {
+        DeviceType::Nvme => {
+            let log = nvme::sanitize_log(t)?;
+            let phase = match log.status {
+                SanitizeStatus::NeverSanitized => SanitizePhase::NeverRun,
+                SanitizeStatus::InProgress => SanitizePhase::InProgress,
+                SanitizeStatus::Completed | SanitizeStatus::CompletedNoDeallocate => SanitizePhase::Completed,
+                SanitizeStatus::Failed => SanitizePhase::Failed,
+                SanitizeStatus::Reserved(_) => SanitizePhase::Unknown,
+            };
+            let detail = match (phase, log.action()) {
+                (SanitizePhase::NeverRun, _) | (_, None) => String::new(),
+                (_, Some(action)) => format!("{:?}", action),
+            };
+            let progress = (phase == SanitizePhase::InProgress).then_some(log.sprog);
+            Ok(Some(SanitizeReport { phase, progress, detail }))
+        }
+        DeviceType::Sata => {
+            let status = ata::sanitize_status(t, false)?;
+            let phase = if status.failed {
+                SanitizePhase::Failed
+            } else if status.in_progress {
+                SanitizePhase::InProgress
+            } else if status.completed {
+                SanitizePhase::Completed
+            } else {
+                SanitizePhase::Unknown
+            };
+            let detail = match (status.frozen, status.antifreeze_locked) {
+                (true, _) => "sanitize frozen",
+                (_, true) => "antifreeze locked",
+                _ => "",
+            };
+            let progress = status.in_progress.then_some(status.progress);
+            Ok(Some(SanitizeReport { phase, progress, detail: detail.to_string() }))
+        }
+        DeviceType::Scsi => {
+            let sense = scsi::request_sense(t)?;
+            let report = if sense.key == 0x2 && sense.asc == 0x04 && sense.ascq == 0x1B {
+                SanitizeReport { phase: SanitizePhase::InProgress, progress: Some(sense.progress.unwrap_or(0)), detail: String::new() }
+            } else if sense.asc == 0x31 && sense.ascq == 0x03 {
+                SanitizeReport { phase: SanitizePhase::Failed, progress: None, detail: sense.to_string() }
+            } else {
+                SanitizeReport { phase: SanitizePhase::Unknown, progress: None, detail: String::new() }
+            };
+            Ok(Some(report))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Wait for a sanitize the drive is already running. Errors if it ends in failure.
+pub fn attach(t: &mut dyn DriveTransport, devtype: &DeviceType, timeout_secs: u64, progress: &ProgressReporter) -> io::Result<()> {
+    match devtype {
+        DeviceType::Nvme => nvme::sanitize_wait(t, timeout_secs, progress),
+        DeviceType::Sata => ata::sanitize_wait(t, timeout_secs, progress),
+        DeviceType::Scsi => scsi::wait_for_completion(t, timeout_secs, progress),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, "device type has no sanitize to attach to")),
+    }
+}
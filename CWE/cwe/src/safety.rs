@@ -0,0 +1,240 @@
+// Preflight checks before anything destructive touches a disk.
+//
+// A disk is refused when any of its partitions (or the disk itself) is mounted,
+// used as swap, held by another block device (MD array, LVM PV, dm-crypt, ...),
+// or when it backs /, /boot or /boot/efi of the running system. Stacked devices
+// are followed down through `slaves` so root on LVM on LUKS still points at the
+// physical disk.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use crate::ata::AtaSanitizeCommand;
+use crate::nvme::NvmeSanitize;
+use crate::progress::ProgressReporter;
+
+const SYSTEM_MOUNTS: [&str; 3] = ["/", "/boot", "/boot/efi"];
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum SafetyIssue {
+    Mounted { device: String, mountpoint: String },
+    Swap { device: String },
+    /// `holder` is the stacked device on top, dm-0, md127 and so on.
+    Held { device: String, holder: String },
+    /// The disk backs a mount the running system needs.
+    SystemDisk { mountpoint: String },
+}
+
+impl fmt::Display for SafetyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafetyIssue::Mounted { device, mountpoint } => write!(f, "{} is mounted on {}", device, mountpoint),
+            SafetyIssue::Swap { device } => write!(f, "{} is in use as swap", device),
+            SafetyIssue::Held { device, holder } => write!(f, "{} is held by {} (RAID/LVM/dm-crypt member)", device, holder),
+            SafetyIssue::SystemDisk { mountpoint } => write!(f, "disk holds {} of the running system", mountpoint),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SafetyReport {
+    pub device: String,
+    pub issues: Vec<SafetyIssue>,
+}
+
+impl SafetyReport {
+    pub fn is_clear(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Knobs for destructive operations.
+#[derive(Debug, Clone, Default)]
+pub struct WipeOptions {
+    /// Wipe even when the preflight finds the disk in use. Never set this by default.
+    pub force: bool,
+    /// Action and options for an NVMe firmware sanitize. None takes the strongest
+    /// action in SANICAP with `NvmeSanitize::new` defaults.
+    pub nvme_sanitize: Option<NvmeSanitize>,
+    /// Same for an ATA SANITIZE, None takes the strongest sub-command in IDENTIFY.
+    pub ata_sanitize: Option<AtaSanitizeCommand>,
+    /// LBA format index an NVMe Format NVM switches to, None keeps the one in use.
+    pub nvme_lba_format: Option<u8>,
+    /// Where progress of the long running steps goes, nowhere by default.
+    pub progress: ProgressReporter,
+}
+
+/// Kernel name of a device path, following /dev/disk/by-id style symlinks.
+fn kernel_name(dev_path: &str) -> Option<String> {
+    let p = fs::canonicalize(dev_path).unwrap_or_else(|_| PathBuf::from(dev_path));
+    p.file_name().map(|n| n.to_string_lossy().into_owned())
+}
+
+fn dir_names(path: impl AsRef<Path>) -> Vec<String> {
+    match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|e| e.file_name().to_string_lossy().into_owned()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The whole disks a block device ends up on: a partition maps to its disk,
+/// dm/md devices to the disks under their slaves.
+pub fn backing_disks(name: &str) -> BTreeSet<String> {
+    let mut out = BTreeSet::new();
+    let Ok(sys) = fs::canonicalize(format!("/sys/class/block/{}", name)) else { return out };
+
+    let slaves = dir_names(sys.join("slaves"));
+    if !slaves.is_empty() {
+        for s in slaves {
+            out.extend(backing_disks(&s));
+        }
+    } else if sys.join("partition").exists() {
+        if let Some(disk) = sys.parent().and_then(|p| p.file_name()) {
+            out.insert(disk.to_string_lossy().into_owned());
+        }
+    } else {
+        out.insert(name.to_string());
+    }
+    out
+}
+
+// Kernel name of the disk `dev_path` is on, looked up by device number so partitions
+// and renamed nodes resolve too. None when the path is no block device at all.
+fn whole_disk(dev_path: &str) -> io::Result<Option<String>> {
+    let meta = match fs::metadata(dev_path) {
+        Ok(m) if m.file_type().is_block_device() => m,
+        _ => return Ok(None),
+    };
+    let link = format!("/sys/dev/block/{}:{}", libc::major(meta.rdev()), libc::minor(meta.rdev()));
+    let sys = fs::canonicalize(&link)
+        .map_err(|e| io::Error::new(e.kind(), format!("no sysfs entry for {} at {}: {}", dev_path, link, e)))?;
+    // a partition's directory sits inside its disk's
+    let disk = if sys.join("partition").exists() { sys.parent().unwrap_or(&sys) } else { &sys };
+    Ok(disk.file_name().map(|n| n.to_string_lossy().into_owned()))
+}
+
+// (kernel name, mount point) for every mounted block device. Btrfs and friends
+// report an anonymous 0:N dev, fall back to the mount source for those.
+fn mounts() -> Vec<(String, String)> {
+    let Ok(text) = fs::read_to_string("/proc/self/mountinfo") else { return Vec::new() };
+    let mut out = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 5 {
+            continue;
+        }
+        let mountpoint = unescape(fields[4]);
+        let from_dev = fs::canonicalize(format!("/sys/dev/block/{}", fields[2]))
+            .ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+        let name = from_dev.or_else(|| {
+            let sep = fields.iter().position(|f| *f == "-")?;
+            let source = fields.get(sep + 2)?;
+            if source.starts_with("/dev/") { kernel_name(source) } else { None }
+        });
+        if let Some(name) = name {
+            out.push((name, mountpoint));
+        }
+    }
+    out
+}
+
+// mountinfo escapes space, tab, newline and backslash as \ooo
+fn unescape(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'\\' && i + 3 < b.len() && b[i + 1..i + 4].iter().all(|c| (b'0'..=b'7').contains(c)) {
+            out.push((b[i + 1] - b'0') * 64 + (b[i + 2] - b'0') * 8 + (b[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn swaps() -> Vec<String> {
+    let Ok(text) = fs::read_to_string("/proc/swaps") else { return Vec::new() };
+    text.lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+        .filter(|p| p.starts_with("/dev/"))
+        .filter_map(kernel_name)
+        .collect()
+}
+
+/// Inspect mounts, swap, holders and the system disks for `dev_path`. A partition
+/// is checked as its whole disk, a firmware erase takes all of it anyway.
+pub fn preflight(dev_path: &str) -> io::Result<SafetyReport> {
+    let mut report = SafetyReport { device: dev_path.to_string(), issues: Vec::new() };
+    // emulator backing files and in-memory targets, nothing to protect
+    let Some(disk) = whole_disk(dev_path)? else { return Ok(report) };
+    let sys = PathBuf::from("/sys/block").join(&disk);
+
+    // NVMe sanitize and format act on the whole controller, every namespace on it counts
+    let mut disks = vec![disk.clone()];
+    if disk.starts_with("nvme") {
+        for name in dir_names(sys.join("device")) {
+            if name != disk && name.starts_with("nvme") && Path::new("/sys/block").join(&name).exists() {
+                disks.push(name);
+            }
+        }
+    }
+
+    let hits = |name: &str| backing_disks(name).iter().any(|d| disks.contains(d));
+
+    for (name, mountpoint) in mounts() {
+        if hits(&name) {
+            if SYSTEM_MOUNTS.contains(&mountpoint.as_str()) {
+                report.issues.push(SafetyIssue::SystemDisk { mountpoint: mountpoint.clone() });
+            }
+            report.issues.push(SafetyIssue::Mounted { device: format!("/dev/{}", name), mountpoint });
+        }
+    }
+
+    for name in swaps() {
+        if hits(&name) {
+            report.issues.push(SafetyIssue::Swap { device: format!("/dev/{}", name) });
+        }
+    }
+
+    // the disks and their partitions
+    for d in &disks {
+        let dir = Path::new("/sys/block").join(d);
+        let mut members = vec![dir.clone()];
+        for name in dir_names(&dir) {
+            if name.starts_with(d.as_str()) && dir.join(&name).join("partition").exists() {
+                members.push(dir.join(name));
+            }
+        }
+        for m in members {
+            let dev = m.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            for holder in dir_names(m.join("holders")) {
+                report.issues.push(SafetyIssue::Held { device: format!("/dev/{}", dev), holder });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Run the preflight and refuse unless it is clear or `opts.force` is set. With
+/// `force` the report comes back with the issues that were overridden.
+pub fn enforce(dev_path: &str, opts: &WipeOptions) -> io::Result<SafetyReport> {
+    let report = preflight(dev_path)?;
+    if report.is_clear() || opts.force {
+        return Ok(report);
+    }
+    let reasons: Vec<String> = report.issues.iter().map(|i| i.to_string()).collect();
+    Err(io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        format!("refusing to wipe {}: {}", dev_path, reasons.join("; ")),
+    ))
+}
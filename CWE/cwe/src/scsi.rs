@@ -0,0 +1,313 @@
+// SCSI/SAS helpers: INQUIRY + VPD, READ CAPACITY(16), SANITIZE, FORMAT UNIT and
+// REQUEST SENSE progress polling (SPC-4 / SBC-4).
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::progress::ProgressReporter;
+use crate::sgio::SenseData;
+use crate::transport::{DataDir, DriveTransport};
+
+pub const SCSI_FORMAT_UNIT: u8 = 0x04;
+pub const SCSI_REQUEST_SENSE: u8 = 0x03;
+pub const SCSI_INQUIRY: u8 = 0x12;
+pub const SCSI_SANITIZE: u8 = 0x48;
+pub const SCSI_READ_CAPACITY_16: u8 = 0x9E;
+pub const SCSI_REPORT_SUPPORTED_OPCODES: u8 = 0xA3;
+pub const SCSI_SECURITY_PROTOCOL_IN: u8 = 0xA2;
+pub const SCSI_SECURITY_PROTOCOL_OUT: u8 = 0xB5;
+
+const SHORT_TIMEOUT_SECS: u32 = 30;
+
+/// SANITIZE service actions.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ScsiSanitizeAction {
+    Overwrite = 0x01,
+    BlockErase = 0x02,
+    CryptoErase = 0x03,
+    ExitFailureMode = 0x1F,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ScsiInquiry {
+    pub peripheral_type: u8,
+    pub vendor: String,
+    pub product: String,
+    pub revision: String,
+    /// Unit Serial Number VPD page (80h)
+    #[serde(skip_serializing, default)]
+    pub serial: Option<String>,
+    /// NAA designator from the Device Identification VPD page (83h)
+    #[serde(skip_serializing, default)]
+    pub wwn: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ScsiCapacity {
+    pub last_lba: u64,
+    pub block_size: u32,
+    pub protection_enabled: bool,
+    /// Logical block provisioning (thin provisioning / UNMAP) enabled
+    pub lbpme: bool,
+}
+
+impl ScsiCapacity {
+    pub fn bytes(&self) -> u64 {
+        (self.last_lba + 1) * self.block_size as u64
+    }
+}
+
+/// Which SANITIZE service actions the device reports through REPORT SUPPORTED
+/// OPERATION CODES. `reported` is false when the device doesn't implement that
+/// command, the actions are then unknown and have to be tried.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ScsiSanitizeSupport {
+    pub reported: bool,
+    pub overwrite: bool,
+    pub block_erase: bool,
+    pub crypto_erase: bool,
+}
+
+impl ScsiSanitizeSupport {
+    pub fn any(&self) -> bool {
+        self.overwrite || self.block_erase || self.crypto_erase
+    }
+
+    /// Actions worth trying, strongest first.
+    pub fn candidates(&self) -> Vec<ScsiSanitizeAction> {
+        let all = [ScsiSanitizeAction::CryptoErase, ScsiSanitizeAction::BlockErase, ScsiSanitizeAction::Overwrite];
+        if !self.reported {
+            return all.to_vec();
+        }
+        all.into_iter()
+            .filter(|a| match a {
+                ScsiSanitizeAction::CryptoErase => self.crypto_erase,
+                ScsiSanitizeAction::BlockErase => self.block_erase,
+                ScsiSanitizeAction::Overwrite => self.overwrite,
+                ScsiSanitizeAction::ExitFailureMode => false,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ScsiInfo {
+    pub inquiry: ScsiInquiry,
+    pub capacity: Option<ScsiCapacity>,
+    pub sanitize: ScsiSanitizeSupport,
+}
+
+// GOOD status or an error carrying the sense data
+fn run(t: &mut dyn DriveTransport, cdb: &[u8], dir: DataDir, data: &mut [u8], timeout_secs: u32) -> io::Result<()> {
+    match t.scsi_command(cdb, dir, data, timeout_secs)? {
+        None => Ok(()),
+        // RECOVERED ERROR means the command completed
+        Some(sense) if sense.key == 0x1 => Ok(()),
+        Some(sense) => Err(io::Error::other(format!("SCSI opcode 0x{:02x} failed: {}", cdb[0], sense))),
+    }
+}
+
+fn scsi_string(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw).trim().trim_matches('\0').to_string()
+}
+
+fn inquiry_page(t: &mut dyn DriveTransport, page: Option<u8>, buf: &mut [u8]) -> io::Result<()> {
+    let len = buf.len() as u16;
+    let mut cdb = [0u8; 6];
+    cdb[0] = SCSI_INQUIRY;
+    if let Some(p) = page {
+        cdb[1] = 0x01; // EVPD
+        cdb[2] = p;
+    }
+    cdb[3..5].copy_from_slice(&len.to_be_bytes());
+    run(t, &cdb, DataDir::In, buf, SHORT_TIMEOUT_SECS)
+}
+
+/// Standard INQUIRY plus the serial number and WWN VPD pages when available.
+pub fn inquiry(t: &mut dyn DriveTransport) -> io::Result<ScsiInquiry> {
+    let mut std_data = [0u8; 96];
+    inquiry_page(t, None, &mut std_data)?;
+
+    let mut inq = ScsiInquiry {
+        peripheral_type: std_data[0] & 0x1F,
+        vendor: scsi_string(&std_data[8..16]),
+        product: scsi_string(&std_data[16..32]),
+        revision: scsi_string(&std_data[32..36]),
+        serial: None,
+        wwn: None,
+    };
+
+    let mut vpd80 = [0u8; 255];
+    if inquiry_page(t, Some(0x80), &mut vpd80).is_ok() && vpd80[1] == 0x80 {
+        let len = (u16::from_be_bytes([vpd80[2], vpd80[3]]) as usize).min(vpd80.len() - 4);
+        let serial = scsi_string(&vpd80[4..4 + len]);
+        if !serial.is_empty() {
+            inq.serial = Some(serial);
+        }
+    }
+
+    let mut vpd83 = [0u8; 512];
+    if inquiry_page(t, Some(0x83), &mut vpd83).is_ok() && vpd83[1] == 0x83 {
+        let end = (4 + u16::from_be_bytes([vpd83[2], vpd83[3]]) as usize).min(vpd83.len());
+        let mut off = 4;
+        while off + 4 <= end {
+            let association = (vpd83[off + 1] >> 4) & 0x3;
+            let designator_type = vpd83[off + 1] & 0x0F;
+            let len = vpd83[off + 3] as usize;
+            let body = &vpd83[off + 4..(off + 4 + len).min(end)];
+            // NAA designator for the logical unit itself
+            if designator_type == 0x3 && association == 0 && !body.is_empty() {
+                inq.wwn = Some(format!("0x{}", hex::encode(body)));
+                break;
+            }
+            off += 4 + len;
+        }
+    }
+
+    Ok(inq)
+}
+
+pub fn read_capacity_16(t: &mut dyn DriveTransport) -> io::Result<ScsiCapacity> {
+    let mut buf = [0u8; 32];
+    let mut cdb = [0u8; 16];
+    cdb[0] = SCSI_READ_CAPACITY_16;
+    cdb[1] = 0x10; // service action READ CAPACITY(16)
+    cdb[10..14].copy_from_slice(&(buf.len() as u32).to_be_bytes());
+    run(t, &cdb, DataDir::In, &mut buf, SHORT_TIMEOUT_SECS)?;
+
+    Ok(ScsiCapacity {
+        last_lba: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+        block_size: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        protection_enabled: buf[12] & 0x01 != 0,
+        lbpme: buf[14] & 0x80 != 0,
+    })
+}
+
+// REPORT SUPPORTED OPERATION CODES, one command with service action
+fn service_action_supported(t: &mut dyn DriveTransport, opcode: u8, sa: u16) -> io::Result<bool> {
+    let mut buf = [0u8; 64];
+    let mut cdb = [0u8; 12];
+    cdb[0] = SCSI_REPORT_SUPPORTED_OPCODES;
+    cdb[1] = 0x0C; // service action
+    cdb[2] = 0x02; // reporting options: opcode + service action
+    cdb[3] = opcode;
+    cdb[4..6].copy_from_slice(&sa.to_be_bytes());
+    cdb[6..10].copy_from_slice(&(buf.len() as u32).to_be_bytes());
+    run(t, &cdb, DataDir::In, &mut buf, SHORT_TIMEOUT_SECS)?;
+    // SUPPORT field: 011b supported per standard, 101b supported vendor specific
+    Ok(matches!(buf[1] & 0x07, 0x3 | 0x5))
+}
+
+pub fn sanitize_support(t: &mut dyn DriveTransport) -> ScsiSanitizeSupport {
+    let mut s = ScsiSanitizeSupport::default();
+    let probe = |t: &mut dyn DriveTransport, a: ScsiSanitizeAction| service_action_supported(t, SCSI_SANITIZE, a as u16);
+    match probe(t, ScsiSanitizeAction::Overwrite) {
+        Ok(ow) => {
+            s.reported = true;
+            s.overwrite = ow;
+            s.block_erase = probe(t, ScsiSanitizeAction::BlockErase).unwrap_or(false);
+            s.crypto_erase = probe(t, ScsiSanitizeAction::CryptoErase).unwrap_or(false);
+        }
+        Err(_) => s.reported = false,
+    }
+    s
+}
+
+/// Gather INQUIRY, capacity and sanitize support in one go.
+pub fn probe(t: &mut dyn DriveTransport) -> io::Result<ScsiInfo> {
+    let inquiry = inquiry(t)?;
+    let capacity = read_capacity_16(t).ok();
+    let sanitize = sanitize_support(t);
+    Ok(ScsiInfo { inquiry, capacity, sanitize })
+}
+
+/// Start a SANITIZE with IMMED set, poll with `wait_for_completion`. For overwrite
+/// a single pass of `pattern` is written.
+pub fn sanitize(t: &mut dyn DriveTransport, action: ScsiSanitizeAction, pattern: &[u8]) -> io::Result<()> {
+    let mut cdb = [0u8; 10];
+    cdb[0] = SCSI_SANITIZE;
+    cdb[1] = 0x80 | action as u8; // IMMED
+
+    if action == ScsiSanitizeAction::Overwrite {
+        // parameter list: byte 0 = INVERT/TEST/OVERWRITE COUNT, bytes 2-3 pattern length
+        let pattern: &[u8] = if pattern.is_empty() { &[0u8; 4] } else { pattern };
+        let mut params = vec![0u8; 4 + pattern.len()];
+        params[0] = 0x01; // one pass
+        params[2..4].copy_from_slice(&(pattern.len() as u16).to_be_bytes());
+        params[4..].copy_from_slice(pattern);
+        cdb[7..9].copy_from_slice(&(params.len() as u16).to_be_bytes());
+        return run(t, &cdb, DataDir::Out, &mut params, SHORT_TIMEOUT_SECS);
+    }
+
+    run(t, &cdb, DataDir::None, &mut [], SHORT_TIMEOUT_SECS)
+}
+
+/// FORMAT UNIT with IMMED set and no defect list, poll with `wait_for_completion`.
+pub fn format_unit(t: &mut dyn DriveTransport) -> io::Result<()> {
+    // short parameter list header: FOV | IMMED
+    let mut header = [0x00, 0x82, 0x00, 0x00];
+    let cdb = [SCSI_FORMAT_UNIT, 0x10 /* FMTDATA */, 0, 0, 0, 0];
+    run(t, &cdb, DataDir::Out, &mut header, SHORT_TIMEOUT_SECS)
+}
+
+// SECURITY PROTOCOL IN/OUT(12): protocol, protocol specific field (the TCG ComID),
+// INC_512 left clear so the length is in bytes
+fn security_cdb(opcode: u8, protocol: u8, comid: u16, len: usize) -> [u8; 12] {
+    let mut cdb = [0u8; 12];
+    cdb[0] = opcode;
+    cdb[1] = protocol;
+    cdb[2..4].copy_from_slice(&comid.to_be_bytes());
+    cdb[6..10].copy_from_slice(&(len as u32).to_be_bytes());
+    cdb
+}
+
+/// SECURITY PROTOCOL IN (IF-RECV).
+pub fn security_protocol_in(t: &mut dyn DriveTransport, protocol: u8, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    let cdb = security_cdb(SCSI_SECURITY_PROTOCOL_IN, protocol, comid, buf.len());
+    run(t, &cdb, DataDir::In, buf, SHORT_TIMEOUT_SECS)
+}
+
+/// SECURITY PROTOCOL OUT (IF-SEND).
+pub fn security_protocol_out(t: &mut dyn DriveTransport, protocol: u8, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    let cdb = security_cdb(SCSI_SECURITY_PROTOCOL_OUT, protocol, comid, buf.len());
+    run(t, &cdb, DataDir::Out, buf, SHORT_TIMEOUT_SECS)
+}
+
+pub fn request_sense(t: &mut dyn DriveTransport) -> io::Result<SenseData> {
+    let mut buf = [0u8; 252];
+    let cdb = [SCSI_REQUEST_SENSE, 0x01 /* DESC */, 0, 0, buf.len() as u8, 0];
+    // REQUEST SENSE returns the sense as data with GOOD status
+    t.scsi_command(&cdb, DataDir::In, &mut buf, SHORT_TIMEOUT_SECS)?;
+    SenseData::parse(&buf).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no sense data returned"))
+}
+
+/// Progress of a background SANITIZE/FORMAT as 0..=65535, None once the device is ready.
+pub fn operation_progress(t: &mut dyn DriveTransport) -> io::Result<Option<u16>> {
+    let sense = request_sense(t)?;
+    // NOT READY, LOGICAL UNIT NOT READY: 04/04 format in progress, 04/1B sanitize in progress
+    if sense.key == 0x2 && sense.asc == 0x04 && matches!(sense.ascq, 0x04 | 0x1B) {
+        return Ok(Some(sense.progress.unwrap_or(0)));
+    }
+    // 31/03 SANITIZE COMMAND FAILED, 31/00 MEDIUM FORMAT CORRUPTED
+    if sense.asc == 0x31 {
+        return Err(io::Error::other(format!("sanitize/format failed: {}", sense)));
+    }
+    Ok(None)
+}
+
+/// Poll REQUEST SENSE until the running SANITIZE/FORMAT UNIT finishes.
+pub fn wait_for_completion(t: &mut dyn DriveTransport, timeout_secs: u64, progress: &ProgressReporter) -> io::Result<()> {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(timeout_secs);
+    loop {
+        match operation_progress(t)? {
+            None => return Ok(()),
+            Some(p) => progress.drive(p),
+        }
+        if start.elapsed() > timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for SCSI sanitize/format"));
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+}
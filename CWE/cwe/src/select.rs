@@ -0,0 +1,167 @@
+// Non-interactive device selection for scripts and the ISO automation.
+//
+// Selector syntax:
+//   /dev/sda, /dev/disk/by-id/...      device path, symlinks are resolved
+//   by-id:ata-Samsung_SSD_860_...      name under /dev/disk/by-id
+//   wwn:0x5002538e40a1b2c3             WWN / NGUID / EUI, prefixes and case ignored
+//   id:3f2a9c...                       keyed serial hash (Device::id), unique prefix is enough
+//   model:Samsung*[,serial:S3Z*]       model and/or serial glob (* and ?)
+//   serial:S3Z*
+
+use std::str::FromStr;
+use thiserror::Error;
+use crate::device::{enumerate_block_devices_linux, Device};
+use crate::identity::OrgSecret;
+
+// short id prefixes match too much by accident
+const MIN_ID_PREFIX: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    Path(String),
+    ById(String),
+    Wwn(String),
+    Id(String),
+    ModelSerial { model: Option<String>, serial: Option<String> },
+}
+
+#[derive(Debug, Error)]
+pub enum SelectError {
+    #[error("invalid selector {0:?}: {1}")]
+    Invalid(String, &'static str),
+    #[error("no device matches {0}")]
+    NotFound(String),
+    #[error("{selector} matches {} devices: {}", .matches.len(), .matches.join(", "))]
+    Ambiguous { selector: String, matches: Vec<String> },
+    #[error("could not enumerate devices: {0}")]
+    Enumerate(String),
+}
+
+impl FromStr for Selector {
+    type Err = SelectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = |why| SelectError::Invalid(s.to_string(), why);
+        if s.starts_with('/') {
+            return Ok(Selector::Path(s.to_string()));
+        }
+        let Some((kind, value)) = s.split_once(':') else {
+            return Err(invalid("expected a /dev path or one of by-id:, wwn:, id:, model:, serial:"));
+        };
+        if value.is_empty() {
+            return Err(invalid("empty value"));
+        }
+        match kind {
+            "by-id" => Ok(Selector::ById(value.to_string())),
+            "wwn" => Ok(Selector::Wwn(value.to_string())),
+            "id" if value.len() < MIN_ID_PREFIX => Err(invalid("id prefix must be at least 8 characters")),
+            "id" => Ok(Selector::Id(value.to_ascii_lowercase())),
+            "serial" => Ok(Selector::ModelSerial { model: None, serial: Some(value.to_string()) }),
+            "model" => match value.split_once(",serial:") {
+                Some((model, serial)) => Ok(Selector::ModelSerial { model: Some(model.to_string()), serial: Some(serial.to_string()) }),
+                None => Ok(Selector::ModelSerial { model: Some(value.to_string()), serial: None }),
+            },
+            _ => Err(invalid("unknown selector kind")),
+        }
+    }
+}
+
+/// `*` and `?` wildcards, everything else literal.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            // let the last * swallow one more character
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+// 0x5002538e..., naa.5002538e..., eui.0025..., wwn-0x5002... all compare equal
+fn normalize_wwn(s: &str) -> String {
+    let s = s.trim().to_ascii_lowercase();
+    let s = s.strip_prefix("wwn-").unwrap_or(&s);
+    let s = s.strip_prefix("naa.").or_else(|| s.strip_prefix("eui.")).unwrap_or(s);
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    s.to_string()
+}
+
+impl Selector {
+    fn matches(&self, dev: &Device) -> bool {
+        match self {
+            Selector::Path(p) => {
+                let resolved = std::fs::canonicalize(p).map(|p| p.to_string_lossy().into_owned());
+                dev.dev_path == *p || resolved.is_ok_and(|r| r == dev.dev_path)
+            }
+            Selector::ById(name) => {
+                let name = name.trim_start_matches("/dev/disk/by-id/");
+                dev.by_id.iter().any(|l| l.rsplit('/').next() == Some(name))
+            }
+            Selector::Wwn(w) => {
+                let want = normalize_wwn(w);
+                dev.wwn.as_deref().is_some_and(|have| normalize_wwn(have) == want)
+                    || dev.by_id.iter().any(|l| l.rsplit('/').next().is_some_and(|n| n.starts_with("wwn-") && normalize_wwn(n) == want))
+            }
+            Selector::Id(prefix) => dev.id.starts_with(prefix.as_str()),
+            Selector::ModelSerial { model, serial } => {
+                let field = |pat: &Option<String>, val: &Option<String>| match pat {
+                    None => true,
+                    Some(pat) => glob_match(pat, val.as_deref().unwrap_or("").trim()),
+                };
+                field(model, &dev.model) && field(serial, &dev.serial)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::Path(p) => write!(f, "{}", p),
+            Selector::ById(n) => write!(f, "by-id:{}", n),
+            Selector::Wwn(w) => write!(f, "wwn:{}", w),
+            Selector::Id(i) => write!(f, "id:{}", i),
+            Selector::ModelSerial { model, serial } => match (model, serial) {
+                (Some(m), Some(s)) => write!(f, "model:{},serial:{}", m, s),
+                (Some(m), None) => write!(f, "model:{}", m),
+                (None, Some(s)) => write!(f, "serial:{}", s),
+                (None, None) => write!(f, "model:*"),
+            },
+        }
+    }
+}
+
+/// Pick exactly one device out of `devices`.
+pub fn select<'a>(devices: &'a [Device], sel: &Selector) -> Result<&'a Device, SelectError> {
+    let found: Vec<&Device> = devices.iter().filter(|d| sel.matches(d)).collect();
+    match found.len() {
+        0 => Err(SelectError::NotFound(sel.to_string())),
+        1 => Ok(found[0]),
+        _ => Err(SelectError::Ambiguous {
+            selector: sel.to_string(),
+            matches: found.iter().map(|d| d.dev_path.clone()).collect(),
+        }),
+    }
+}
+
+/// Parse `spec`, enumerate the system and return the single matching device.
+pub fn select_device(spec: &str, secret: &OrgSecret) -> Result<Device, SelectError> {
+    let sel: Selector = spec.parse()?;
+    let devices = enumerate_block_devices_linux(secret).map_err(|e| SelectError::Enumerate(e.to_string()))?;
+    select(&devices, &sel).cloned()
+}
@@ -0,0 +1,351 @@
+// SG_IO plumbing and the SAT ATA PASS-THROUGH(12/16) commands.
+//
+// HDIO_DRIVE_CMD can only do data-in and is not implemented by USB-SATA bridges.
+// Going through the SCSI generic layer with SAT CDBs works for libata and for
+// every bridge that implements SAT, and carries proper data-out.
+
+use std::fmt;
+use std::io;
+use libc::{c_int, c_uint, c_void, ioctl};
+use crate::transport::{AtaTaskfile, DataDir};
+
+pub const SG_IO: u64 = 0x2285;
+
+const SG_DXFER_NONE: c_int = -1;
+const SG_DXFER_TO_DEV: c_int = -2;
+const SG_DXFER_FROM_DEV: c_int = -3;
+
+const SG_INFO_OK_MASK: c_uint = 0x1;
+
+pub const ATA_PASS_THROUGH_12: u8 = 0xA1;
+pub const ATA_PASS_THROUGH_16: u8 = 0x85;
+
+/// Mirror of the kernel's `struct sg_io_hdr` (scsi/sg.h).
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct sg_io_hdr {
+    pub interface_id: c_int,
+    pub dxfer_direction: c_int,
+    pub cmd_len: u8,
+    pub mx_sb_len: u8,
+    pub iovec_count: u16,
+    pub dxfer_len: c_uint,
+    pub dxferp: *mut c_void,
+    pub cmdp: *const u8,
+    pub sbp: *mut u8,
+    pub timeout: c_uint,
+    pub flags: c_uint,
+    pub pack_id: c_int,
+    pub usr_ptr: *mut c_void,
+    pub status: u8,
+    pub masked_status: u8,
+    pub msg_status: u8,
+    pub sb_len_wr: u8,
+    pub host_status: u16,
+    pub driver_status: u16,
+    pub resid: c_int,
+    pub duration: c_uint,
+    pub info: c_uint,
+}
+
+/// Outcome of an SG_IO call that reached the device.
+#[derive(Debug, Clone)]
+pub struct SgResult {
+    pub status: u8,
+    pub host_status: u16,
+    pub driver_status: u16,
+    pub resid: i32,
+    pub sense: Vec<u8>,
+}
+
+impl SgResult {
+    pub fn ok(&self) -> bool {
+        self.status == 0 && self.host_status == 0 && (self.driver_status & 0x0F) == 0
+    }
+
+    pub fn sense(&self) -> Option<SenseData> {
+        SenseData::parse(&self.sense)
+    }
+}
+
+/// Issue one CDB through SG_IO. Transport level failures (no such device, bad ioctl)
+/// are returned as `Err`, SCSI/ATA level failures are left to the caller via `SgResult`.
+pub fn sg_io(fd: i32, cdb: &[u8], dir: DataDir, data: &mut [u8], timeout_ms: u32) -> io::Result<SgResult> {
+    let mut sense = [0u8; 64];
+    let mut hdr = sg_io_hdr {
+        interface_id: 'S' as c_int,
+        dxfer_direction: match dir {
+            DataDir::None => SG_DXFER_NONE,
+            DataDir::In => SG_DXFER_FROM_DEV,
+            DataDir::Out => SG_DXFER_TO_DEV,
+        },
+        cmd_len: cdb.len() as u8,
+        mx_sb_len: sense.len() as u8,
+        iovec_count: 0,
+        dxfer_len: if dir == DataDir::None { 0 } else { data.len() as c_uint },
+        dxferp: if dir == DataDir::None { std::ptr::null_mut() } else { data.as_mut_ptr() as *mut c_void },
+        cmdp: cdb.as_ptr(),
+        sbp: sense.as_mut_ptr(),
+        timeout: timeout_ms,
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null_mut(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+
+    let ret = unsafe { ioctl(fd, SG_IO as _, &mut hdr as *mut sg_io_hdr) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = SgResult {
+        status: hdr.status,
+        host_status: hdr.host_status,
+        driver_status: hdr.driver_status,
+        resid: hdr.resid,
+        sense: sense[..hdr.sb_len_wr as usize].to_vec(),
+    };
+    if hdr.info & SG_INFO_OK_MASK == 0 && result.host_status != 0 {
+        return Err(io::Error::other(format!("SG_IO host error 0x{:x}", result.host_status)));
+    }
+    Ok(result)
+}
+
+// ---------- sense data ----------
+
+/// ATA Status Return sense data descriptor (descriptor code 09h).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AtaStatusReturn {
+    pub error: u8,
+    pub count: u16,
+    pub lba: u64,
+    pub device: u8,
+    pub status: u8,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SenseData {
+    pub response_code: u8,
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+    /// Command-specific information / progress indication when present.
+    pub progress: Option<u16>,
+    pub ata: Option<AtaStatusReturn>,
+}
+
+impl SenseData {
+    /// Decode fixed (70h/71h) or descriptor (72h/73h) format sense data.
+    pub fn parse(sb: &[u8]) -> Option<Self> {
+        if sb.is_empty() {
+            return None;
+        }
+        let response_code = sb[0] & 0x7F;
+        match response_code {
+            0x70 | 0x71 if sb.len() >= 14 => {
+                let mut s = SenseData {
+                    response_code,
+                    key: sb[2] & 0x0F,
+                    asc: sb[12],
+                    ascq: sb[13],
+                    ..Default::default()
+                };
+                // sense key specific bytes 15..17, SKSV set + NOT READY = progress indication
+                if sb.len() >= 18 && sb[15] & 0x80 != 0 && s.key == 0x02 {
+                    s.progress = Some(u16::from_be_bytes([sb[16], sb[17]]));
+                }
+                // SAT fixed format: ATA registers in the information fields
+                if s.asc == 0x00 && s.ascq == 0x1D {
+                    s.ata = Some(AtaStatusReturn {
+                        error: sb[3],
+                        status: sb[4],
+                        device: sb[5],
+                        count: sb[6] as u16,
+                        lba: (sb[9] as u64) | ((sb[10] as u64) << 8) | ((sb[11] as u64) << 16),
+                    });
+                }
+                Some(s)
+            }
+            0x72 | 0x73 if sb.len() >= 8 => {
+                let mut s = SenseData {
+                    response_code,
+                    key: sb[1] & 0x0F,
+                    asc: sb[2],
+                    ascq: sb[3],
+                    ..Default::default()
+                };
+                let total = (8 + sb[7] as usize).min(sb.len());
+                let mut off = 8;
+                while off + 2 <= total {
+                    let code = sb[off];
+                    let len = sb[off + 1] as usize;
+                    let d = &sb[off..(off + 2 + len).min(total)];
+                    match code {
+                        // sense key specific descriptor, progress indication
+                        0x02 if d.len() >= 7 && d[4] & 0x80 != 0 => {
+                            s.progress = Some(u16::from_be_bytes([d[5], d[6]]));
+                        }
+                        0x09 if d.len() >= 14 => {
+                            let extend = d[2] & 0x01 != 0;
+                            let (count, lba) = if extend {
+                                (
+                                    u16::from_be_bytes([d[4], d[5]]),
+                                    (d[7] as u64) | ((d[9] as u64) << 8) | ((d[11] as u64) << 16)
+                                        | ((d[6] as u64) << 24) | ((d[8] as u64) << 32) | ((d[10] as u64) << 40),
+                                )
+                            } else {
+                                (d[5] as u16, (d[7] as u64) | ((d[9] as u64) << 8) | ((d[11] as u64) << 16))
+                            };
+                            s.ata = Some(AtaStatusReturn { error: d[3], count, lba, device: d[12], status: d[13] });
+                        }
+                        // progress indication descriptor
+                        0x0A if d.len() >= 8 => {
+                            s.progress = Some(u16::from_be_bytes([d[6], d[7]]));
+                        }
+                        _ => {}
+                    }
+                    off += 2 + len;
+                }
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn key_name(&self) -> &'static str {
+        match self.key {
+            0x0 => "NO SENSE",
+            0x1 => "RECOVERED ERROR",
+            0x2 => "NOT READY",
+            0x3 => "MEDIUM ERROR",
+            0x4 => "HARDWARE ERROR",
+            0x5 => "ILLEGAL REQUEST",
+            0x6 => "UNIT ATTENTION",
+            0x7 => "DATA PROTECT",
+            0xB => "ABORTED COMMAND",
+            _ => "OTHER",
+        }
+    }
+
+    /// ASC/ASCQ 20h/00h, the device doesn't know the opcode
+    pub fn invalid_opcode(&self) -> bool {
+        self.key == 0x5 && self.asc == 0x20 && self.ascq == 0x00
+    }
+}
+
+impl fmt::Display for SenseData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sense key {} (0x{:x}), asc/ascq 0x{:02x}/0x{:02x}", self.key_name(), self.key, self.asc, self.ascq)?;
+        if let Some(a) = &self.ata {
+            write!(f, ", ata status 0x{:02x} error 0x{:02x}", a.status, a.error)?;
+        }
+        Ok(())
+    }
+}
+
+// ---------- ATA PASS-THROUGH ----------
+
+// 48-bit commands that need the EXTEND bit
+fn is_ext_command(cmd: u8) -> bool {
+    matches!(cmd, 0x24 | 0x25 | 0x27 | 0x29 | 0x2F | 0x34 | 0x35 | 0x37 | 0x39 | 0x3F | 0x42 | 0x57 | 0xB4 | 0xEA)
+}
+
+fn protocol(tf: &AtaTaskfile) -> u8 {
+    match tf.dir {
+        DataDir::None => 3, // non-data
+        DataDir::In => 4,   // PIO data-in
+        DataDir::Out => 5,  // PIO data-out
+    }
+}
+
+// byte 2: CK_COND always, so the registers come back in the sense data
+fn flags(tf: &AtaTaskfile) -> u8 {
+    let mut b = 1 << 5; // CK_COND
+    if tf.dir != DataDir::None {
+        b |= 1 << 2; // BYT_BLOK: length in blocks
+        b |= 0x2; // T_LENGTH: length is in the COUNT field
+        if tf.dir == DataDir::In {
+            b |= 1 << 3; // T_DIR: from device
+        }
+    }
+    b
+}
+
+pub fn ata_pass_through_16(tf: &AtaTaskfile) -> [u8; 16] {
+    let ext = is_ext_command(tf.command);
+    let mut cdb = [0u8; 16];
+    cdb[0] = ATA_PASS_THROUGH_16;
+    cdb[1] = (protocol(tf) << 1) | ext as u8;
+    cdb[2] = flags(tf);
+    cdb[3] = (tf.features >> 8) as u8;
+    cdb[4] = tf.features as u8;
+    cdb[5] = (tf.count >> 8) as u8;
+    cdb[6] = tf.count as u8;
+    if ext {
+        cdb[7] = (tf.lba >> 24) as u8;
+        cdb[9] = (tf.lba >> 32) as u8;
+        cdb[11] = (tf.lba >> 40) as u8;
+    }
+    cdb[8] = tf.lba as u8;
+    cdb[10] = (tf.lba >> 8) as u8;
+    cdb[12] = (tf.lba >> 16) as u8;
+    cdb[13] = if ext { tf.device } else { tf.device | ((tf.lba >> 24) as u8 & 0x0F) };
+    cdb[14] = tf.command;
+    cdb
+}
+
+pub fn ata_pass_through_12(tf: &AtaTaskfile) -> [u8; 12] {
+    let mut cdb = [0u8; 12];
+    cdb[0] = ATA_PASS_THROUGH_12;
+    cdb[1] = protocol(tf) << 1;
+    cdb[2] = flags(tf);
+    cdb[3] = tf.features as u8;
+    cdb[4] = tf.count as u8;
+    cdb[5] = tf.lba as u8;
+    cdb[6] = (tf.lba >> 8) as u8;
+    cdb[7] = (tf.lba >> 16) as u8;
+    cdb[8] = tf.device | ((tf.lba >> 24) as u8 & 0x0F);
+    cdb[9] = tf.command;
+    cdb
+}
+
+/// Run an ATA command through SAT. Uses the 16 byte CDB and falls back to the 12 byte
+/// one for bridges that reject it (only when the command fits in 28-bit registers).
+pub fn ata_command(fd: i32, tf: &mut AtaTaskfile, data: &mut [u8]) -> io::Result<()> {
+    let timeout_ms = tf.timeout_secs.saturating_mul(1000);
+
+    let mut res = sg_io(fd, &ata_pass_through_16(tf), tf.dir, data, timeout_ms)?;
+    let fits_12 = !is_ext_command(tf.command) && tf.lba <= 0x0FFF_FFFF;
+    if fits_12 && res.sense().is_some_and(|s| s.invalid_opcode()) {
+        res = sg_io(fd, &ata_pass_through_12(tf), tf.dir, data, timeout_ms)?;
+    }
+
+    let sense = res.sense();
+    if let Some(ata) = sense.as_ref().and_then(|s| s.ata) {
+        tf.status = ata.status;
+        tf.error = ata.error;
+        tf.count = ata.count;
+        tf.lba = ata.lba;
+        tf.device = ata.device;
+    }
+
+    match &sense {
+        // with CK_COND a successful command reports RECOVERED ERROR / ATA PASS-THROUGH INFORMATION AVAILABLE
+        Some(s) if s.key == 0x1 && s.asc == 0x00 && s.ascq == 0x1D && !tf.failed() => Ok(()),
+        Some(s) if s.key == 0x0 && !tf.failed() => Ok(()),
+        None if res.ok() => Ok(()),
+        Some(s) => Err(io::Error::other(format!("ATA command 0x{:02x} failed: {}", tf.command, s))),
+        None => Err(io::Error::other(format!(
+            "ATA command 0x{:02x} failed: scsi status 0x{:x} driver status 0x{:x}",
+            tf.command, res.status, res.driver_status
+        ))),
+    }
+}
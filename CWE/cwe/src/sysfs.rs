@@ -0,0 +1,141 @@
+// Block device attributes from /sys/block and /dev/disk/by-id.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bus the kernel sees the disk on, taken from the sysfs device path.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum BusType {
+    Ata,
+    Usb,
+    Nvme,
+    Scsi,
+    Virtio,
+    Mmc,
+    Xen,
+    #[default]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SysfsBlock {
+    pub name: String,
+    pub size_bytes: u64,
+    pub logical_block_size: u32,
+    pub physical_block_size: u32,
+    pub rotational: bool,
+    pub removable: bool,
+    pub bus: BusType,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+    pub wwn: Option<String>,
+    /// Resolved /sys/devices path, used to tell virtual devices apart.
+    pub sys_path: PathBuf,
+}
+
+impl SysfsBlock {
+    /// loop, ram, zram, dm-*, md* and friends live under /sys/devices/virtual and
+    /// have no backing `device` link. Wiping them only hits another layer.
+    pub fn is_virtual(&self) -> bool {
+        self.sys_path.starts_with("/sys/devices/virtual") || !self.sys_path.join("device").exists()
+    }
+}
+
+fn attr(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn attr_u64(path: impl AsRef<Path>) -> Option<u64> {
+    attr(path).and_then(|s| s.parse().ok())
+}
+
+// the first match wins: usb before scsi (usb-storage is a scsi host) and ata before scsi (libata too)
+fn bus_from_path(sys_path: &Path) -> BusType {
+    let p = sys_path.to_string_lossy();
+    if p.contains("/usb") {
+        BusType::Usb
+    } else if p.contains("/nvme") {
+        BusType::Nvme
+    } else if p.contains("/mmc_host/") {
+        BusType::Mmc
+    } else if p.contains("/virtio") {
+        BusType::Virtio
+    } else if p.contains("/vbd-") || p.contains("/xen") {
+        BusType::Xen
+    } else if p.contains("/ata") {
+        BusType::Ata
+    } else if p.contains("/host") && p.contains("/target") {
+        BusType::Scsi
+    } else {
+        BusType::Unknown
+    }
+}
+
+/// Read everything we want to know about /sys/block/<name>.
+pub fn read_block(name: &str) -> SysfsBlock {
+    let base = PathBuf::from("/sys/block").join(name);
+    let dev = base.join("device");
+    let sys_path = fs::canonicalize(&base).unwrap_or_else(|_| base.clone());
+
+    // size is always in 512 byte units regardless of the logical block size
+    let size_bytes = attr_u64(base.join("size")).unwrap_or(0) * 512;
+
+    // nvme exposes firmware_rev/wwid on the controller, scsi/ata rev and wwid on the device
+    let firmware = attr(dev.join("firmware_rev")).or_else(|| attr(dev.join("rev")));
+    let wwn = attr(base.join("wwid")).or_else(|| attr(dev.join("wwid")));
+
+    SysfsBlock {
+        name: name.to_string(),
+        size_bytes,
+        logical_block_size: attr_u64(base.join("queue/logical_block_size")).unwrap_or(512) as u32,
+        physical_block_size: attr_u64(base.join("queue/physical_block_size")).unwrap_or(512) as u32,
+        rotational: attr_u64(base.join("queue/rotational")) == Some(1),
+        removable: attr_u64(base.join("removable")) == Some(1),
+        bus: bus_from_path(&sys_path),
+        model: attr(dev.join("model")),
+        vendor: attr(dev.join("vendor")),
+        serial: attr(dev.join("serial")),
+        firmware,
+        wwn,
+        sys_path,
+    }
+}
+
+/// Largest discard the queue accepts, 0 when the device can't discard at all.
+pub fn discard_max_bytes(dev_path: &str) -> u64 {
+    let name = dev_path.rsplit('/').next().unwrap_or(dev_path);
+    attr_u64(PathBuf::from("/sys/block").join(name).join("queue/discard_max_bytes")).unwrap_or(0)
+}
+
+/// Map of kernel name (sda, nvme0n1) to the /dev/disk/by-id links pointing at it.
+pub fn by_id_aliases() -> HashMap<String, Vec<String>> {
+    let mut map: HashMap<String, Vec<String>> = HashMap::new();
+    let Ok(entries) = fs::read_dir("/dev/disk/by-id") else { return map };
+    for entry in entries.flatten() {
+        let link = entry.path();
+        let Ok(target) = fs::canonicalize(&link) else { continue };
+        if let Some(name) = target.file_name() {
+            map.entry(name.to_string_lossy().into_owned())
+                .or_default()
+                .push(link.to_string_lossy().into_owned());
+        }
+    }
+    for links in map.values_mut() {
+        links.sort();
+    }
+    map
+}
+
+/// Ask the SCSI layer to re-read the capacity, after an HPA/DCO change the kernel
+/// still has the old size until this is done.
+pub fn rescan(dev_path: &str) -> std::io::Result<()> {
+    let name = dev_path.rsplit('/').next().unwrap_or(dev_path);
+    fs::write(PathBuf::from("/sys/block").join(name).join("device/rescan"), "1")
+}
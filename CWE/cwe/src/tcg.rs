@@ -0,0 +1,925 @@
+// TCG Storage self-encrypting drives: Opal, Opalite, Pyrite, Ruby and Enterprise.
+//
+// Level 0 discovery tells which security subsystem class (SSC) the drive speaks
+// and the global locking state. Everything else runs in sessions: method calls
+// packed into ComPackets, sent with IF-SEND and picked up with IF-RECV on the
+// drive's base ComID. Wire format per the TCG Storage Architecture Core Spec 2.01,
+// UIDs per the Opal 2.01 and Enterprise 1.01 SSCs.
+//
+// IF-SEND / IF-RECV are ATA TRUSTED SEND/RECEIVE, NVMe Security Send/Receive or
+// SCSI SECURITY PROTOCOL OUT/IN depending on the device type.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::ata;
+use crate::device::DeviceType;
+use crate::nvme;
+use crate::scsi;
+use crate::transport::DriveTransport;
+
+pub const SECURITY_PROTOCOL_TCG: u8 = 0x01;
+pub(crate) const LEVEL0_COMID: u16 = 0x0001;
+const IO_BUFFER: usize = 2048;
+// a revert or GenKey can keep the TPer busy for a while before it answers
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const HOST_SESSION_ID: u32 = 1;
+
+// UIDs
+pub(crate) const SMUID: u64 = 0x0000_0000_0000_00FF;
+pub(crate) const THIS_SP: u64 = 0x0000_0000_0000_0001;
+pub(crate) const ADMIN_SP: u64 = 0x0000_0205_0000_0001;
+pub(crate) const LOCKING_SP: u64 = 0x0000_0205_0000_0002;
+pub(crate) const ENTERPRISE_LOCKING_SP: u64 = 0x0000_0205_0001_0001;
+pub(crate) const SID: u64 = 0x0000_0009_0000_0006;
+pub(crate) const PSID: u64 = 0x0000_0009_0001_FF01;
+pub(crate) const ADMIN1: u64 = 0x0000_0009_0001_0001;
+pub(crate) const ERASE_MASTER: u64 = 0x0000_0009_0000_8401;
+pub(crate) const C_PIN_MSID: u64 = 0x0000_000B_0000_8402;
+pub(crate) const LOCKING_INFO: u64 = 0x0000_0801_0000_0001;
+pub(crate) const LOCKING_GLOBAL_RANGE: u64 = 0x0000_0802_0000_0001;
+
+// methods
+pub(crate) const START_SESSION: u64 = 0x0000_0000_0000_FF02;
+pub(crate) const SYNC_SESSION: u64 = 0x0000_0000_0000_FF03;
+pub(crate) const GET: u64 = 0x0000_0006_0000_0016;
+pub(crate) const ENTERPRISE_GET: u64 = 0x0000_0006_0000_0006;
+pub(crate) const GEN_KEY: u64 = 0x0000_0006_0000_0010;
+pub(crate) const REVERT_SP: u64 = 0x0000_0006_0000_0011;
+pub(crate) const REVERT: u64 = 0x0000_0006_0000_0202;
+pub(crate) const ACTIVATE: u64 = 0x0000_0006_0000_0203;
+pub(crate) const ERASE: u64 = 0x0000_0006_0000_0803;
+
+// control tokens
+pub(crate) const START_LIST: u8 = 0xF0;
+pub(crate) const END_LIST: u8 = 0xF1;
+pub(crate) const START_NAME: u8 = 0xF2;
+pub(crate) const END_NAME: u8 = 0xF3;
+pub(crate) const CALL: u8 = 0xF8;
+pub(crate) const END_OF_DATA: u8 = 0xF9;
+pub(crate) const END_OF_SESSION: u8 = 0xFA;
+pub(crate) const EMPTY: u8 = 0xFF;
+
+/// A table column, Opal addresses it by number and Enterprise by name.
+#[derive(Debug, Clone, Copy)]
+struct Column(u64, &'static str);
+
+const PIN: Column = Column(3, "PIN");
+const MAX_RANGES: Column = Column(4, "MaxRanges");
+const RANGE_START: Column = Column(3, "RangeStart");
+const RANGE_LENGTH: Column = Column(4, "RangeLength");
+const READ_LOCK_ENABLED: Column = Column(5, "ReadLockEnabled");
+const WRITE_LOCK_ENABLED: Column = Column(6, "WriteLockEnabled");
+const READ_LOCKED: Column = Column(7, "ReadLocked");
+const WRITE_LOCKED: Column = Column(8, "WriteLocked");
+const ACTIVE_KEY: Column = Column(10, "ActiveKey");
+
+/// Security subsystem class from the Level 0 feature descriptors.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Ssc {
+    Enterprise,
+    OpalV1,
+    OpalV2,
+    Opalite,
+    PyriteV1,
+    PyriteV2,
+    Ruby,
+}
+
+impl fmt::Display for Ssc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Ssc::Enterprise => "Enterprise",
+            Ssc::OpalV1 => "Opal 1.0",
+            Ssc::OpalV2 => "Opal 2.0",
+            Ssc::Opalite => "Opalite",
+            Ssc::PyriteV1 => "Pyrite 1.0",
+            Ssc::PyriteV2 => "Pyrite 2.0",
+            Ssc::Ruby => "Ruby",
+        };
+        f.write_str(s)
+    }
+}
+
+/// One row of the Locking table. Range 0 is the global range (Band0 on Enterprise).
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct LockingRange {
+    pub number: u32,
+    pub start_lba: u64,
+    pub length_lba: u64,
+    pub read_lock_enabled: bool,
+    pub write_lock_enabled: bool,
+    pub read_locked: bool,
+    pub write_locked: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SedStatus {
+    /// None when the drive has a TPer but no SSC we know.
+    pub ssc: Option<Ssc>,
+    pub base_comid: u16,
+    pub num_comids: u16,
+    pub locking_supported: bool,
+    /// Locking SP activated. Always set on Enterprise.
+    pub locking_enabled: bool,
+    /// Some range is locked right now.
+    pub locked: bool,
+    pub media_encryption: bool,
+    pub mbr_enabled: bool,
+    pub mbr_done: bool,
+    /// Block SID feature: SID authentication refused until the next power cycle.
+    pub sid_blocked: bool,
+    /// Block SID feature: C_PIN_SID still holds the MSID. None if not reported.
+    pub sid_is_msid: Option<bool>,
+    /// Opal 2 feature descriptor: Locking SP Admin and User authorities.
+    pub admin_authorities: Option<u16>,
+    pub user_authorities: Option<u16>,
+    /// Locking table rows, only readable inside an Admin1 / EraseMaster session.
+    pub ranges: Vec<LockingRange>,
+    /// Set in the evidence once a PSID revert put the whole TPer back to factory state.
+    #[serde(default)]
+    pub psid_reverted: bool,
+}
+
+impl SedStatus {
+    /// Decode a Level 0 discovery response: a 48 byte header followed by
+    /// feature descriptors (code, version, length, data).
+    pub fn parse_level0(d: &[u8]) -> io::Result<Self> {
+        if d.len() < 48 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Level 0 discovery data shorter than 48 bytes"));
+        }
+        let be16 = |b: &[u8], o: usize| u16::from_be_bytes([b[o], b[o + 1]]);
+        // the length field doesn't count itself
+        let total = (u32::from_be_bytes([d[0], d[1], d[2], d[3]]) as usize + 4).min(d.len());
+        if total <= 48 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Level 0 discovery returned no features"));
+        }
+
+        let mut status = SedStatus::default();
+        let mut tper = false;
+        let mut off = 48;
+        while off + 4 <= total {
+            let code = be16(d, off);
+            let len = d[off + 3] as usize;
+            let data = &d[off + 4..(off + 4 + len).min(total)];
+            off += 4 + len;
+            if data.is_empty() {
+                continue;
+            }
+
+            let ssc = match code {
+                0x0001 => {
+                    tper = true;
+                    None
+                }
+                0x0002 => {
+                    let f = data[0];
+                    status.locking_supported = f & 0x01 != 0;
+                    status.locking_enabled = f & 0x02 != 0;
+                    status.locked = f & 0x04 != 0;
+                    status.media_encryption = f & 0x08 != 0;
+                    status.mbr_enabled = f & 0x10 != 0;
+                    status.mbr_done = f & 0x20 != 0;
+                    None
+                }
+                0x0100 => Some(Ssc::Enterprise),
+                0x0200 => Some(Ssc::OpalV1),
+                0x0203 => {
+                    if data.len() >= 9 {
+                        status.admin_authorities = Some(be16(data, 5));
+                        status.user_authorities = Some(be16(data, 7));
+                    }
+                    Some(Ssc::OpalV2)
+                }
+                0x0301 => Some(Ssc::Opalite),
+                0x0302 => Some(Ssc::PyriteV1),
+                0x0303 => Some(Ssc::PyriteV2),
+                0x0304 => Some(Ssc::Ruby),
+                0x0402 => {
+                    // bit 0: SID value state, set once C_PIN_SID differs from MSID
+                    status.sid_is_msid = Some(data[0] & 0x01 == 0);
+                    status.sid_blocked = data[0] & 0x02 != 0;
+                    None
+                }
+                _ => None,
+            };
+            // the first SSC descriptor wins, that's the one the TPer lists as primary
+            if let Some(ssc) = ssc
+                && status.ssc.is_none()
+                && data.len() >= 4
+            {
+                status.ssc = Some(ssc);
+                status.base_comid = be16(data, 0);
+                status.num_comids = be16(data, 2);
+            }
+        }
+
+        if !tper {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "no TPer feature in Level 0 discovery"));
+        }
+        Ok(status)
+    }
+
+    pub fn is_enterprise(&self) -> bool {
+        self.ssc == Some(Ssc::Enterprise)
+    }
+
+    /// One line for the device listing and the evidence log.
+    pub fn summary(&self) -> String {
+        let mut parts = vec![self.ssc.map_or("unknown SSC".to_string(), |s| s.to_string())];
+        if !self.locking_supported {
+            parts.push("no locking".to_string());
+        } else {
+            parts.push(if self.locking_enabled { "Locking SP active" } else { "Locking SP inactive" }.to_string());
+            parts.push(if self.locked { "locked" } else { "unlocked" }.to_string());
+        }
+        parts.push(if self.media_encryption { "media encryption" } else { "no media encryption" }.to_string());
+        if self.mbr_enabled {
+            parts.push(format!("MBR shadow {}", if self.mbr_done { "done" } else { "active" }));
+        }
+        if self.sid_blocked {
+            parts.push("SID blocked".to_string());
+        }
+        if self.sid_is_msid == Some(false) {
+            parts.push("SID changed".to_string());
+        }
+        if !self.ranges.is_empty() {
+            parts.push(format!("{} locking range(s)", self.ranges.len()));
+        }
+        if self.psid_reverted {
+            parts.push("PSID reverted".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+// ---------- IF-SEND / IF-RECV ----------
+
+fn if_recv(t: &mut dyn DriveTransport, devtype: &DeviceType, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    match devtype {
+        DeviceType::Sata => ata::trusted_receive(t, SECURITY_PROTOCOL_TCG, comid, buf),
+        DeviceType::Nvme => nvme::security_receive(t, SECURITY_PROTOCOL_TCG, comid, buf),
+        DeviceType::Scsi => scsi::security_protocol_in(t, SECURITY_PROTOCOL_TCG, comid, buf),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, format!("no security protocol path for {:?} devices", devtype))),
+    }
+}
+
+fn if_send(t: &mut dyn DriveTransport, devtype: &DeviceType, comid: u16, buf: &mut [u8]) -> io::Result<()> {
+    match devtype {
+        DeviceType::Sata => ata::trusted_send(t, SECURITY_PROTOCOL_TCG, comid, buf),
+        DeviceType::Nvme => nvme::security_send(t, SECURITY_PROTOCOL_TCG, comid, buf),
+        DeviceType::Scsi => scsi::security_protocol_out(t, SECURITY_PROTOCOL_TCG, comid, buf),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, format!("no security protocol path for {:?} devices", devtype))),
+    }
+}
+
+/// Level 0 discovery. Errors when the drive has no TCG security subsystem.
+pub fn discover(t: &mut dyn DriveTransport, devtype: &DeviceType) -> io::Result<SedStatus> {
+    let mut buf = vec![0u8; IO_BUFFER];
+    if_recv(t, devtype, LEVEL0_COMID, &mut buf)?;
+    SedStatus::parse_level0(&buf)
+}
+
+// ---------- token stream ----------
+
+/// Encoder for the token stream inside a data subpacket.
+#[derive(Debug, Default)]
+pub(crate) struct Tokens(pub(crate) Vec<u8>);
+
+impl Tokens {
+    pub(crate) fn token(&mut self, t: u8) -> &mut Self {
+        self.0.push(t);
+        self
+    }
+
+    /// Tiny atom below 64, otherwise a short atom with the minimal byte count.
+    pub(crate) fn uint(&mut self, v: u64) -> &mut Self {
+        if v < 64 {
+            self.0.push(v as u8);
+        } else {
+            let bytes = v.to_be_bytes();
+            let skip = (v.leading_zeros() / 8) as usize;
+            self.0.push(0x80 | (8 - skip) as u8);
+            self.0.extend_from_slice(&bytes[skip..]);
+        }
+        self
+    }
+
+    pub(crate) fn bytes(&mut self, b: &[u8]) -> &mut Self {
+        match b.len() {
+            0..=15 => self.0.push(0xA0 | b.len() as u8),
+            16..=2047 => self.0.extend_from_slice(&[0xD0 | (b.len() >> 8) as u8, b.len() as u8]),
+            n => self.0.extend_from_slice(&[0xE2, (n >> 16) as u8, (n >> 8) as u8, n as u8]),
+        }
+        self.0.extend_from_slice(b);
+        self
+    }
+
+    pub(crate) fn uid(&mut self, uid: u64) -> &mut Self {
+        self.bytes(&uid.to_be_bytes())
+    }
+
+    /// Method status list that ends every call and response.
+    pub(crate) fn status(&mut self, status: u8) -> &mut Self {
+        self.token(END_OF_DATA).token(START_LIST).uint(status as u64).uint(0).uint(0).token(END_LIST)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    Control(u8),
+}
+
+impl Token {
+    fn is(&self, control: u8) -> bool {
+        *self == Token::Control(control)
+    }
+
+    pub(crate) fn uid(&self) -> Option<u64> {
+        match self {
+            Token::Bytes(b) if b.len() == 8 => Some(u64::from_be_bytes(b[..8].try_into().unwrap())),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn decode(mut d: &[u8]) -> io::Result<Vec<Token>> {
+    let short = || io::Error::new(io::ErrorKind::InvalidData, "truncated TCG token");
+    let mut out = Vec::new();
+    while let Some(&b) = d.first() {
+        let (header, len, bytes) = match b {
+            0x00..=0x7F => {
+                out.push(Token::Uint((b & 0x3F) as u64));
+                d = &d[1..];
+                continue;
+            }
+            0x80..=0xBF => (1, (b & 0x0F) as usize, b & 0x20 != 0),
+            0xC0..=0xDF => {
+                let lo = *d.get(1).ok_or_else(short)? as usize;
+                (2, (((b & 0x07) as usize) << 8) | lo, b & 0x10 != 0)
+            }
+            0xE0..=0xE3 => {
+                let l = d.get(1..4).ok_or_else(short)?;
+                (4, ((l[0] as usize) << 16) | ((l[1] as usize) << 8) | l[2] as usize, b & 0x02 != 0)
+            }
+            EMPTY => {
+                d = &d[1..];
+                continue;
+            }
+            _ => {
+                out.push(Token::Control(b));
+                d = &d[1..];
+                continue;
+            }
+        };
+        let value = d.get(header..header + len).ok_or_else(short)?;
+        out.push(if bytes || len > 8 {
+            Token::Bytes(value.to_vec())
+        } else {
+            Token::Uint(value.iter().fold(0u64, |acc, x| (acc << 8) | *x as u64))
+        });
+        d = &d[header + len..];
+    }
+    Ok(out)
+}
+
+// ---------- packets ----------
+
+/// ComPacket (20 bytes) + Packet (24) + data subpacket (12) around `payload`,
+/// padded to a whole number of 512 byte blocks for the transport.
+pub(crate) fn compacket(comid: u16, tsn: u32, hsn: u32, payload: &[u8]) -> Vec<u8> {
+    let sub_len = payload.len();
+    let packet_len = 12 + sub_len.next_multiple_of(4);
+    let compacket_len = 24 + packet_len;
+    let mut buf = vec![0u8; (20 + compacket_len).next_multiple_of(512)];
+    buf[4..6].copy_from_slice(&comid.to_be_bytes());
+    buf[16..20].copy_from_slice(&(compacket_len as u32).to_be_bytes());
+    buf[20..24].copy_from_slice(&tsn.to_be_bytes());
+    buf[24..28].copy_from_slice(&hsn.to_be_bytes());
+    buf[40..44].copy_from_slice(&(packet_len as u32).to_be_bytes());
+    buf[52..56].copy_from_slice(&(sub_len as u32).to_be_bytes());
+    buf[56..56 + sub_len].copy_from_slice(payload);
+    buf
+}
+
+/// Session numbers and payload of a ComPacket. None while the TPer has
+/// nothing for us yet (length 0).
+pub(crate) fn parse_compacket(d: &[u8]) -> io::Result<Option<(u32, u32, &[u8])>> {
+    let be32 = |o: usize| u32::from_be_bytes([d[o], d[o + 1], d[o + 2], d[o + 3]]);
+    if d.len() < 56 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "ComPacket shorter than its headers"));
+    }
+    if be32(16) == 0 {
+        return Ok(None);
+    }
+    let sub_len = be32(52) as usize;
+    let payload = d.get(56..56 + sub_len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "subpacket runs past the ComPacket"))?;
+    Ok(Some((be32(20), be32(24), payload)))
+}
+
+fn status_name(status: u64) -> &'static str {
+    match status {
+        0x01 => "NOT_AUTHORIZED",
+        0x03 => "SP_BUSY",
+        0x04 => "SP_FAILED",
+        0x05 => "SP_DISABLED",
+        0x06 => "SP_FROZEN",
+        0x07 => "NO_SESSIONS_AVAILABLE",
+        0x0C => "INVALID_PARAMETER",
+        0x0F => "TPER_MALFUNCTION",
+        0x12 => "AUTHORITY_LOCKED_OUT",
+        0x3F => "FAIL",
+        _ => "unknown status",
+    }
+}
+
+fn method_name(method: u64) -> String {
+    match method {
+        START_SESSION => "StartSession".to_string(),
+        GET | ENTERPRISE_GET => "Get".to_string(),
+        GEN_KEY => "GenKey".to_string(),
+        REVERT_SP => "RevertSP".to_string(),
+        REVERT => "Revert".to_string(),
+        ACTIVATE => "Activate".to_string(),
+        ERASE => "Erase".to_string(),
+        m => format!("method 0x{:016x}", m),
+    }
+}
+
+/// Result tokens of one method call, up to the end-of-data token.
+#[derive(Debug)]
+struct Reply(Vec<Token>);
+
+impl Reply {
+    fn value(&self, col: Column) -> Option<&Token> {
+        self.0.windows(3).find_map(|w| {
+            let named = w[0].is(START_NAME)
+                && (w[1] == Token::Uint(col.0) || w[1] == Token::Bytes(col.1.as_bytes().to_vec()));
+            named.then_some(&w[2])
+        })
+    }
+
+    fn uint(&self, col: Column) -> Option<u64> {
+        match self.value(col)? {
+            Token::Uint(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self, col: Column) -> Option<&[u8]> {
+        match self.value(col)? {
+            Token::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn flag(&self, col: Column) -> bool {
+        self.uint(col).is_some_and(|v| v != 0)
+    }
+}
+
+// ---------- sessions ----------
+
+/// An open session with one SP. The TPer ends it after a revert, otherwise call `close`.
+pub struct Session<'a> {
+    t: &'a mut dyn DriveTransport,
+    devtype: DeviceType,
+    comid: u16,
+    enterprise: bool,
+    tsn: u32,
+    hsn: u32,
+}
+
+impl<'a> Session<'a> {
+    /// StartSession on `sp`, as `authority` with its password or as Anybody.
+    pub fn start(t: &'a mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, sp: u64, authority: Option<(u64, &[u8])>) -> io::Result<Self> {
+        if status.base_comid == 0 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "no base ComID from Level 0 discovery"));
+        }
+        let mut s = Session { t, devtype: devtype.clone(), comid: status.base_comid, enterprise: status.is_enterprise(), tsn: 0, hsn: 0 };
+
+        let mut params = Tokens::default();
+        params.uint(HOST_SESSION_ID as u64).uid(sp).uint(1); // HostSessionID, SPID, Write
+        if let Some((auth, password)) = authority {
+            params.token(START_NAME).uint(0).bytes(password).token(END_NAME);
+            params.token(START_NAME).uint(3).uid(auth).token(END_NAME);
+        }
+        let reply = s.call(SMUID, START_SESSION, &params.0)?;
+
+        // SyncSession(HostSessionID, SPSessionID)
+        let sync = reply.0.iter().position(|t| t.uid() == Some(SYNC_SESSION));
+        let numbers: Vec<u64> = reply.0.iter().skip(sync.unwrap_or(0)).filter_map(|t| match t {
+            Token::Uint(v) => Some(*v),
+            _ => None,
+        }).take(2).collect();
+        match (sync, numbers.as_slice()) {
+            (Some(_), [hsn, tsn]) => {
+                s.hsn = *hsn as u32;
+                s.tsn = *tsn as u32;
+                Ok(s)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "malformed SyncSession reply")),
+        }
+    }
+
+    fn exchange(&mut self, payload: &[u8]) -> io::Result<Vec<Token>> {
+        let mut buf = compacket(self.comid, self.tsn, self.hsn, payload);
+        if_send(self.t, &self.devtype, self.comid, &mut buf)?;
+
+        let start = Instant::now();
+        let mut resp = vec![0u8; IO_BUFFER];
+        loop {
+            resp.fill(0);
+            if_recv(self.t, &self.devtype, self.comid, &mut resp)?;
+            if let Some((_, _, payload)) = parse_compacket(&resp)? {
+                return decode(payload);
+            }
+            if start.elapsed() > RESPONSE_TIMEOUT {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no response from the TPer"));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Invoke `method` on `object`. `params` is the encoded parameter list
+    /// without the surrounding list tokens.
+    fn call(&mut self, object: u64, method: u64, params: &[u8]) -> io::Result<Reply> {
+        let mut call = Tokens::default();
+        call.token(CALL).uid(object).uid(method).token(START_LIST);
+        call.0.extend_from_slice(params);
+        call.token(END_LIST).status(0);
+
+        let tokens = self.exchange(&call.0)?;
+        if tokens.first().is_some_and(|t| t.is(END_OF_SESSION)) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "TPer closed the session"));
+        }
+        let eod = tokens.iter().rposition(|t| t.is(END_OF_DATA))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "method reply without status"))?;
+        let status = match tokens.get(eod + 2) {
+            Some(Token::Uint(s)) => *s,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed method status")),
+        };
+        match status {
+            0 => Ok(Reply(tokens[..eod].to_vec())),
+            0x01 | 0x12 => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                format!("{} failed: {}", method_name(method), status_name(status)))),
+            s => Err(io::Error::other(format!("{} failed: {} (0x{:02x})", method_name(method), status_name(s), s))),
+        }
+    }
+
+    /// Get columns `first..=last` of a table row.
+    fn get(&mut self, row: u64, first: Column, last: Column) -> io::Result<Reply> {
+        let mut cells = Tokens::default();
+        cells.token(START_LIST);
+        if self.enterprise {
+            cells.token(START_NAME).bytes(b"startColumn").bytes(first.1.as_bytes()).token(END_NAME);
+            cells.token(START_NAME).bytes(b"endColumn").bytes(last.1.as_bytes()).token(END_NAME);
+        } else {
+            cells.token(START_NAME).uint(3).uint(first.0).token(END_NAME);
+            cells.token(START_NAME).uint(4).uint(last.0).token(END_NAME);
+        }
+        cells.token(END_LIST);
+        let method = if self.enterprise { ENTERPRISE_GET } else { GET };
+        self.call(row, method, &cells.0)
+    }
+
+    pub fn close(mut self) -> io::Result<()> {
+        self.exchange(&[END_OF_SESSION])?;
+        Ok(())
+    }
+}
+
+fn range_uid(enterprise: bool, n: u32) -> u64 {
+    match (enterprise, n) {
+        (_, 0) => LOCKING_GLOBAL_RANGE,
+        (true, n) => LOCKING_GLOBAL_RANGE + n as u64, // BandN
+        (false, n) => 0x0000_0802_0003_0000 + n as u64,
+    }
+}
+
+fn require_opal(status: &SedStatus) -> io::Result<()> {
+    match status.ssc {
+        Some(Ssc::Enterprise) => Err(io::Error::new(io::ErrorKind::Unsupported, "Enterprise drives have no Locking SP revert, use the band erase")),
+        Some(_) => Ok(()),
+        None => Err(io::Error::new(io::ErrorKind::Unsupported, "no known TCG SSC")),
+    }
+}
+
+// ---------- operations ----------
+
+/// MSID from the Admin SP, readable by Anybody. It's the factory password of SID
+/// (and of every Enterprise authority) until someone changes it.
+pub fn read_msid(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus) -> io::Result<Vec<u8>> {
+    let mut s = Session::start(t, devtype, status, ADMIN_SP, None)?;
+    let reply = s.get(C_PIN_MSID, PIN, PIN);
+    s.close()?;
+    reply?.bytes(PIN)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no PIN in C_PIN_MSID"))
+}
+
+/// Revert the whole TPer with the PSID printed on the drive label. Works whoever
+/// owns the drive: every SP goes back to factory state and the media encryption
+/// keys are replaced.
+pub fn psid_revert(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, psid: &str) -> io::Result<()> {
+    let mut s = Session::start(t, devtype, status, ADMIN_SP, Some((PSID, psid.trim().as_bytes())))?;
+    // the TPer drops the session once the revert is done
+    s.call(ADMIN_SP, REVERT, &[])?;
+    Ok(())
+}
+
+/// Activate the Locking SP as SID. Admin1 starts out with SID's password.
+pub fn activate_locking_sp(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, sid_password: &[u8]) -> io::Result<()> {
+    require_opal(status)?;
+    let mut s = Session::start(t, devtype, status, ADMIN_SP, Some((SID, sid_password)))?;
+    s.call(LOCKING_SP, ACTIVATE, &[])?;
+    s.close()
+}
+
+fn read_range(s: &mut Session, enterprise: bool, n: u32) -> io::Result<(LockingRange, Option<u64>)> {
+    let row = s.get(range_uid(enterprise, n), RANGE_START, ACTIVE_KEY)?;
+    let range = LockingRange {
+        number: n,
+        start_lba: row.uint(RANGE_START).unwrap_or(0),
+        length_lba: row.uint(RANGE_LENGTH).unwrap_or(0),
+        read_lock_enabled: row.flag(READ_LOCK_ENABLED),
+        write_lock_enabled: row.flag(WRITE_LOCK_ENABLED),
+        read_locked: row.flag(READ_LOCKED),
+        write_locked: row.flag(WRITE_LOCKED),
+    };
+    Ok((range, row.value(ACTIVE_KEY).and_then(Token::uid)))
+}
+
+fn max_ranges(s: &mut Session) -> u32 {
+    // LockingInfo is readable by Anybody; without it only the global range is known
+    s.get(LOCKING_INFO, MAX_RANGES, MAX_RANGES).ok().and_then(|r| r.uint(MAX_RANGES)).unwrap_or(0) as u32
+}
+
+/// Read the Locking table as Admin1 (Opal) or EraseMaster (Enterprise).
+pub fn read_locking_ranges(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, password: &[u8]) -> io::Result<Vec<LockingRange>> {
+    let enterprise = status.is_enterprise();
+    let (sp, auth) = if enterprise { (ENTERPRISE_LOCKING_SP, ERASE_MASTER) } else { (LOCKING_SP, ADMIN1) };
+    let mut s = Session::start(t, devtype, status, sp, Some((auth, password)))?;
+    let mut ranges = Vec::new();
+    for n in 0..=max_ranges(&mut s) {
+        match read_range(&mut s, enterprise, n) {
+            Ok((range, _)) => ranges.push(range),
+            Err(e) => {
+                let _ = s.close();
+                return Err(e);
+            }
+        }
+    }
+    s.close()?;
+    Ok(ranges)
+}
+
+/// GenKey on the active key of every locking range, as Admin1. Whatever was
+/// written under the old keys is unreadable afterwards. Returns the ranges rekeyed.
+pub fn regenerate_range_keys(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, admin1_password: &[u8]) -> io::Result<Vec<LockingRange>> {
+    require_opal(status)?;
+    let mut s = Session::start(t, devtype, status, LOCKING_SP, Some((ADMIN1, admin1_password)))?;
+    let mut ranges = Vec::new();
+    for n in 0..=max_ranges(&mut s) {
+        let res = read_range(&mut s, false, n).and_then(|(range, key)| {
+            let key = key.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("locking range {} has no active key", n)))?;
+            s.call(key, GEN_KEY, &[])?;
+            Ok(range)
+        });
+        match res {
+            Ok(range) => ranges.push(range),
+            Err(e) => {
+                let _ = s.close();
+                return Err(e);
+            }
+        }
+    }
+    s.close()?;
+    Ok(ranges)
+}
+
+/// RevertSP on the Locking SP as Admin1: the range keys are destroyed and the
+/// Locking SP is back to Manufactured-Inactive.
+pub fn revert_locking_sp(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, admin1_password: &[u8]) -> io::Result<()> {
+    require_opal(status)?;
+    let mut s = Session::start(t, devtype, status, LOCKING_SP, Some((ADMIN1, admin1_password)))?;
+    // the TPer drops the session once the revert is done
+    s.call(THIS_SP, REVERT_SP, &[])?;
+    Ok(())
+}
+
+/// Enterprise: Erase every band as EraseMaster, each gets a new key and is reset
+/// to defaults. Returns the bands erased.
+pub fn enterprise_erase(t: &mut dyn DriveTransport, devtype: &DeviceType, status: &SedStatus, erase_master_password: &[u8]) -> io::Result<Vec<LockingRange>> {
+    if !status.is_enterprise() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "band erase is Enterprise SSC only"));
+    }
+    let mut s = Session::start(t, devtype, status, ENTERPRISE_LOCKING_SP, Some((ERASE_MASTER, erase_master_password)))?;
+    let mut bands = Vec::new();
+    for n in 0..=max_ranges(&mut s) {
+        // the Get only fills in the report, a band we can't read still gets erased
+        let band = read_range(&mut s, true, n)
+            .map(|(band, _)| band)
+            .unwrap_or(LockingRange { number: n, ..Default::default() });
+        let res = s.call(range_uid(true, n), ERASE, &[]).map(|_| band);
+        match res {
+            Ok(band) => bands.push(band),
+            Err(e) => {
+                let _ = s.close();
+                return Err(e);
+            }
+        }
+    }
+    s.close()?;
+    Ok(bands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(code: u16, data: &[u8]) -> Vec<u8> {
+        let mut f = code.to_be_bytes().to_vec();
+        f.extend_from_slice(&[0x10, data.len() as u8]);
+        f.extend_from_slice(data);
+        f
+    }
+
+    fn level0(features: &[Vec<u8>]) -> Vec<u8> {
+        let mut d = vec![0u8; 48];
+        for f in features {
+            d.extend_from_slice(f);
+        }
+        let len = (d.len() - 4) as u32;
+        d[0..4].copy_from_slice(&len.to_be_bytes());
+        // trailing junk past the length field must be ignored
+        d.extend_from_slice(&[0x02, 0x03, 0x10, 0x0C]);
+        d
+    }
+
+    #[test]
+    fn atoms() {
+        let mut t = Tokens::default();
+        t.uint(5).uint(63).uint(64).uint(0x1234).uint(u64::MAX);
+        assert_eq!(t.0, [
+            &[0x05, 0x3F, 0x81, 0x40, 0x82, 0x12, 0x34, 0x88][..],
+            &[0xFF; 8],
+        ].concat());
+
+        let mut t = Tokens::default();
+        t.bytes(b"").bytes(b"abc").uid(SMUID);
+        assert_eq!(t.0, [0xA0, 0xA3, b'a', b'b', b'c', 0xA8, 0, 0, 0, 0, 0, 0, 0, 0xFF]);
+
+        let mut t = Tokens::default();
+        t.bytes(&[7; 16]);
+        assert_eq!(&t.0[..3], &[0xD0, 0x10, 7]);
+        let mut t = Tokens::default();
+        t.bytes(&[7; 2048]);
+        assert_eq!(&t.0[..5], &[0xE2, 0x00, 0x08, 0x00, 7]);
+
+        let mut t = Tokens::default();
+        t.status(0x12);
+        assert_eq!(t.0, [END_OF_DATA, START_LIST, 0x12, 0, 0, END_LIST]);
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let long = vec![0x5A; 300];
+        let huge = vec![0xA5; 4096];
+        let mut t = Tokens::default();
+        t.token(CALL).uid(LOCKING_SP).uid(GET).token(START_LIST)
+            .token(START_NAME).uint(3).uint(u32::MAX as u64).token(END_NAME)
+            .bytes(b"startColumn").bytes(&long).bytes(&huge)
+            .token(END_LIST).status(0);
+        assert_eq!(decode(&t.0).unwrap(), vec![
+            Token::Control(CALL),
+            Token::Bytes(LOCKING_SP.to_be_bytes().to_vec()),
+            Token::Bytes(GET.to_be_bytes().to_vec()),
+            Token::Control(START_LIST),
+            Token::Control(START_NAME),
+            Token::Uint(3),
+            Token::Uint(u32::MAX as u64),
+            Token::Control(END_NAME),
+            Token::Bytes(b"startColumn".to_vec()),
+            Token::Bytes(long),
+            Token::Bytes(huge),
+            Token::Control(END_LIST),
+            Token::Control(END_OF_DATA),
+            Token::Control(START_LIST),
+            Token::Uint(0),
+            Token::Uint(0),
+            Token::Uint(0),
+            Token::Control(END_LIST),
+        ]);
+        assert_eq!(decode(&t.0).unwrap()[1].uid(), Some(LOCKING_SP));
+    }
+
+    #[test]
+    fn decode_edge_cases() {
+        // empty tokens are skipped, a long unsigned atom is kept as bytes
+        assert_eq!(decode(&[EMPTY, 0x01, EMPTY]).unwrap(), vec![Token::Uint(1)]);
+        let mut wide = vec![0x89];
+        wide.extend_from_slice(&[1; 9]);
+        assert_eq!(decode(&wide).unwrap(), vec![Token::Bytes(vec![1; 9])]);
+        assert_eq!(decode(&[0xC0, 0x02, 0x01, 0x00]).unwrap(), vec![Token::Uint(0x100)]);
+
+        for truncated in [&[0x82, 0x12][..], &[0xA3, b'a'], &[0xD0], &[0xD0, 0x10, 0], &[0xE2, 0, 0], &[0xE2, 0, 0, 2, 0]] {
+            assert_eq!(decode(truncated).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:02x?}", truncated);
+        }
+    }
+
+    #[test]
+    fn compacket_round_trip() {
+        let mut payload = Tokens::default();
+        payload.token(CALL).uid(SMUID).uid(START_SESSION).status(0);
+        let buf = compacket(0x1000, 0x2345, 1, &payload.0);
+        assert_eq!(buf.len(), 512);
+        assert_eq!(&buf[4..6], &[0x10, 0x00]);
+        assert_eq!(parse_compacket(&buf).unwrap(), Some((0x2345, 1, &payload.0[..])));
+
+        // a payload that doesn't fit one block spills into the next
+        let big = vec![0x42; 600];
+        let buf = compacket(0x1000, 7, 9, &big);
+        assert_eq!(buf.len(), 1024);
+        assert_eq!(parse_compacket(&buf).unwrap(), Some((7, 9, &big[..])));
+    }
+
+    #[test]
+    fn compacket_errors() {
+        assert_eq!(parse_compacket(&[0u8; 512]).unwrap(), None);
+        assert_eq!(parse_compacket(&[0u8; 40]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut buf = compacket(0x1000, 1, 1, b"abcd");
+        buf[52..56].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(parse_compacket(&buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn level0_opal2() {
+        let mut opal = vec![0u8; 16];
+        opal[0..2].copy_from_slice(&0x1000u16.to_be_bytes());
+        opal[2..4].copy_from_slice(&1u16.to_be_bytes());
+        opal[5..7].copy_from_slice(&4u16.to_be_bytes());
+        opal[7..9].copy_from_slice(&8u16.to_be_bytes());
+        let mut enterprise = vec![0u8; 16];
+        enterprise[0..2].copy_from_slice(&0x07FEu16.to_be_bytes());
+        let d = level0(&[
+            feature(0x0001, &[0x11; 12]),
+            feature(0x0002, &[0x0F | 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            feature(0x0203, &opal),
+            feature(0x0100, &enterprise),
+            feature(0x0402, &[0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        ]);
+        let s = SedStatus::parse_level0(&d).unwrap();
+        assert_eq!(s, SedStatus {
+            ssc: Some(Ssc::OpalV2),
+            base_comid: 0x1000,
+            num_comids: 1,
+            locking_supported: true,
+            locking_enabled: true,
+            locked: true,
+            media_encryption: true,
+            mbr_enabled: true,
+            mbr_done: false,
+            sid_blocked: true,
+            sid_is_msid: Some(false),
+            admin_authorities: Some(4),
+            user_authorities: Some(8),
+            ..Default::default()
+        });
+        assert!(!s.is_enterprise());
+        assert_eq!(s.summary(), "Opal 2.0, Locking SP active, locked, media encryption, MBR shadow active, SID blocked, SID changed");
+    }
+
+    #[test]
+    fn level0_enterprise() {
+        let mut enterprise = vec![0u8; 16];
+        enterprise[0..2].copy_from_slice(&0x07FEu16.to_be_bytes());
+        enterprise[2..4].copy_from_slice(&2u16.to_be_bytes());
+        let d = level0(&[
+            feature(0x0001, &[0x11; 12]),
+            feature(0x0002, &[0x0B, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            // unknown feature codes are skipped
+            feature(0x1234, &[0xFF; 4]),
+            feature(0x0100, &enterprise),
+        ]);
+        let s = SedStatus::parse_level0(&d).unwrap();
+        assert!(s.is_enterprise());
+        assert_eq!((s.base_comid, s.num_comids), (0x07FE, 2));
+        assert_eq!(s.sid_is_msid, None);
+        assert_eq!(s.admin_authorities, None);
+    }
+
+    #[test]
+    fn level0_errors() {
+        assert_eq!(SedStatus::parse_level0(&[0u8; 47]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(SedStatus::parse_level0(&level0(&[])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let no_tper = level0(&[feature(0x0203, &[0x10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])]);
+        assert_eq!(SedStatus::parse_level0(&no_tper).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
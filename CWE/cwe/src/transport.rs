@@ -0,0 +1,696 @@
+// Command transport between the wipe engine and a drive.
+//
+// Everything in `device` and `wipe` talks to the hardware through `DriveTransport`
+// instead of opening files and calling ioctl directly. `LinuxTransport` is the real
+// backend, `MemoryTransport` is an in-memory stand-in so the wipe paths can run
+// without sacrificing a disk.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use libc::{c_void, ioctl};
+use crate::device::{Device, DeviceType};
+use crate::sgio::{self, SenseData};
+
+pub const HDIO_DRIVE_CMD: u64 = 0x031f;
+pub const NVME_IOCTL_ID: u64 = 0x4E40; // _IO('N', 0x40)
+pub const NVME_IOCTL_ADMIN_CMD: u64 = 0xC0484E41; // _IOWR('N', 0x41, struct nvme_admin_cmd)
+pub const MMC_IOC_CMD: u64 = 0xC048B300; // _IOWR(MMC_BLOCK_MAJOR, 0, struct mmc_ioc_cmd)
+pub const MMC_IOC_MULTI_CMD: u64 = 0xC008B301; // _IOWR(MMC_BLOCK_MAJOR, 1, struct mmc_ioc_multi_cmd)
+pub const BLKFLSBUF: u64 = 0x1261; // _IO(0x12, 97)
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Mirror of the kernel's `struct nvme_admin_cmd` (linux/nvme_ioctl.h).
+#[repr(C)]
+#[derive(Debug, Default)]
+#[allow(non_camel_case_types)]
+pub struct nvme_admin_cmd {
+    pub opcode: u8,
+    pub flags: u8,
+    pub rsvd1: u16,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub metadata: u64,
+    pub addr: u64,
+    pub metadata_len: u32,
+    pub data_len: u32,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+    pub timeout_ms: u32,
+    pub result: u32,
+}
+
+/// Mirror of the kernel's `struct mmc_ioc_cmd` (linux/mmc/ioctl.h).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub struct mmc_ioc_cmd {
+    pub write_flag: i32,
+    pub is_acmd: i32,
+    pub opcode: u32,
+    pub arg: u32,
+    pub response: [u32; 4],
+    pub flags: u32,
+    pub blksz: u32,
+    pub blocks: u32,
+    pub postsleep_min_us: u32,
+    pub postsleep_max_us: u32,
+    pub data_timeout_ns: u32,
+    pub cmd_timeout_ms: u32,
+    pub pad: u32,
+    pub data_ptr: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataDir {
+    None,
+    In,  // device -> host
+    Out, // host -> device
+}
+
+/// ATA taskfile. Inputs are filled by the caller, `status`/`error` (and `lba`/`count`
+/// for commands that return values in registers) are filled by the transport.
+#[derive(Debug, Clone)]
+pub struct AtaTaskfile {
+    pub command: u8,
+    pub features: u16,
+    pub count: u16,
+    pub lba: u64,
+    pub device: u8,
+    pub dir: DataDir,
+    /// Command timeout, only honoured by backends that take one (SG_IO).
+    pub timeout_secs: u32,
+    pub status: u8,
+    pub error: u8,
+}
+
+impl AtaTaskfile {
+    pub fn new(command: u8) -> Self {
+        AtaTaskfile {
+            command,
+            features: 0,
+            count: 0,
+            lba: 0,
+            device: 0,
+            dir: DataDir::None,
+            timeout_secs: 60,
+            status: 0,
+            error: 0,
+        }
+    }
+
+    pub fn features(mut self, features: u16) -> Self {
+        self.features = features;
+        self
+    }
+
+    pub fn count(mut self, count: u16) -> Self {
+        self.count = count;
+        self
+    }
+
+    pub fn lba(mut self, lba: u64) -> Self {
+        self.lba = lba;
+        self.device |= 0x40; // LBA mode
+        self
+    }
+
+    pub fn timeout(mut self, secs: u32) -> Self {
+        self.timeout_secs = secs;
+        self
+    }
+
+    pub fn data_in(mut self) -> Self {
+        self.dir = DataDir::In;
+        self
+    }
+
+    pub fn data_out(mut self) -> Self {
+        self.dir = DataDir::Out;
+        self
+    }
+
+    /// ERR bit of the status register.
+    pub fn failed(&self) -> bool {
+        self.status & 0x01 != 0
+    }
+}
+
+/// NVMe admin command. The data buffer is passed separately to the transport.
+#[derive(Debug, Clone, Default)]
+pub struct NvmeCommand {
+    pub opcode: u8,
+    pub nsid: u32,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+    pub timeout_ms: u32,
+}
+
+impl NvmeCommand {
+    pub fn new(opcode: u8) -> Self {
+        NvmeCommand { opcode, ..Default::default() }
+    }
+}
+
+/// MMC/SD command. `flags` uses the kernel MMC_RSP_*/MMC_CMD_* bits, `response`
+/// is filled by the transport.
+#[derive(Debug, Clone, Default)]
+pub struct MmcCommand {
+    pub opcode: u32,
+    pub arg: u32,
+    pub flags: u32,
+    pub write: bool,
+    pub blksz: u32,
+    pub blocks: u32,
+    /// Busy wait the host controller allows for R1b commands, 0 = kernel default.
+    pub timeout_ms: u32,
+    pub response: [u32; 4],
+}
+
+impl MmcCommand {
+    pub fn new(opcode: u32, arg: u32, flags: u32) -> Self {
+        MmcCommand { opcode, arg, flags, ..Default::default() }
+    }
+
+    fn to_ioc(&self, data: &mut [u8]) -> mmc_ioc_cmd {
+        mmc_ioc_cmd {
+            write_flag: self.write as i32,
+            opcode: self.opcode,
+            arg: self.arg,
+            flags: self.flags,
+            blksz: self.blksz,
+            blocks: self.blocks,
+            cmd_timeout_ms: self.timeout_ms,
+            data_ptr: if data.is_empty() { 0 } else { data.as_mut_ptr() as u64 },
+            ..Default::default()
+        }
+    }
+}
+
+pub trait DriveTransport {
+    /// Path of the device this transport talks to, used for logging.
+    fn path(&self) -> &str;
+
+    /// Issue an ATA command. `data` holds the data-in/data-out sectors (may be empty).
+    fn ata_command(&mut self, tf: &mut AtaTaskfile, data: &mut [u8]) -> io::Result<()>;
+
+    /// Issue an NVMe admin command, returns the completion dword 0.
+    fn nvme_admin(&mut self, cmd: &NvmeCommand, data: &mut [u8]) -> io::Result<u32>;
+
+    /// NSID of the namespace this transport was opened on. Unsupported when it was
+    /// opened on something else, a controller node for instance.
+    fn nvme_nsid(&mut self) -> io::Result<u32> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "transport is not an NVMe namespace"))
+    }
+
+    /// Issue a SCSI CDB. Returns the sense data when the device answered CHECK CONDITION,
+    /// None on GOOD status. Transports without a SCSI path report Unsupported.
+    fn scsi_command(&mut self, _cdb: &[u8], _dir: DataDir, _data: &mut [u8], _timeout_secs: u32) -> io::Result<Option<SenseData>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "transport has no SCSI path"))
+    }
+
+    /// Issue a single MMC command with an optional data block.
+    fn mmc_command(&mut self, _cmd: &mut MmcCommand, _data: &mut [u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "transport has no MMC path"))
+    }
+
+    /// Issue data-less MMC commands back to back, nothing else reaches the card in
+    /// between. ERASE needs this: the start/end addresses are lost if the block
+    /// layer slips a read in.
+    fn mmc_sequence(&mut self, _cmds: &mut [MmcCommand]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "transport has no MMC path"))
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()>;
+
+    /// Write around the page cache (O_DIRECT). `offset`, the length and the buffer
+    /// address must be aligned to the logical block size. Transports without direct
+    /// I/O just write.
+    fn write_direct_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.write_at(offset, buf)
+    }
+
+    /// Capacity in bytes as seen by the host.
+    fn size(&mut self) -> io::Result<u64>;
+
+    /// Get everything written so far onto the media, out of the drive's cache too.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// ---------- Linux ioctl backend ----------
+
+/// How `LinuxTransport` delivers ATA commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtaBackend {
+    /// SG_IO with SAT ATA PASS-THROUGH(16/12) CDBs. Works through libata and USB bridges.
+    SgIo,
+    /// Legacy HDIO_DRIVE_CMD. libata only, no real data-out.
+    HdioDriveCmd,
+}
+
+pub struct LinuxTransport {
+    dev_path: String,
+    file: File,
+    writer: Option<File>,
+    // O_DIRECT write handle, opened by the first direct write
+    direct: Option<File>,
+    direct_refused: bool,
+    ctrl: Option<File>,
+    ata_backend: AtaBackend,
+}
+
+impl LinuxTransport {
+    /// Open a block device read-only. A write handle is opened on first write.
+    pub fn open(dev_path: &str) -> io::Result<Self> {
+        let file = File::open(dev_path)?;
+        Ok(LinuxTransport {
+            dev_path: dev_path.to_string(),
+            file,
+            writer: None,
+            direct: None,
+            direct_refused: false,
+            ctrl: None,
+            ata_backend: AtaBackend::HdioDriveCmd,
+        })
+    }
+
+    /// Open a device with the backend that suits its type: SATA goes through SG_IO.
+    pub fn open_device(dev: &Device) -> io::Result<Self> {
+        let t = Self::open(&dev.dev_path)?;
+        Ok(match dev.devtype {
+            DeviceType::Sata | DeviceType::Scsi => t.with_ata_backend(AtaBackend::SgIo),
+            _ => t,
+        })
+    }
+
+    pub fn with_ata_backend(mut self, backend: AtaBackend) -> Self {
+        self.ata_backend = backend;
+        self
+    }
+
+    fn writer(&mut self) -> io::Result<&File> {
+        if self.writer.is_none() {
+            self.writer = Some(OpenOptions::new().read(true).write(true).open(&self.dev_path)?);
+        }
+        Ok(self.writer.as_ref().unwrap())
+    }
+
+    // SG_IO and MMC passthrough want a read-write handle, fall back to the read-only one
+    fn rw_fd(&mut self) -> i32 {
+        match self.writer() {
+            Ok(w) => w.as_raw_fd(),
+            Err(_) => self.file.as_raw_fd(),
+        }
+    }
+
+    // Legacy HDIO_DRIVE_CMD: buffer is 4 header bytes followed by the data sectors.
+    // args[0] = command, args[1] = sector count (lba low for SMART), args[2] = feature,
+    // args[3] = number of data-in sectors. On return args[0] = status, args[1] = error.
+    // LBA mid/high can't be expressed and data-out is not really transferred.
+    fn hdio_drive_cmd(&mut self, tf: &mut AtaTaskfile, data: &mut [u8]) -> io::Result<()> {
+        let sectors = data.len().div_ceil(SECTOR_SIZE);
+        let mut args = vec![0u8; 4 + sectors * SECTOR_SIZE];
+        args[0] = tf.command;
+        args[1] = if tf.command == 0xB0 { tf.lba as u8 } else { tf.count as u8 };
+        args[2] = tf.features as u8;
+        if tf.dir == DataDir::In {
+            args[3] = sectors as u8;
+        } else if tf.dir == DataDir::Out {
+            args[4..4 + data.len()].copy_from_slice(data);
+        }
+
+        let fd = self.file.as_raw_fd();
+        let ret = unsafe { ioctl(fd, HDIO_DRIVE_CMD as _, args.as_mut_ptr() as *mut c_void) };
+        tf.status = args[0];
+        tf.error = args[1];
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if tf.dir == DataDir::In {
+            data.copy_from_slice(&args[4..4 + data.len()]);
+        }
+        Ok(())
+    }
+
+    // NVMe admin ioctl usually expects controller character device (e.g., /dev/nvme0).
+    fn ctrl_fd(&mut self) -> i32 {
+        if self.ctrl.is_none() {
+            let ctrl_path = nvme_ctrl_path(&self.dev_path);
+            if ctrl_path != self.dev_path {
+                self.ctrl = File::open(&ctrl_path).ok();
+            }
+        }
+        match &self.ctrl {
+            Some(f) => f.as_raw_fd(),
+            None => self.file.as_raw_fd(),
+        }
+    }
+}
+
+impl DriveTransport for LinuxTransport {
+    fn path(&self) -> &str {
+        &self.dev_path
+    }
+
+    fn ata_command(&mut self, tf: &mut AtaTaskfile, data: &mut [u8]) -> io::Result<()> {
+        match self.ata_backend {
+            AtaBackend::SgIo => {
+                let fd = self.rw_fd();
+                sgio::ata_command(fd, tf, data)
+            }
+            AtaBackend::HdioDriveCmd => self.hdio_drive_cmd(tf, data),
+        }
+    }
+
+    fn nvme_admin(&mut self, cmd: &NvmeCommand, data: &mut [u8]) -> io::Result<u32> {
+        let mut raw = nvme_admin_cmd {
+            opcode: cmd.opcode,
+            nsid: cmd.nsid,
+            addr: if data.is_empty() { 0 } else { data.as_mut_ptr() as u64 },
+            data_len: data.len() as u32,
+            cdw10: cmd.cdw10,
+            cdw11: cmd.cdw11,
+            cdw12: cmd.cdw12,
+            cdw13: cmd.cdw13,
+            cdw14: cmd.cdw14,
+            cdw15: cmd.cdw15,
+            timeout_ms: cmd.timeout_ms,
+            ..Default::default()
+        };
+
+        let fd = self.ctrl_fd();
+        let ret = unsafe { ioctl(fd, NVME_IOCTL_ADMIN_CMD, &mut raw) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // positive return is the NVMe status field
+        if ret > 0 {
+            return Err(io::Error::other(format!("NVMe admin opcode 0x{:02x} failed: status 0x{:x}", cmd.opcode, ret)));
+        }
+        Ok(raw.result)
+    }
+
+    // the namespace block device answers with its NSID, a controller node with ENOTTY
+    fn nvme_nsid(&mut self) -> io::Result<u32> {
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), NVME_IOCTL_ID as _) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret as u32)
+    }
+
+    fn scsi_command(&mut self, cdb: &[u8], dir: DataDir, data: &mut [u8], timeout_secs: u32) -> io::Result<Option<SenseData>> {
+        let fd = self.rw_fd();
+        let res = sgio::sg_io(fd, cdb, dir, data, timeout_secs.saturating_mul(1000))?;
+        if res.ok() {
+            return Ok(None);
+        }
+        match res.sense() {
+            Some(sense) => Ok(Some(sense)),
+            None => Err(io::Error::other(format!(
+                "SCSI opcode 0x{:02x} failed: status 0x{:x} driver status 0x{:x}",
+                cdb[0], res.status, res.driver_status
+            ))),
+        }
+    }
+
+    fn mmc_command(&mut self, cmd: &mut MmcCommand, data: &mut [u8]) -> io::Result<()> {
+        let mut ioc = cmd.to_ioc(data);
+        let fd = self.rw_fd();
+        let ret = unsafe { ioctl(fd, MMC_IOC_CMD as _, &mut ioc as *mut mmc_ioc_cmd as *mut c_void) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        cmd.response = ioc.response;
+        Ok(())
+    }
+
+    fn mmc_sequence(&mut self, cmds: &mut [MmcCommand]) -> io::Result<()> {
+        // struct mmc_ioc_multi_cmd is a u64 count followed by the commands,
+        // build it in a u64 buffer so the alignment is right
+        let words = std::mem::size_of::<mmc_ioc_cmd>() / 8;
+        let mut buf = vec![0u64; 1 + cmds.len() * words];
+        buf[0] = cmds.len() as u64;
+        let iocs = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr().add(1) as *mut mmc_ioc_cmd, cmds.len()) };
+        for (ioc, cmd) in iocs.iter_mut().zip(cmds.iter()) {
+            *ioc = cmd.to_ioc(&mut []);
+        }
+
+        let fd = self.rw_fd();
+        let ret = unsafe { ioctl(fd, MMC_IOC_MULTI_CMD as _, buf.as_mut_ptr() as *mut c_void) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (ioc, cmd) in iocs.iter().zip(cmds.iter_mut()) {
+            cmd.response = ioc.response;
+        }
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.writer()?.write_all_at(buf, offset)
+    }
+
+    fn write_direct_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if self.direct.is_none() && !self.direct_refused {
+            match OpenOptions::new().write(true).custom_flags(libc::O_DIRECT).open(&self.dev_path) {
+                Ok(f) => self.direct = Some(f),
+                // tmpfs and some FUSE files have no O_DIRECT, write through the cache there
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => self.direct_refused = true,
+                Err(e) => return Err(e),
+            }
+        }
+        match &self.direct {
+            Some(f) => f.write_all_at(buf, offset),
+            None => self.write_at(offset, buf),
+        }
+    }
+
+    // metadata().len() is 0 for block devices, seeking to the end gives the real size
+    fn size(&mut self) -> io::Result<u64> {
+        self.file.seek(SeekFrom::End(0))
+    }
+
+    // fsync makes the kernel send a cache flush to the drive, BLKFLSBUF then drops
+    // the page cache buffers so later reads come from the media
+    fn flush(&mut self) -> io::Result<()> {
+        if self.writer.is_none() && self.direct.is_none() {
+            return Ok(());
+        }
+        for f in [&self.writer, &self.direct].into_iter().flatten() {
+            f.sync_all()?;
+        }
+        let ret = unsafe { ioctl(self.file.as_raw_fd(), BLKFLSBUF as _, 0) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // not a block device
+            if e.raw_os_error() != Some(libc::ENOTTY) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert a namespace device path like /dev/nvme0n1 -> controller device /dev/nvme0
+pub fn nvme_ctrl_path(dev_path: &str) -> String {
+    // If device looks like /dev/nvme0n1 or /dev/nvme1n2, strip trailing 'n<digits>'
+    if dev_path.starts_with("/dev/nvme") {
+        // find last 'n' followed by digits
+        if let Some(pos) = dev_path.rfind('n') {
+            let tail = &dev_path[pos+1..];
+            if !tail.is_empty() && tail.chars().all(|c| c.is_ascii_digit()) {
+                return dev_path[..pos].to_string(); // strip the n#
+            }
+        }
+    }
+    // otherwise return input (may already be controller or char device)
+    dev_path.to_string()
+}
+
+// ---------- In-memory backend ----------
+
+type AtaHandler = Box<dyn FnMut(&mut AtaTaskfile, &mut [u8]) -> io::Result<()>>;
+type NvmeHandler = Box<dyn FnMut(&NvmeCommand, &mut [u8]) -> io::Result<u32>>;
+
+/// Transport backed by a `Vec<u8>`. ATA/NVMe commands are answered by optional
+/// handlers (default: unsupported) and every command is recorded in `ata_log`/`nvme_log`.
+pub struct MemoryTransport {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub ata_log: Vec<AtaTaskfile>,
+    pub nvme_log: Vec<NvmeCommand>,
+    ata_handler: Option<AtaHandler>,
+    nvme_handler: Option<NvmeHandler>,
+}
+
+impl MemoryTransport {
+    pub fn new(name: &str, size: usize) -> Self {
+        MemoryTransport {
+            name: name.to_string(),
+            data: vec![0u8; size],
+            ata_log: Vec::new(),
+            nvme_log: Vec::new(),
+            ata_handler: None,
+            nvme_handler: None,
+        }
+    }
+
+    pub fn on_ata(mut self, f: impl FnMut(&mut AtaTaskfile, &mut [u8]) -> io::Result<()> + 'static) -> Self {
+        self.ata_handler = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_nvme(mut self, f: impl FnMut(&NvmeCommand, &mut [u8]) -> io::Result<u32> + 'static) -> Self {
+        self.nvme_handler = Some(Box::new(f));
+        self
+    }
+
+    fn range(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = offset as usize;
+        let end = start.checked_add(len).filter(|e| *e <= self.data.len());
+        match end {
+            Some(end) => Ok(start..end),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "access past end of device")),
+        }
+    }
+}
+
+impl DriveTransport for MemoryTransport {
+    fn path(&self) -> &str {
+        &self.name
+    }
+
+    fn ata_command(&mut self, tf: &mut AtaTaskfile, data: &mut [u8]) -> io::Result<()> {
+        let res = match &mut self.ata_handler {
+            Some(h) => h(tf, data),
+            None => Err(io::Error::new(io::ErrorKind::Unsupported, "no ATA handler")),
+        };
+        self.ata_log.push(tf.clone());
+        res
+    }
+
+    fn nvme_admin(&mut self, cmd: &NvmeCommand, data: &mut [u8]) -> io::Result<u32> {
+        self.nvme_log.push(cmd.clone());
+        match &mut self.nvme_handler {
+            Some(h) => h(cmd, data),
+            None => Err(io::Error::new(io::ErrorKind::Unsupported, "no NVMe handler")),
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let r = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[r]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let r = self.range(offset, buf.len())?;
+        self.data[r].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ata, nvme};
+
+    // space padded to the field width and byte swapped per word, like a drive does it
+    fn put_ata_string(page: &mut [u8], word: usize, words: usize, s: &str) {
+        let mut raw = s.as_bytes().to_vec();
+        raw.resize(words * 2, b' ');
+        for (i, pair) in raw.chunks(2).enumerate() {
+            page[(word + i) * 2] = pair[1];
+            page[(word + i) * 2 + 1] = pair[0];
+        }
+    }
+
+    #[test]
+    fn ata_identify_goes_through_the_handler() {
+        let mut t = MemoryTransport::new("mem", 0).on_ata(|tf, data| {
+            assert_eq!(tf.command, ata::ATA_IDENTIFY_DEVICE);
+            assert_eq!(tf.dir, DataDir::In);
+            assert_eq!(data.len(), 512);
+            put_ata_string(data, 27, 20, "CWE Test Disk");
+            data[83 * 2 + 1] = 1 << 2; // word 83 bit 10, 48-bit addressing
+            data[100 * 2..100 * 2 + 8].copy_from_slice(&1_000_000u64.to_le_bytes());
+            tf.status = 0x50;
+            Ok(())
+        });
+
+        let id = ata::identify_device(&mut t).unwrap();
+        assert_eq!(id.model, "CWE Test Disk");
+        assert_eq!(id.sectors(), 1_000_000);
+        assert_eq!(t.ata_log.len(), 1);
+        assert_eq!(t.ata_log[0].status, 0x50);
+    }
+
+    #[test]
+    fn nvme_get_log_page_goes_through_the_handler() {
+        let mut t = MemoryTransport::new("mem", 0).on_nvme(|cmd, data| {
+            assert_eq!(cmd.opcode, 0x02);
+            assert_eq!(cmd.nsid, 0xFFFF_FFFF);
+            assert_eq!(cmd.cdw10 & 0xFF, 0x81);
+            assert_eq!((cmd.cdw10 >> 16) as usize + 1, data.len() / 4);
+            data[0..2].copy_from_slice(&0x8000u16.to_le_bytes()); // SPROG half way
+            data[2..4].copy_from_slice(&2u16.to_le_bytes()); // SSTAT in progress
+            Ok(0)
+        });
+
+        let log = nvme::sanitize_log(&mut t).unwrap();
+        assert_eq!(log.status, nvme::SanitizeStatus::InProgress);
+        assert_eq!(log.sprog, 0x8000);
+        assert_eq!(t.nvme_log.len(), 1);
+    }
+
+    #[test]
+    fn commands_without_a_handler_are_unsupported_and_still_logged() {
+        let mut t = MemoryTransport::new("mem", 0);
+        let err = ata::identify_device(&mut t).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(t.ata_log.len(), 1);
+        let err = nvme::sanitize_log(&mut t).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(t.nvme_log.len(), 1);
+    }
+
+    #[test]
+    fn reads_and_writes_stay_inside_the_device() {
+        let mut t = MemoryTransport::new("mem", 4096);
+        t.write_at(512, &[0xAB; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        t.read_at(512, &mut buf).unwrap();
+        assert_eq!(buf, [0xAB; 512]);
+        assert_eq!(t.size().unwrap(), 4096);
+        assert!(t.write_at(4000, &[0; 512]).is_err());
+        assert!(t.read_at(u64::MAX, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+// Hotplug watcher: kernel uevents over a NETLINK_KOBJECT_UEVENT socket.
+//
+// Only whole-disk block events are turned into DeviceEvents; partitions, loop
+// devices and the like are dropped. Every add/change comes with a freshly probed
+// `Device`. Kernel events arrive before udev has made the /dev/disk/by-id links,
+// so `by_id` may still be empty on an Added device.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::mpsc;
+use std::thread;
+use crate::device::{probe_block_device, Device};
+use crate::identity::OrgSecret;
+
+// kernel multicast group, udev's rebroadcast is group 2
+const KERNEL_GROUP: u32 = 1;
+const UEVENT_BUFFER: usize = 16 * 1024;
+// socket receive queue, a rack of drives plugged in at once makes a burst of events
+const RECV_QUEUE: libc::c_int = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Added(Device),
+    Removed { dev_path: String },
+    /// Media change, resize, new partition table.
+    Changed(Device),
+    /// The receive queue overflowed and events were lost, enumerate again.
+    Resync,
+}
+
+/// One parsed uevent: `ACTION@DEVPATH\0KEY=VALUE\0...`
+#[derive(Debug, Clone, Default)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub vars: HashMap<String, String>,
+}
+
+impl Uevent {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let mut fields = buf.split(|b| *b == 0).filter(|f| !f.is_empty()).map(String::from_utf8_lossy);
+        let header = fields.next()?;
+        // udev's own messages start with "libudev", we only bind the kernel group
+        let (action, devpath) = header.split_once('@')?;
+        let vars = fields
+            .filter_map(|f| f.split_once('=').map(|(k, v)| (k.to_string(), v.to_string())))
+            .collect();
+        Some(Uevent { action: action.to_string(), devpath: devpath.to_string(), vars })
+    }
+
+    fn var(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(String::as_str)
+    }
+}
+
+pub struct DeviceWatcher {
+    fd: OwnedFd,
+    secret: OrgSecret,
+}
+
+impl DeviceWatcher {
+    pub fn new(secret: OrgSecret) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = KERNEL_GROUP;
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // RCVBUFFORCE goes past rmem_max but needs CAP_NET_ADMIN, plain RCVBUF is capped
+        let size = RECV_QUEUE;
+        let set = |opt| unsafe {
+            libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, opt, &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if set(libc::SO_RCVBUFFORCE) < 0 {
+            set(libc::SO_RCVBUF);
+        }
+        Ok(DeviceWatcher { fd, secret })
+    }
+
+    fn recv(&self) -> io::Result<Uevent> {
+        let mut buf = vec![0u8; UEVENT_BUFFER];
+        loop {
+            let n = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if let Some(ev) = Uevent::parse(&buf[..n as usize]) {
+                return Ok(ev);
+            }
+        }
+    }
+
+    /// Block until the next disk appears, disappears or changes. A full receive
+    /// queue drops events, that comes back as `Resync` and the socket stays usable.
+    pub fn next_event(&mut self) -> io::Result<DeviceEvent> {
+        loop {
+            let ev = match self.recv() {
+                Ok(ev) => ev,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(DeviceEvent::Resync),
+                Err(e) => return Err(e),
+            };
+            if ev.var("SUBSYSTEM") != Some("block") || ev.var("DEVTYPE") != Some("disk") {
+                continue;
+            }
+            let Some(name) = ev.var("DEVNAME").map(|n| n.trim_start_matches("/dev/").to_string()) else { continue };
+            let dev_path = format!("/dev/{}", name);
+
+            match ev.action.as_str() {
+                "remove" => return Ok(DeviceEvent::Removed { dev_path }),
+                "add" => {
+                    if let Some(d) = probe_block_device(&name, &self.secret) {
+                        return Ok(DeviceEvent::Added(d));
+                    }
+                }
+                "change" => match probe_block_device(&name, &self.secret) {
+                    Some(d) => return Ok(DeviceEvent::Changed(d)),
+                    // card pulled out of a reader: the disk stays, the media is gone
+                    None if ev.var("DISK_MEDIA_CHANGE") == Some("1") => return Ok(DeviceEvent::Removed { dev_path }),
+                    None => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Watch on a background thread. The thread ends when the receiver is dropped
+    /// or the socket fails, a socket error is the last thing sent.
+    pub fn spawn(secret: OrgSecret) -> io::Result<mpsc::Receiver<io::Result<DeviceEvent>>> {
+        let mut watcher = DeviceWatcher::new(secret)?;
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new().name("cwe-hotplug".to_string()).spawn(move || {
+            loop {
+                let ev = watcher.next_event();
+                let failed = ev.is_err();
+                if tx.send(ev).is_err() || failed {
+                    break;
+                }
+            }
+        })?;
+        Ok(rx)
+    }
+}
@@ -1,336 +1,599 @@
 use std::io;
-use std::io::{Seek,Write};
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::os::unix::io::AsRawFd;
-use libc::{c_void, ioctl};
-use rand::Rng;
-use std::time::Instant;
-use std::time::Duration;
-use std::thread;
+use crate::ata;
+use crate::capability::{self, Mechanism, NistLevel};
 use crate::device;
+use crate::evidence::WipeEvidence;
+use crate::health;
+use crate::partition;
+use crate::plan::{self, PrepStep, WipeMethod};
+use crate::progress::{ProgressReporter, WipePhase};
+use crate::resume;
+use crate::mmc;
+use crate::nvme::{self, NvmeFormat, NvmeSanitize, SanitizeAction, SecureErase};
+use crate::overwrite::{self, KeyStream};
+use crate::safety::{self, SafetyReport, WipeOptions};
+use crate::scsi;
+use crate::sysfs;
+use crate::tcg;
+use crate::transport::{DriveTransport, LinuxTransport, NvmeCommand};
+
+
+pub fn wipe_device(dev: &mut device::Device) -> io::Result<WipeEvidence> { // the main wipe routine
+    wipe_device_opts(dev, &WipeOptions::default())
+}
 
+/// `wipe_device` with explicit options, e.g. `force` to override the safety preflight.
+pub fn wipe_device_opts(dev: &mut device::Device, opts: &WipeOptions) -> io::Result<WipeEvidence> {
+    // refuse before the disk is even opened
+    let safety = safety::enforce(&dev.dev_path, opts)?;
+    let mut t = LinuxTransport::open_device(dev)?;
+    run_wipe(dev, &mut t, opts, &safety)
+}
 
-pub fn wipe_device(dev: &mut device::Device) -> io::Result<()> { // the main wipe routine
-    if dev.firmsan {
-        match firmware_erase(dev) {
-            Ok(_) => {
-                println!("Firmware sanitize completed on {}", dev.dev_path);
-                return Ok(());
+/// Same as `wipe_device_opts` but runs every command through the given transport.
+pub fn wipe_device_with(dev: &mut device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions) -> io::Result<WipeEvidence> {
+    let safety = safety::enforce(t.path(), opts)?;
+    run_wipe(dev, t, opts, &safety)
+}
+
+fn run_wipe(dev: &mut device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, safety: &SafetyReport) -> io::Result<WipeEvidence> {
+    let plan = plan::plan_for(dev);
+    let mut ev = WipeEvidence::new(&dev.id, &dev.dev_path, "", "");
+    log_overrides(&mut ev, safety);
+    ev.plan = Some(plan.clone());
+    ev.health_pre = Some(health_snapshot(dev, t, &mut ev));
+
+    // never start over a sanitize that is still running, see it through instead
+    if let Ok(Some(report)) = resume::query(t, &dev.devtype)
+        && report.in_progress()
+    {
+        ev.log(format!("{} on {}, attaching to it", report.summary(), dev.dev_path));
+        opts.progress.phase(&dev.dev_path, WipePhase::ResumedSanitize, None);
+        resume::attach(t, &dev.devtype, 48 * 3600, &opts.progress)?;
+        ev.resumed_sanitize = Some(report);
+        ev.method = "Firmware sanitize (resumed)".to_string();
+        ev.nist_level = "Purge".to_string();
+        ev.log(format!("Resumed sanitize completed on {}", dev.dev_path));
+        ev.health_post = Some(health_snapshot(dev, t, &mut ev));
+        ev.finish();
+        opts.progress.done();
+        return Ok(ev);
+    }
+
+    match partition::read_inventory(t) {
+        Ok(inv) => {
+            ev.log(format!("Found on {}: {}", dev.dev_path, inv.summary()));
+            ev.partitions = Some(inv);
+        }
+        Err(e) => ev.log(format!("Could not read partition table on {}: {}", dev.dev_path, e)),
+    }
+    // rediscover, the locking state may have changed since the device was probed
+    if dev.sed.is_some() {
+        match tcg::discover(t, &dev.devtype) {
+            Ok(sed) => {
+                ev.log(format!("SED on {}: {}", dev.dev_path, sed.summary()));
+                ev.sed = Some(sed);
             }
-            Err(e) => println!("Firmware erase failed: {}. Falling back.", e),
+            Err(e) => ev.log(format!("TCG discovery on {} failed: {}", dev.dev_path, e)),
         }
     }
 
-    if try_crypto_purge(dev)? {
-        println!("Crypto purge completed on {}", dev.dev_path);
-        return Ok(());
+    for step in &plan.prepare {
+        match step {
+            PrepStep::RestoreNativeCapacity => restore_native_capacity(dev, t, &mut ev)?,
+        }
     }
 
-    println!("Falling back to software overwrite on {}", dev.dev_path);
-    disk_clean(&dev.dev_path)?;
-
-    Ok(())
+    let mut last_err = io::Error::other("no wipe method in the plan");
+    for method in &plan.methods {
+        // each method answers with the mechanism it actually ran, None when it had none
+        let (name, result) = match method {
+            WipeMethod::FirmwareErase => ("Firmware sanitize", firmware_erase(dev, t, opts, &mut ev).map(Some)),
+            WipeMethod::NvmeFormatCrypto => ("NVMe format, crypto erase", nvme_format(dev, t, SecureErase::Crypto, opts, &mut ev)),
+            WipeMethod::TcgCryptoErase => ("TCG crypto erase", tcg_crypto_erase(dev, t, opts, &mut ev)),
+            WipeMethod::CryptoPurge => ("Crypto purge", try_crypto_purge(dev, t, opts, &mut ev)),
+            WipeMethod::NvmeFormatUserData => ("NVMe format, user data erase", nvme_format(dev, t, SecureErase::UserData, opts, &mut ev)),
+            WipeMethod::ScsiFormatUnit => ("SCSI format unit", scsi_format_unit(dev, t, opts, &mut ev)),
+            WipeMethod::Overwrite => ("Software overwrite", disk_clean(dev, t, opts, &mut ev).map(|_| Some(Mechanism::Overwrite))),
+        };
+        match result {
+            Ok(Some(mechanism)) => {
+                ev.method = mechanism.to_string();
+                ev.nist_level = nist_level(dev, t, mechanism).to_string();
+                ev.log(format!("{} completed on {}", mechanism, dev.dev_path));
+                ev.health_post = Some(health_snapshot(dev, t, &mut ev));
+                ev.finish();
+                opts.progress.done();
+                return Ok(ev);
+            }
+            Ok(None) => ev.log(format!("{} not available on {}", name, dev.dev_path)),
+            Err(e) => {
+                ev.log(format!("{} failed: {}. Falling back.", name, e));
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
 }
 
-fn firmware_erase(dev: &device::Device) -> io::Result<()> {
-    let file = File::open(&dev.dev_path)?;
-    let fd = file.as_raw_fd();
+/// PSID revert with the PSID from the drive label, for SEDs whose owner password is
+/// gone. Every SP goes back to factory state and the media keys are replaced, so on
+/// a drive with media encryption the old data is unreadable. Refuses in-use disks
+/// like a wipe does.
+pub fn psid_revert_with(dev: &device::Device, t: &mut dyn DriveTransport, psid: &str, opts: &WipeOptions) -> io::Result<WipeEvidence> {
+    let safety = safety::enforce(t.path(), opts)?;
+    let mut ev = WipeEvidence::new(&dev.id, &dev.dev_path, "TCG PSID revert", "");
+    log_overrides(&mut ev, &safety);
+    let sed = tcg::discover(t, &dev.devtype)?;
+    ev.log(format!("SED on {}: {}", dev.dev_path, sed.summary()));
+
+    opts.progress.phase(&dev.dev_path, WipePhase::CryptoErase, None);
+    tcg::psid_revert(t, &dev.devtype, &sed, psid).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => io::Error::new(e.kind(), format!("PSID refused ({}), check it against the drive label", e)),
+        _ => e,
+    })?;
+    ev.log(format!("PSID revert of {} done, the TPer is back in factory state", dev.dev_path));
+
+    // a revert without media encryption resets the locking, the data stays put
+    let level = if sed.media_encryption { NistLevel::Purge } else { NistLevel::None };
+    let mut after = match tcg::discover(t, &dev.devtype) {
+        Ok(after) => after,
+        Err(e) => {
+            ev.log(format!("TCG discovery on {} after the revert failed: {}", dev.dev_path, e));
+            sed
+        }
+    };
+    after.psid_reverted = true;
+    ev.log(format!("SED on {} now: {}", dev.dev_path, after.summary()));
+    ev.sed = Some(after);
+    ev.nist_level = level.to_string();
+    ev.finish();
+    opts.progress.done();
+    Ok(ev)
+}
 
-    match dev.devtype {
-        device::DeviceType::Sata => ata_secure_erase(fd, "ERASEPWD"),
-        device::DeviceType::Nvme => nvme_sanitize(fd, 3), // use crypto erase
-        _ => Err(io::Error::new(io::ErrorKind::Other, "Unsupported device type")),
+// Whatever the preflight found and `force` let through goes on the record.
+fn log_overrides(ev: &mut WipeEvidence, safety: &SafetyReport) {
+    for issue in &safety.issues {
+        ev.log(format!("WARNING: {} (overridden with force)", issue));
     }
 }
 
+// The level the capability matrix gives what ran, it knows security erase is only a
+// Clear on an SSD. Mechanisms it doesn't list are taken as Clear.
+fn nist_level(dev: &mut device::Device, t: &mut dyn DriveTransport, mechanism: Mechanism) -> NistLevel {
+    if dev.capabilities.capabilities.is_empty() {
+        dev.capabilities = capability::assess(dev, t);
+    }
+    dev.capabilities.get(mechanism).map_or(NistLevel::Clear, |c| c.nist_level)
+}
 
-fn try_crypto_purge(dev: &device::Device) -> io::Result<bool> {
-    match dev.devtype {
-        device::DeviceType::Nvme => {
-            println!("Attempting NVMe crypto erase on {}", dev.dev_path);
+fn health_snapshot(dev: &device::Device, t: &mut dyn DriveTransport, ev: &mut WipeEvidence) -> health::HealthSnapshot {
+    let snap = health::snapshot(dev, t);
+    for note in &snap.notes {
+        ev.log(format!("Health: {}", note));
+    }
+    snap
+}
 
-            match nvme_crypto_purge("/dev/nvme0n1", 3600) {
-                Ok(_) => println!("NVMe crypto purge complete"),
-                Err(e) => eprintln!("NVMe purge failed: {}", e),
+// Anything behind an HPA or DCO is invisible to the overwrite and may be skipped by
+// the firmware erase too, so it has to be made addressable before either runs.
+fn restore_native_capacity(dev: &mut device::Device, t: &mut dyn DriveTransport, ev: &mut WipeEvidence) -> io::Result<()> {
+    let id = match &dev.ata {
+        Some(id) => id.clone(),
+        None => match ata::identify_device(t) {
+            Ok(id) => id,
+            Err(e) => {
+                ev.log(format!("Could not check for HPA/DCO on {}: {}", dev.dev_path, e));
+                return Ok(());
             }
+        },
+    };
 
-            Ok(true)
-        }
-        device::DeviceType::Sata => {
-            println!("Attempting ATA crypto purge on {}", dev.dev_path);
+    let mut area = ata::detect_hidden_area(t, &id);
+    for note in &area.notes {
+        ev.log(note.clone());
+    }
+    let hidden = area.hidden_sectors();
+    if hidden == 0 {
+        ev.hidden_area = Some(area);
+        return Ok(());
+    }
 
-            match ata_crypto_purge("/dev/sda", "ERASEPWD") {
-                Ok(_) => println!("ATA secure erase triggered"),
-                Err(e) => eprintln!("ATA purge failed: {}", e),
-            }
+    ev.log(format!("{} sectors hidden on {} (HPA {}, DCO {}), restoring native capacity",
+        hidden, dev.dev_path, area.hpa_hidden(), area.dco_hidden()));
+    let res = ata::restore_native_capacity(t, &id, &mut area);
+    ev.hidden_area = Some(area);
+    res.map_err(|e| io::Error::other(format!(
+        "could not restore native capacity, {} sectors would stay unwiped: {}", hidden, e)))?;
 
-            // for many SATA SEDs, normal secure erase == crypto purge
-            Ok(false) // only return true if known supported
-        }
-        _ => Ok(false),
+    if dev.dev_path.starts_with("/dev/")
+        && let Err(e) = sysfs::rescan(&dev.dev_path)
+    {
+        ev.log(format!("Capacity rescan of {} failed: {}", dev.dev_path, e));
     }
+    if let Ok(id) = ata::identify_device(t) {
+        ev.log(format!("{} now reports {} sectors", dev.dev_path, id.sectors()));
+        dev.ata = Some(id);
+    }
+    Ok(())
 }
 
-fn disk_clean(dev_path: &str) -> io::Result<()> {
-    let mut f = OpenOptions::new()
-        .write(true)
-        .open(dev_path)?;
-
-    let size = f.metadata()?.len();
-
-    let mut buf = vec![0u8; 1024 * 1024]; // 1 MB buffer
-    let mut rng = rand::thread_rng();
+// Answers with the mechanism that ran, the evidence takes its NIST level from that.
+fn firmware_erase(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Mechanism> {
+    match dev.devtype {
+        device::DeviceType::Sata => ata_firmware_erase(dev, t, opts, ev),
+        device::DeviceType::Nvme => nvme_firmware_sanitize(dev, t, opts, ev),
+        device::DeviceType::Scsi => scsi_sanitize(dev, t, &opts.progress, ev),
+        device::DeviceType::Mmc => mmc_sanitize(dev, t, &opts.progress, ev),
+        _ => Err(io::Error::other("Unsupported device type")),
+    }
+}
 
-    for offset in (0..size).step_by(buf.len()) {
-        rng.fill(&mut buf[..]);
-        f.seek(io::SeekFrom::Start(offset))?;
-        f.write_all(&buf)?;
+// The caller's sanitize options if given, otherwise the strongest action the controller
+// advertises in SANICAP. Waits for the sanitize to finish.
+fn nvme_firmware_sanitize(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Mechanism> {
+    let id = match &dev.nvme {
+        Some(id) => id.clone(),
+        None => nvme::identify_controller(t)?,
+    };
+    let op = match &opts.nvme_sanitize {
+        Some(op) if op.action == SanitizeAction::ExitFailureMode =>
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Exit Failure Mode does not erase anything")),
+        Some(op) => op.clone(),
+        None => match id.preferred_sanitize() {
+            Some(action) => NvmeSanitize::new(action),
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported, "controller reports no sanitize actions")),
+        },
+    };
+    op.check(&id)?;
+    let mechanism = match op.action {
+        SanitizeAction::CryptoErase => Mechanism::NvmeSanitizeCrypto,
+        SanitizeAction::BlockErase => Mechanism::NvmeSanitizeBlock,
+        _ => Mechanism::NvmeSanitizeOverwrite,
+    };
+    if op.no_deallocate {
+        if id.sanicap.no_dealloc_inhibited {
+            ev.log(format!("{} ignores No-Deallocate After Sanitize, the media will be deallocated", dev.dev_path));
+        } else if id.sanicap.nodmmas == 2 {
+            ev.log(format!("{} writes the media again after sanitize to honour No-Deallocate", dev.dev_path));
+        }
     }
 
-    f.flush()?;
-    Ok(())
+    report_controller_scope(t, "sanitize");
+    ev.log(format!("Issuing NVMe sanitize on {}: {:?}", dev.dev_path, op));
+    ev.nvme_sanitize = Some(op.clone());
+    opts.progress.phase(&dev.dev_path, WipePhase::Sanitize, None);
+    nvme_sanitize_wait(t, &op, 48 * 3600, &opts.progress)?;
+    Ok(mechanism)
 }
 
-fn nvme_sanitize(fd: i32, action: u32) -> io::Result<()> {
-    let mut cmd = device::nvme_admin_cmd {
-        opcode: 0x84, // SANITIZE
-        flags: 0,
-        rsvd1: 0,
-        nsid: 0,
-        cdw2: 0,
-        cdw3: 0,
-        metadata: 0,
-        addr: 0,
-        metadata_len: 0,
-        data_len: 0,
-        cdw10: action, // 1=overwrite, 2=block, 3=crypto
-        cdw11: 0,
-        cdw12: 0,
-        cdw13: 0,
-        cdw14: 0,
-        cdw15: 0,
-        timeout_ms: 0,
-        result: 0,
+// Try the SANITIZE service actions strongest first; a device that doesn't implement
+// one answers ILLEGAL REQUEST straight away and the next one is tried.
+fn scsi_sanitize(dev: &device::Device, t: &mut dyn DriveTransport, progress: &ProgressReporter, ev: &mut WipeEvidence) -> io::Result<Mechanism> {
+    let support = match &dev.scsi {
+        Some(info) => info.sanitize.clone(),
+        None => scsi::sanitize_support(t),
     };
 
-    let ret = unsafe { ioctl(fd, device::NVME_IOCTL_ADMIN_CMD, &mut cmd) };
-    if ret < 0 {
-        return Err(io::Error::last_os_error());
+    let mut last_err = io::Error::new(io::ErrorKind::Unsupported, "device reports no SANITIZE actions");
+    for action in support.candidates() {
+        ev.log(format!("Issuing SCSI SANITIZE {:?} on {}", action, dev.dev_path));
+        progress.phase(&dev.dev_path, WipePhase::Sanitize, None);
+        match scsi::sanitize(t, action, &[]) {
+            Ok(()) => {
+                scsi::wait_for_completion(t, 48 * 3600, progress)?;
+                return Ok(match action {
+                    scsi::ScsiSanitizeAction::CryptoErase => Mechanism::ScsiSanitizeCrypto,
+                    scsi::ScsiSanitizeAction::BlockErase => Mechanism::ScsiSanitizeBlock,
+                    _ => Mechanism::ScsiSanitizeOverwrite,
+                });
+            }
+            Err(e) => {
+                ev.log(format!("SCSI SANITIZE {:?} rejected: {}", action, e));
+                last_err = e;
+            }
+        }
     }
-
-    Ok(())
+    Err(last_err)
 }
 
-fn ata_secure_erase(fd: i32, password: &str) -> io::Result<()> {
-    let mut args: [u8; 4 + 512] = [0; 4 + 512];
-
-    // SECURITY_SET_PASSWORD
-    args[0] = 0xF1;
-    args[1] = 0; // sector count
-    let pwd_bytes = password.as_bytes();
-    args[4..4+pwd_bytes.len()].copy_from_slice(pwd_bytes);
+// FORMAT UNIT without a defect list, polled like a sanitize. What's left to a SCSI
+// disk when SANITIZE is missing or rejected.
+fn scsi_format_unit(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Option<Mechanism>> {
+    if dev.devtype != device::DeviceType::Scsi {
+        return Ok(None);
+    }
+    ev.log(format!("Issuing SCSI FORMAT UNIT on {}", dev.dev_path));
+    opts.progress.phase(&dev.dev_path, WipePhase::Format, None);
+    scsi::format_unit(t)?;
+    scsi::wait_for_completion(t, 48 * 3600, &opts.progress)?;
+    Ok(Some(Mechanism::ScsiFormatUnit))
+}
 
-    let ret = unsafe { ioctl(fd, device::HDIO_DRIVE_CMD as _, args.as_mut_ptr() as *mut c_void) };
-    if ret < 0 { return Err(io::Error::last_os_error()); }
+// Same idea for eMMC: sanitize, then secure erase, then secure trim. Plain ERASE
+// alone isn't a purge, that case is left to the overwrite.
+fn mmc_sanitize(dev: &device::Device, t: &mut dyn DriveTransport, progress: &ProgressReporter, ev: &mut WipeEvidence) -> io::Result<Mechanism> {
+    let info = match &dev.mmc {
+        Some(info) => info.clone(),
+        None => {
+            let card_type = mmc::card_type(&dev.dev_path).unwrap_or(mmc::MmcCardType::Mmc);
+            mmc::probe(t, card_type)?
+        }
+    };
+    ev.log(format!("NOTE: {} boot partitions and RPMB are not touched by the user area erase", dev.dev_path));
+
+    let mut last_err = io::Error::new(io::ErrorKind::Unsupported, "card reports no sanitize or secure erase");
+    for method in info.methods().into_iter().filter(|m| *m != mmc::MmcErase::Erase) {
+        ev.log(format!("Issuing eMMC {:?} on {}", method, dev.dev_path));
+        progress.phase(&dev.dev_path, WipePhase::Sanitize, None);
+        match mmc::purge(t, &info, method) {
+            Ok(()) => return Ok(match method {
+                mmc::MmcErase::Sanitize => Mechanism::MmcSanitize,
+                mmc::MmcErase::SecureErase => Mechanism::MmcSecureErase,
+                mmc::MmcErase::SecureTrim => Mechanism::MmcSecureTrim,
+                mmc::MmcErase::Erase => Mechanism::MmcErase,
+            }),
+            Err(e) => {
+                ev.log(format!("eMMC {:?} failed: {}", method, e));
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
 
-    // SECURITY_ERASE_UNIT
-    let mut args: [u8; 4 + 512] = [0; 4 + 512];
-    args[0] = 0xF4; // erase
-    let ret = unsafe { ioctl(fd, device::HDIO_DRIVE_CMD as _, args.as_mut_ptr() as *mut c_void) };
-    if ret < 0 { return Err(io::Error::last_os_error()); }
+// Format NVM with a secure erase, for controllers without (working) sanitize. The
+// namespace keeps its current LBA format. FNA decides the scope: if the controller
+// formats or erases all namespaces together, all of them are addressed explicitly.
+fn nvme_format(dev: &device::Device, t: &mut dyn DriveTransport, ses: SecureErase, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Option<Mechanism>> {
+    let id = match &dev.nvme {
+        Some(id) => id.clone(),
+        None => nvme::identify_controller(t)?,
+    };
+    if !id.oacs.format_nvm || (ses == SecureErase::Crypto && !id.fna.crypto_erase_supported) {
+        return Ok(None);
+    }
+    let namespaces = match dev.nvme_namespaces.is_empty() {
+        true => nvme::scan_controller(t)?.namespaces,
+        false => dev.nvme_namespaces.clone(),
+    };
+    // never fall back to some other namespace, the format would hit the wrong one
+    let nsid = t.nvme_nsid().ok();
+    let ns = namespaces.iter()
+        .find(|n| n.block_device.as_deref() == Some(dev.dev_path.as_str()))
+        .or_else(|| namespaces.iter().find(|n| Some(n.nsid) == nsid))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!(
+            "{} matches none of the controller's {} active namespace(s)", dev.dev_path, namespaces.len())))?;
+
+    let lba_format = opts.nvme_lba_format.unwrap_or(ns.lba_format_index);
+    if ns.lba_formats.get(lba_format as usize).is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "namespace {} has no LBA format {}, it supports {}", ns.nsid, lba_format, ns.lba_formats.len())));
+    }
 
-    Ok(())
+    let all = match ses {
+        SecureErase::None => id.fna.format_all_namespaces,
+        _ => id.fna.format_all_namespaces || id.fna.erase_all_namespaces,
+    };
+    if all {
+        report_controller_scope(t, "format");
+        // the other namespaces get the same LBA format, only when asked for
+        for other in namespaces.iter().filter(|n| n.nsid != ns.nsid && n.lba_format_index != lba_format) {
+            if opts.nvme_lba_format.is_none() || other.lba_formats.get(lba_format as usize).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "the format covers every namespace and namespace {} uses LBA format {}, not {}",
+                    other.nsid, other.lba_format_index, lba_format)));
+            }
+            ev.log(format!("Namespace {} goes from LBA format {} to {}", other.nsid, other.lba_format_index, lba_format));
+        }
+    }
+    let format = NvmeFormat { nsid: if all { nvme::NVME_NSID_ALL } else { ns.nsid }, lba_format, ses };
+    ev.log(format!("Issuing NVMe Format NVM {:?} on {} (nsid 0x{:x}, LBA format {})", ses, dev.dev_path, format.nsid, format.lba_format));
+    opts.progress.phase(&dev.dev_path, WipePhase::Format, None);
+    nvme::format_nvm(t, &format)?;
+
+    // the command only completes when the format is done, check it took
+    let after = nvme::identify_namespace(t, ns.nsid)?;
+    if after.lba_format_index != format.lba_format {
+        return Err(io::Error::other(format!("namespace {} reports LBA format {} after formatting to {}",
+            ns.nsid, after.lba_format_index, format.lba_format)));
+    }
+    ev.nvme_format = Some(format);
+    Ok(Some(match ses {
+        SecureErase::Crypto => Mechanism::NvmeFormatCrypto,
+        _ => Mechanism::NvmeFormatUserData,
+    }))
 }
 
-/// ---------- ATA (SATA) crypto purge via SECURITY_SET_PASSWORD + SECURITY_ERASE_UNIT ----------
-pub fn ata_crypto_purge(dev_path: &str, password: &str) -> io::Result<()> {
-    // open block device
-    let file = File::open(dev_path)?;
-    let fd = file.as_raw_fd();
-
-    // Build 4 + 512 buffer as used by HDIO_DRIVE_CMD (first 4 bytes are header)
-    // SECURITY_SET_PASSWORD (0xF1)
-    {
-        let mut args: [u8; 4 + 512] = [0; 4 + 512];
-        args[0] = 0xF1u8; // SECURITY_SET_PASSWORD
+// Key erase through the TCG security subsystem with the factory credentials. An
+// unowned Opal drive gets its Locking SP activated first so there are range keys
+// to regenerate, RevertSP afterwards leaves it unowned again. Enterprise drives
+// erase every band as EraseMaster.
+fn tcg_crypto_erase(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Option<Mechanism>> {
+    let sed = match ev.sed.clone().or_else(|| dev.sed.clone()) {
+        Some(sed) => sed,
+        None => return Ok(None),
+    };
+    if !sed.media_encryption {
+        return Ok(None);
+    }
+    opts.progress.phase(&dev.dev_path, WipePhase::CryptoErase, None);
+    let devtype = &dev.devtype;
+    let msid = tcg::read_msid(t, devtype, &sed)?;
+    let not_msid = |e: io::Error| match e.kind() {
+        io::ErrorKind::PermissionDenied => io::Error::new(e.kind(), format!(
+            "factory password refused ({}), the owner's password or a PSID revert is needed", e)),
+        _ => e,
+    };
 
-        // password length — ATA password area varies; we copy at offset 4, smallest common subset
-        let pwd_bytes = password.as_bytes();
-        let max_pwd = 32.min(pwd_bytes.len()); // don't overflow; typical ATA password field is 32 bytes
-        args[4..4 + max_pwd].copy_from_slice(&pwd_bytes[..max_pwd]);
+    let ranges = if sed.is_enterprise() {
+        let bands = tcg::enterprise_erase(t, devtype, &sed, &msid).map_err(not_msid)?;
+        ev.log(format!("Erased {} band(s) on {}", bands.len(), dev.dev_path));
+        bands
+    } else {
+        if !sed.locking_enabled {
+            ev.log(format!("Activating the Locking SP on {} with the factory SID", dev.dev_path));
+            tcg::activate_locking_sp(t, devtype, &sed, &msid).map_err(not_msid)?;
+        }
+        let ranges = tcg::regenerate_range_keys(t, devtype, &sed, &msid).map_err(not_msid)?;
+        ev.log(format!("Regenerated the keys of {} locking range(s) on {}", ranges.len(), dev.dev_path));
+        tcg::revert_locking_sp(t, devtype, &sed, &msid)?;
+        ev.log(format!("Locking SP on {} reverted to factory state", dev.dev_path));
+        ranges
+    };
+    if let Some(sed) = &mut ev.sed {
+        sed.ranges = ranges;
+    }
+    Ok(Some(Mechanism::TcgCryptoErase))
+}
 
-        let ret = unsafe {
-            ioctl(fd, device::HDIO_DRIVE_CMD as _, args.as_mut_ptr() as *mut c_void)
-        };
-        if ret < 0 {
-            return Err(io::Error::last_os_error());
+fn try_crypto_purge(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Option<Mechanism>> {
+    match dev.devtype {
+        device::DeviceType::Nvme => {
+            if dev.nvme.as_ref().is_some_and(|id| !id.sanicap.crypto_erase) {
+                return Ok(None);
+            }
+            report_controller_scope(t, "sanitize");
+            ev.log(format!("Attempting NVMe crypto erase on {}", dev.dev_path));
+
+            let op = NvmeSanitize::new(SanitizeAction::CryptoErase);
+            ev.nvme_sanitize = Some(op.clone());
+            opts.progress.phase(&dev.dev_path, WipePhase::CryptoErase, None);
+            // a sanitize went out, a failure is a failure and not "not available"
+            nvme_sanitize_wait(t, &op, 3600, &opts.progress)?;
+            ev.log(format!("NVMe crypto purge complete on {}", dev.dev_path));
+            Ok(Some(Mechanism::NvmeSanitizeCrypto))
         }
+        device::DeviceType::Sata => {
+            // on SATA SEDs the enhanced erase is the key change
+            let enhanced = match &dev.ata {
+                Some(id) => id.security.enhanced_erase_supported,
+                None => ata::identify_device(t)?.security.enhanced_erase_supported,
+            };
+            if !enhanced {
+                return Ok(None);
+            }
+            ev.log(format!("Attempting ATA enhanced security erase on {}", dev.dev_path));
+            ata_security_erase(dev, t, Some(ata::SecurityEraseMode::Enhanced), opts, ev).map(Some)
+        }
+        _ => Ok(None),
     }
+}
 
-    // SECURITY_ERASE_UNIT (0xF4) - this will start the secure erase (may be async)
-    {
-        let mut args: [u8; 4 + 512] = [0; 4 + 512];
-        args[0] = 0xF4u8; // SECURITY_ERASE_UNIT
-        // Optionally set feature bits in args[1..], vendor-specific - keeping defaults (0).
-        let ret = unsafe {
-            ioctl(fd, device::HDIO_DRIVE_CMD as _, args.as_mut_ptr() as *mut c_void)
-        };
-        if ret < 0 {
-            return Err(io::Error::last_os_error());
+// NVMe sanitize acts on the whole controller, not the namespace we were pointed at,
+// and so does a format when FNA says so. Spell out everything that is about to go.
+fn report_controller_scope(t: &mut dyn DriveTransport, what: &str) {
+    match nvme::scan_controller(t) {
+        Ok(ctrl) => {
+            println!("WARNING: {} on {} destroys {} namespace(s):", what, ctrl.ctrl_path, ctrl.namespaces.len());
+            for ns in &ctrl.namespaces {
+                println!("    nsid {} {} size {} bytes, {} bytes in use, {} byte blocks",
+                    ns.nsid,
+                    ns.block_device.as_deref().unwrap_or("-"),
+                    ns.size_bytes(),
+                    ns.in_use_bytes(),
+                    ns.block_size());
+            }
         }
+        Err(e) => println!("WARNING: could not list namespaces ({}), {} still destroys every namespace on the controller", e, what),
     }
+}
 
-    // At this point the drive should begin the erase (may be time-consuming).
-    // Many drives do this synchronously at firmware level (or in background). There's
-    // no portable way here to poll ATA sanitize progress conveniently — userspace tools
-    // like hdparm often rely on subsequent IDENTIFY to check status or use vendor-specific logs.
+// One pass of random data over everything the host can address, see `overwrite`.
+fn disk_clean(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<()> {
+    let stats = overwrite::overwrite(t, dev.logical_block_size, &KeyStream::random(), &opts.progress)?;
+    ev.log(format!("Overwrote {} bytes on {} in {:.0} s, {:.1} MiB/s ({} byte blocks, {} KiB chunks, {} workers)",
+        stats.bytes, dev.dev_path, stats.elapsed.as_secs_f64(), stats.throughput() / (1024.0 * 1024.0),
+        stats.block_size, stats.chunk_size / 1024, stats.workers));
     Ok(())
 }
 
-/// ---------- NVMe crypto purge (SANITIZE action=3) + polling of Sanitize Status log page ----------
-pub fn nvme_crypto_purge(dev_path: &str, timeout_secs: u64) -> io::Result<()> {
-    // NVMe admin ioctl usually expects controller character device (e.g., /dev/nvme0).
-    let ctrl_path = nvme_ctrl_path(dev_path);
-    let file = File::open(&ctrl_path)?;
-    let fd = file.as_raw_fd();
-
-    // Issue SANITIZE with action = 3 (Crypto Erase)
-    {
-        let mut cmd = device::nvme_admin_cmd {
-            opcode: 0x84, // SANITIZE
-            flags: 0,
-            rsvd1: 0,
-            nsid: 0, // controller-level sanitize
-            cdw2: 0,
-            cdw3: 0,
-            metadata: 0,
-            addr: 0,
-            metadata_len: 0,
-            data_len: 0,
-            cdw10: 3, // action = 3 (crypto erase)
-            cdw11: 0,
-            cdw12: 0,
-            cdw13: 0,
-            cdw14: 0,
-            cdw15: 0,
-            timeout_ms: 0,
-            result: 0,
-        };
-
-        let ret = unsafe { ioctl(fd, device::NVME_IOCTL_ADMIN_CMD, &mut cmd) };
-        if ret < 0 {
-            return Err(io::Error::last_os_error());
+// SANITIZE when the drive has it (or the caller asked for one), it needs no password
+// and can't leave the drive locked. Security erase otherwise.
+fn ata_firmware_erase(dev: &device::Device, t: &mut dyn DriveTransport, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Mechanism> {
+    let id = match &dev.ata {
+        Some(id) => id.clone(),
+        None => ata::identify_device(t)?,
+    };
+    let cmd = match &opts.ata_sanitize {
+        Some(cmd) => cmd.clone(),
+        None => match id.preferred_sanitize() {
+            Some(action) => ata::AtaSanitizeCommand::new(action),
+            None => return ata_security_erase(dev, t, None, opts, ev),
+        },
+    };
+    cmd.check(&id)?;
+    if id.sanitize.antifreeze_lock && !id.sanitize.antifreeze_locked {
+        // keep anything else from freezing the drive under us
+        if let Err(e) = ata::sanitize_antifreeze_lock(t) {
+            ev.log(format!("SANITIZE ANTIFREEZE LOCK on {} failed: {}", dev.dev_path, e));
         }
     }
+    ev.log(format!("Issuing ATA sanitize on {}: {:?}", dev.dev_path, cmd));
+    ev.ata_sanitize = Some(cmd.clone());
+    opts.progress.phase(&dev.dev_path, WipePhase::Sanitize, None);
+    ata::sanitize(t, &cmd)?;
+    ata::sanitize_wait(t, 48 * 3600, &opts.progress)?;
+    Ok(match cmd.action {
+        ata::AtaSanitizeAction::CryptoScramble => Mechanism::AtaSanitizeCrypto,
+        ata::AtaSanitizeAction::BlockErase => Mechanism::AtaSanitizeBlock,
+        ata::AtaSanitizeAction::Overwrite => Mechanism::AtaSanitizeOverwrite,
+    })
+}
 
-    // Poll Sanitize Status log page (0x81). We'll read 512 bytes and parse sprog (u16 at 0)
-    // and sstat (u16 at offset 2). SPROG==0xFFFF (65535) usually means finished (see spec).
-    let start = Instant::now();
-    let timeout = Duration::from_secs(timeout_secs);
-    loop {
-        // read log page 0x81 into buffer
-        let mut buf = vec![0u8; 512];
-        get_nvme_log_page(fd, 0x81, &mut buf)?;
-
-        // parse SPROG and SSTAT (both little-endian)
-        if buf.len() >= 4 {
-            let sprog = u16::from_le_bytes([buf[0], buf[1]]);
-            let sstat = u16::from_le_bytes([buf[2], buf[3]]);
-
-            // Debug print (optional)
-            println!("nvme sanitize log: SPROG={} SSTAT=0x{:x}", sprog, sstat);
-
-            // SPROG is 16-bit numerator over 65536; spec says set to 0xFFFF if not IN_PROGRESS.
-            // In practice: sprog == 0xFFFF or sprog == 65535 indicates completion (100%).
-            if sprog == 0xFFFF || sprog == 65535 {
-                // Completed. Now check SSTAT for success/failure.
-                // The SSTAT contains flags & status; highest-level detection varies by implementation.
-                // We'll treat 0x0 or low bit success codes as success; otherwise return error.
-                // (For robust handling you may mask with NVME_SANITIZE_SSTAT_STATUS_MASK from headers)
-                // Many tools print SSTAT like 0x101 on success. We'll treat any non-zero as success
-                // if lower 3 bits indicate COMPLETE_SUCCESS — but to keep portable we'll accept common cases:
-                let status_masked = sstat; // real implementation: mask with NVME_SANITIZE_SSTAT_STATUS_MASK
-                // heuristics: if masked == 0 (never sanitized) or indicates fail -> compute accordingly.
-                // We'll assume completion is OK unless SSTAT explicitly indicates failure code. Some controllers
-                // use values > 0 to indicate success (e.g., 0x101). So treat SSTAT == 0x2xx (failed) as failure.
-                // Simple heuristic:
-                if (status_masked & 0x0FF) == 0x2 {
-                    return Err(io::Error::new(io::ErrorKind::Other, format!("NVMe sanitize reported failure sstat=0x{:x}", sstat)));
-                } else {
-                    println!("NVMe sanitize/crypto-erase complete: sstat=0x{:x}", sstat);
-                    return Ok(());
-                }
-            }
-
-            // If still in progress, continue to poll until timeout
-        }
+// SECURITY ERASE UNIT with a password of its own, enhanced when the drive has it
+// unless the caller asks for a mode. The command blocks until done, so progress only
+// gets the drive's own estimate. A normal erase on an SSD is only a Clear, the
+// mechanism returned says which one ran.
+fn ata_security_erase(dev: &device::Device, t: &mut dyn DriveTransport, mode: Option<ata::SecurityEraseMode>, opts: &WipeOptions, ev: &mut WipeEvidence) -> io::Result<Mechanism> {
+    let id = ata::identify_device(t)?;
+    let mode = mode.unwrap_or(match id.security.enhanced_erase_supported {
+        true => ata::SecurityEraseMode::Enhanced,
+        false => ata::SecurityEraseMode::Normal,
+    });
+    ev.log(format!("Issuing ATA SECURITY ERASE UNIT ({:?}) on {}, timeout {} s", mode, dev.dev_path, id.erase_unit_timeout(mode)));
+    opts.progress.phase(&dev.dev_path, WipePhase::SecurityErase, id.erase_unit_estimate(mode).map(u64::from));
+    ata::security_erase(t, &dev.id, mode)?;
+    ev.log(format!("Security disabled again on {}", dev.dev_path));
+    Ok(match mode {
+        ata::SecurityEraseMode::Normal => Mechanism::AtaSecurityErase,
+        ata::SecurityEraseMode::Enhanced => Mechanism::AtaEnhancedSecurityErase,
+    })
+}
 
-        if start.elapsed() > timeout {
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for NVMe sanitize"));
-        }
+/// ---------- NVMe crypto purge (SANITIZE action=4) + polling of Sanitize Status log page ----------
+pub fn nvme_crypto_purge(t: &mut dyn DriveTransport, timeout_secs: u64, progress: &ProgressReporter) -> io::Result<()> {
+    nvme_sanitize_wait(t, &NvmeSanitize::new(SanitizeAction::CryptoErase), timeout_secs, progress)
+}
 
-        // Sleep a short while before polling again
-        thread::sleep(Duration::from_secs(3));
+/// Issue a sanitize with the given options and poll the Sanitize Status log until it
+/// is done. A sanitize already running is waited for instead of being started again.
+pub fn nvme_sanitize_wait(t: &mut dyn DriveTransport, op: &NvmeSanitize, timeout_secs: u64, progress: &ProgressReporter) -> io::Result<()> {
+    if nvme::sanitize_log(t)?.status == nvme::SanitizeStatus::InProgress {
+        println!("NVMe sanitize already in progress on {}, waiting for it instead", t.path());
+    } else {
+        nvme::sanitize(t, op)?;
     }
+    nvme::sanitize_wait(t, timeout_secs, progress)
 }
 
 /// Helper: perform NVMe Get Log Page (opcode=0x02)
-fn get_nvme_log_page(fd: i32, log_id: u32, buf: &mut [u8]) -> io::Result<()> {
+pub(crate) fn get_nvme_log_page(t: &mut dyn DriveTransport, log_id: u32, buf: &mut [u8]) -> io::Result<()> {
     // cdw10 format: (numd-1) << 16 | (log_id)
     // numd is number of dwords (32-bit) to transfer. numd = (buf.len() / 4)
     let numd = (buf.len() / 4) as u32;
     let numd_field = if numd == 0 { 0 } else { (numd - 1) << 16 };
-    let cdw10 = (log_id & 0xff) | numd_field;
-
-    let mut cmd = device::nvme_admin_cmd {
-        opcode: 0x02, // Get Log Page
-        flags: 0,
-        rsvd1: 0,
-        nsid: 0xffffffff, // controller-global
-        cdw2: 0,
-        cdw3: 0,
-        metadata: 0,
-        addr: buf.as_mut_ptr() as u64,
-        metadata_len: 0,
-        data_len: buf.len() as u32,
-        cdw10,
-        cdw11: 0,
-        cdw12: 0,
-        cdw13: 0,
-        cdw14: 0,
-        cdw15: 0,
-        timeout_ms: 0,
-        result: 0,
-    };
 
-    let ret = unsafe { ioctl(fd, device::NVME_IOCTL_ADMIN_CMD, &mut cmd) };
-    if ret < 0 {
-        return Err(io::Error::last_os_error());
-    }
+    let mut cmd = NvmeCommand::new(0x02); // Get Log Page
+    cmd.nsid = 0xffffffff; // controller-global
+    cmd.cdw10 = (log_id & 0xff) | numd_field;
+    t.nvme_admin(&cmd, buf)?;
     Ok(())
 }
 
-/// Convert a namespace device path like /dev/nvme0n1 -> controller device /dev/nvme0
-fn nvme_ctrl_path(dev_path: &str) -> String {
-    // If device looks like /dev/nvme0n1 or /dev/nvme1n2, strip trailing 'n<digits>'
-    if dev_path.starts_with("/dev/nvme") {
-        // find last 'n' followed by digits
-        if let Some(pos) = dev_path.rfind('n') {
-            let tail = &dev_path[pos+1..];
-            if tail.chars().all(|c| c.is_ascii_digit()) {
-                return dev_path[..pos].to_string(); // strip the n#
-            }
-        }
-    }
-    // otherwise return input (may already be controller or char device)
-    dev_path.to_string()
-}
-
 
 
 pub fn dummy_erase(){
     println!("OHMYGOD ERASED!!!");
 }
-
-
@@ -2,13 +2,24 @@ use gtk4::prelude::*;
 use gtk4::{
     Application, ApplicationWindow, Button, Box, ListBox, ListBoxRow, Label, 
     Orientation, MessageDialog, HeaderBar, Stack, StackSidebar, Separator,
-    ScrolledWindow, Frame, CheckButton, ProgressBar, ButtonsType, MessageType
+    ScrolledWindow, Frame, CheckButton, ProgressBar, ButtonsType, MessageType, Window
 };
-use cwe::device::{enumerate_block_devices_linux,find_device_by_path};
+use cwe::device::{check_firmware_sanitize, enumerate_block_devices_linux, find_device_by_path};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
 use cwe::device::Device;
-use cwe::wipe::wipe_device;
+use cwe::evidence::WipeEvidence;
+use cwe::identity::OrgSecret;
+use cwe::partition;
+use cwe::progress::ProgressEvent;
+use cwe::transport::LinuxTransport;
+use cwe::watch::{DeviceEvent, DeviceWatcher};
+use std::time::Duration;
+use cwe::safety::{self, WipeOptions};
+use cwe::wipe::wipe_device_opts;
 
 
 #[derive(Clone)]
@@ -82,11 +93,34 @@ fn build_device_selection_page(stack: &Stack, app_state: &AppState) {
     listbox.set_selection_mode(gtk4::SelectionMode::None);
     listbox.add_css_class("boxed-list");
     
-    // Populate device list
-    match enumerate_block_devices_linux("run") {
+    // Populate device list, hotplug events update the rows after that
+    populate_device_list(&listbox, app_state);
+    watch_devices(&listbox, app_state);
+    
+    scrolled.set_child(Some(&listbox));
+    list_frame.set_child(Some(&scrolled));
+    
+    main_box.append(&title_box);
+    main_box.append(&list_frame);
+    
+    stack.add_titled(&main_box, Some("devices"), "Select Device");
+}
+
+fn populate_device_list(listbox: &ListBox, app_state: &AppState) {
+    while let Some(child) = listbox.first_child() {
+        listbox.remove(&child);
+    }
+    let secret = OrgSecret::load_noting_creation().map(|(secret, created)| {
+        if let Some(path) = created {
+            println!("Created organization secret {}, copy it to every station that should share device ids", path.display());
+        }
+        secret
+    });
+    match secret.map_err(anyhow::Error::from).and_then(|secret| enumerate_block_devices_linux(&secret)) {
         Ok(devices) => {
             if devices.is_empty() {
                 let empty_row = create_empty_state_row();
+                empty_row.set_widget_name(PLACEHOLDER_ROW);
                 listbox.append(&empty_row);
             } else {
                 for dev in devices.iter() {
@@ -97,22 +131,86 @@ fn build_device_selection_page(stack: &Stack, app_state: &AppState) {
         }
         Err(e) => {
             let error_row = create_error_row(&e.to_string());
+            error_row.set_widget_name(PLACEHOLDER_ROW);
             listbox.append(&error_row);
         }
     }
-    
-    scrolled.set_child(Some(&listbox));
-    list_frame.set_child(Some(&scrolled));
-    
-    main_box.append(&title_box);
-    main_box.append(&list_frame);
-    
-    stack.add_titled(&main_box, Some("devices"), "Select Device");
+}
+
+// Hot-swapped drives show up without restarting. The watcher runs on its own
+// thread, the GTK side polls the channel from the main loop and patches the rows.
+fn watch_devices(listbox: &ListBox, app_state: &AppState) {
+    let rx = match OrgSecret::load().and_then(DeviceWatcher::spawn) {
+        Ok(rx) => rx,
+        Err(e) => {
+            println!("Hotplug updates disabled: {}", e);
+            return;
+        }
+    };
+    let listbox = listbox.clone();
+    let app_state = app_state.clone();
+    gtk4::glib::timeout_add_local(Duration::from_millis(500), move || {
+        loop {
+            match rx.try_recv() {
+                Ok(Ok(event)) => apply_device_event(&listbox, &app_state, event),
+                // the watcher thread is gone after this, Disconnected comes next
+                Ok(Err(e)) => println!("Hotplug updates stopped: {}", e),
+                Err(std::sync::mpsc::TryRecvError::Empty) => return gtk4::glib::ControlFlow::Continue,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => return gtk4::glib::ControlFlow::Break,
+            }
+        }
+    });
+}
+
+// Device rows are named after their device path, the empty and error rows after this
+const PLACEHOLDER_ROW: &str = "placeholder";
+
+fn find_row(listbox: &ListBox, name: &str) -> Option<ListBoxRow> {
+    (0..).map_while(|i| listbox.row_at_index(i)).find(|row| row.widget_name().as_str() == name)
+}
+
+fn apply_device_event(listbox: &ListBox, app_state: &AppState, event: DeviceEvent) {
+    match event {
+        // events were lost, the rows can't be trusted any more
+        DeviceEvent::Resync => populate_device_list(listbox, app_state),
+        DeviceEvent::Added(dev) | DeviceEvent::Changed(dev) => {
+            let row = create_device_row(&dev, app_state);
+            match find_row(listbox, &dev.dev_path) {
+                Some(old) => {
+                    listbox.insert(&row, old.index());
+                    listbox.remove(&old);
+                }
+                None => {
+                    if let Some(placeholder) = find_row(listbox, PLACEHOLDER_ROW) {
+                        listbox.remove(&placeholder);
+                    }
+                    listbox.append(&row);
+                }
+            }
+        }
+        DeviceEvent::Removed { dev_path } => {
+            if let Some(old) = find_row(listbox, &dev_path) {
+                listbox.remove(&old);
+            }
+            // the selected disk was pulled, don't leave the wipe page pointing at it
+            let selected = app_state.selected_device.borrow().clone();
+            if selected.as_deref() == Some(dev_path.as_str()) {
+                *app_state.selected_device.borrow_mut() = None;
+                app_state.stack.set_visible_child_name("devices");
+            }
+            if listbox.row_at_index(0).is_none() {
+                let empty_row = create_empty_state_row();
+                empty_row.set_widget_name(PLACEHOLDER_ROW);
+                listbox.append(&empty_row);
+            }
+        }
+    }
 }
 
 fn create_device_row(dev: &Device, app_state: &AppState) -> ListBoxRow {
     let row = ListBoxRow::new();
     row.set_activatable(false);
+    row.set_widget_name(&dev.dev_path);
     
     let hbox = Box::new(Orientation::Horizontal, 15);
     hbox.set_margin_top(15);
@@ -361,20 +459,66 @@ fn show_confirmation_dialog(app_state: &AppState) {
     let device_path = app_state.selected_device.borrow().clone()
         .unwrap_or("Unknown device".to_string());
     
+    // the library refuses in-use disks anyway, tell the user why up front
+    let issues: Vec<String> = match safety::preflight(&device_path) {
+        Ok(report) => report.issues.iter().map(|i| format!("• {}", i)).collect(),
+        Err(e) => vec![format!("• safety check failed: {}", e)],
+    };
+
+    // show what is about to be destroyed, "GPT, 2 partitions: ext4, NTFS"
+    let contents = match LinuxTransport::open(&device_path).and_then(|mut t| partition::read_inventory(&mut t)) {
+        Ok(inv) => format!("This disk contains: {}.", inv.summary()),
+        Err(e) => format!("Could not read the partition table: {}.", e),
+    };
+
+    // what the drive can do, strongest methods are tried first
+    let capabilities = match OrgSecret::load().and_then(|secret| find_device_by_path(&device_path, &secret)) {
+        Ok(mut dev) => {
+            check_firmware_sanitize(&mut dev);
+            let lines: Vec<String> = dev.capabilities.capabilities.iter()
+                .map(|c| format!("{} {}", if c.available { "✓" } else { "✗" }, c))
+                .collect();
+            let sed = dev.sed.as_ref().map(|s| format!("Self-encrypting drive: {}\n", s.summary())).unwrap_or_default();
+            let status = dev.sanitize_status.as_ref()
+                .filter(|s| s.in_progress())
+                .map(|s| format!("{}, the wipe will wait for it to finish\n", s.summary()))
+                .unwrap_or_default();
+            format!("{}{}Sanitize methods:\n{}", status, sed, lines.join("\n"))
+        }
+        Err(e) => format!("Could not probe sanitize methods: {}", e),
+    };
+
+    let text = if issues.is_empty() {
+        format!(
+            "This will permanently erase all data on {}.\n\n{}\n\n{}\n\nThis action cannot be undone. Are you sure you want to continue?",
+            device_path, contents, capabilities
+        )
+    } else {
+        format!(
+            "{} is in use:\n{}\n\n{}\n\n{}\n\nWiping it will destroy data the running system depends on. Only continue if you know exactly what you are doing.",
+            device_path,
+            issues.join("\n"),
+            contents,
+            capabilities
+        )
+    };
+
     let dialog = MessageDialog::builder()
         .transient_for(&app_state.window)
         .modal(true)
-        .message_type(MessageType::Warning)
+        .message_type(if issues.is_empty() { MessageType::Warning } else { MessageType::Error })
         .buttons(ButtonsType::None)
         .text("Confirm Device Wipe")
-        .secondary_text(&format!(
-            "This will permanently erase all data on {}.\n\nThis action cannot be undone. Are you sure you want to continue?",
-            device_path
-        ))
+        .secondary_text(&text)
         .build();
     
     dialog.add_button("Cancel", gtk4::ResponseType::Cancel);
-    dialog.add_button("Wipe Device", gtk4::ResponseType::Accept);
+    if issues.is_empty() {
+        dialog.add_button("Wipe Device", gtk4::ResponseType::Accept);
+    } else {
+        let override_button = dialog.add_button("Wipe Anyway", gtk4::ResponseType::Other(1));
+        override_button.add_css_class("destructive-action");
+    }
     dialog.set_default_response(gtk4::ResponseType::Cancel);
     
     let app_state_clone = app_state.clone();
@@ -382,7 +526,11 @@ fn show_confirmation_dialog(app_state: &AppState) {
         match response {
             gtk4::ResponseType::Accept => {
                 dialog.close();
-                start_wipe_process(&app_state_clone);
+                start_wipe_process(&app_state_clone, false);
+            }
+            gtk4::ResponseType::Other(1) => {
+                dialog.close();
+                start_wipe_process(&app_state_clone, true);
             }
             _ => dialog.close(),
         }
@@ -390,22 +538,79 @@ fn show_confirmation_dialog(app_state: &AppState) {
     
     dialog.show();
 }
-fn start_wipe_process(app_state: &AppState) {
+// The wipe runs for hours, keep it off the main loop. Progress and the result come
+// back over channels that the main loop polls, like the hotplug watcher.
+fn start_wipe_process(app_state: &AppState, force: bool) {
     let device_path = app_state.selected_device.borrow().clone()
         .unwrap_or("Unknown device".to_string());
-    
-    // Handle device lookup and wipe process with proper error handling
-    let wipe_result = match find_device_by_path(&device_path, "hehe") {
-        Ok(mut device) => {
-            // Device found, attempt to wipe
-            wipe_device(&mut device)
+
+    let (progress_tx, progress_rx) = mpsc::channel::<ProgressEvent>();
+    let (result_tx, result_rx) = mpsc::channel::<io::Result<WipeEvidence>>();
+    let path = device_path.clone();
+    thread::spawn(move || {
+        let result = OrgSecret::load().and_then(|secret| find_device_by_path(&path, &secret)).and_then(|mut device| {
+            let mut opts = WipeOptions { force, ..Default::default() };
+            opts.progress.subscribe(progress_tx);
+            wipe_device_opts(&mut device, &opts)
+        });
+        let _ = result_tx.send(result);
+    });
+
+    let status = Label::new(Some(&format!("Wiping {}", device_path)));
+    status.set_halign(gtk4::Align::Start);
+    let bar = ProgressBar::new();
+    bar.set_show_text(true);
+    bar.set_text(Some("Starting"));
+    let content = Box::new(Orientation::Vertical, 12);
+    content.set_margin_top(24);
+    content.set_margin_bottom(24);
+    content.set_margin_start(24);
+    content.set_margin_end(24);
+    content.append(&status);
+    content.append(&bar);
+
+    // no close button, the wipe can't be cancelled halfway
+    let progress_window = Window::builder()
+        .transient_for(&app_state.window)
+        .modal(true)
+        .deletable(false)
+        .title("Wiping Device")
+        .default_width(480)
+        .child(&content)
+        .build();
+    progress_window.show();
+
+    let app_state = app_state.clone();
+    let mut pulsing = true;
+    gtk4::glib::timeout_add_local(Duration::from_millis(200), move || {
+        for event in progress_rx.try_iter() {
+            pulsing = event.fraction.is_none();
+            if let Some(fraction) = event.fraction {
+                bar.set_fraction(fraction);
+            }
+            bar.set_text(Some(&event.to_string()));
         }
-        Err(e) => {
-            // Device lookup failed
-            Err(e)
+        // firmware erases that can't be polled just show activity
+        if pulsing {
+            bar.pulse();
         }
-    };
-    
+        match result_rx.try_recv() {
+            Ok(result) => {
+                progress_window.close();
+                show_wipe_result(&app_state, &device_path, result);
+                gtk4::glib::ControlFlow::Break
+            }
+            Err(mpsc::TryRecvError::Empty) => gtk4::glib::ControlFlow::Continue,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                progress_window.close();
+                show_wipe_result(&app_state, &device_path, Err(io::Error::other("wipe thread exited without a result")));
+                gtk4::glib::ControlFlow::Break
+            }
+        }
+    });
+}
+
+fn show_wipe_result(app_state: &AppState, device_path: &str, wipe_result: io::Result<WipeEvidence>) {
     // Create appropriate dialog based on result
     let dialog = match wipe_result {
         Ok(_) => {